@@ -1,21 +1,88 @@
 use std::{
     pin::pin,
-    time::{Duration, Instant},
+    time::Duration,
 };
-use actix_ws::{AggregatedMessage, MessageStream, Session};
+use actix_ws::{AggregatedMessage, CloseCode, CloseReason, Closed, MessageStream, ProtocolError, Session};
 use futures_util::{
     future::{select, Either},
-    StreamExt as _,
+    Stream, StreamExt as _,
 };
-use tokio::{sync::mpsc, time::interval};
-use serde_json::Value;
-use crate::server::{ChatServerHandle, ConnId, EncryptedMessage, UserProfile, ClientEvent};
+use tokio::{sync::{mpsc, oneshot}, time::{interval, Instant}};
+use tracing::Instrument;
+use crate::keys;
+use crate::server::{Activity, ChatServerHandle, ConnId, EncryptedMessage, FileChunk, LeaveReason, Msg, PresenceStatus, ReactionAction, ServerUnavailable, SessionEnd, UserProfile, ClientEvent, ServerEvent, MAX_PAYLOAD_SIZE};
 
-/// How often heartbeat pings are sent
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest group code accepted from clients (generated codes are 6 characters)
+const MAX_GROUP_CODE_LEN: usize = 16;
 
-/// How long before lack of client response causes a timeout
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(3600);
+/// Application close code sent when the client stopped responding to heartbeats
+const CLOSE_CODE_TIMEOUT: u16 = 4000;
+
+/// Application close code sent when the client's IP already has the maximum number of sessions
+const CLOSE_CODE_TOO_MANY_CONNECTIONS: u16 = 4001;
+
+/// Application close code sent when the client never sent `join_chat` within the join grace period
+const CLOSE_CODE_JOIN_TIMEOUT: u16 = 4002;
+
+/// Why a connection loop ended; each reason maps to its own close frame
+enum DisconnectReason {
+    /// The client sent a close frame; echo its reason back
+    ClientClosed(Option<CloseReason>),
+    /// The client stream ended or the socket could no longer be written to
+    ConnectionLost,
+    /// No heartbeat from the client within the configured client timeout
+    Timeout,
+    /// The client sent a frame we could not decode
+    ProtocolError,
+    /// The client sent a frame or message larger than `MAX_PAYLOAD_SIZE`
+    PayloadTooLarge,
+    /// The chat server went away; clients may reconnect once it is back
+    ServerShutdown,
+    /// The client's IP already holds the maximum number of sessions
+    TooManyConnections,
+    /// The client connected but never joined a chat within the join grace period
+    JoinTimeout,
+    /// The connection was banned for exceeding rate limits; clients should not auto-reconnect
+    RateLimitBan,
+    /// The client read so slowly that the server gave up queueing frames for it
+    SlowConsumer,
+}
+
+impl DisconnectReason {
+    // What the partner is told about why this user went away
+    fn leave_reason(&self) -> LeaveReason {
+        match self {
+            DisconnectReason::ClientClosed(_) => LeaveReason::Left,
+            DisconnectReason::Timeout | DisconnectReason::JoinTimeout => LeaveReason::Timeout,
+            DisconnectReason::RateLimitBan => LeaveReason::Banned,
+            DisconnectReason::ConnectionLost
+            | DisconnectReason::ProtocolError
+            | DisconnectReason::PayloadTooLarge
+            | DisconnectReason::TooManyConnections
+            | DisconnectReason::SlowConsumer
+            | DisconnectReason::ServerShutdown => LeaveReason::Error,
+        }
+    }
+
+    fn close_reason(self) -> Option<CloseReason> {
+        let (code, description) = match self {
+            DisconnectReason::ClientClosed(reason) => return reason,
+            DisconnectReason::ConnectionLost => return None,
+            DisconnectReason::Timeout => (CloseCode::Other(CLOSE_CODE_TIMEOUT), "heartbeat timeout"),
+            DisconnectReason::ProtocolError => (CloseCode::Protocol, "protocol error"),
+            DisconnectReason::PayloadTooLarge => (CloseCode::Size, "payload too large"),
+            DisconnectReason::ServerShutdown => (CloseCode::Restart, "server shutting down"),
+            DisconnectReason::TooManyConnections => (CloseCode::Other(CLOSE_CODE_TOO_MANY_CONNECTIONS), "too many connections"),
+            DisconnectReason::JoinTimeout => (CloseCode::Other(CLOSE_CODE_JOIN_TIMEOUT), "join timeout"),
+            DisconnectReason::RateLimitBan => (CloseCode::Policy, "rate limit exceeded"),
+            DisconnectReason::SlowConsumer => (CloseCode::Policy, "too slow to receive messages"),
+        };
+        Some(CloseReason {
+            code,
+            description: Some(description.to_string()),
+        })
+    }
+}
 
 #[derive(serde::Deserialize)]
 struct SendMessageData {
@@ -23,6 +90,7 @@ struct SendMessageData {
     is_group_chat: bool,
     group_code: Option<String>,
     reply_to_id: Option<i32>,
+    message_id: Option<String>, // client-chosen id echoed back in message_sent and message_delivered
 }
 
 // New struct for file sending data
@@ -33,6 +101,15 @@ struct FileStatusData {
     group_code: Option<String>,
 }
 
+#[derive(serde::Deserialize)]
+struct FileProgressData {
+    file_id: String,
+    bytes_sent: u64,
+    total_bytes: u64,
+    is_group_chat: bool,
+    group_code: Option<String>,
+}
+
 // New struct for delete message data
 #[derive(serde::Deserialize)]
 struct DeleteMessageData {
@@ -41,10 +118,101 @@ struct DeleteMessageData {
     group_code: Option<String>,
 }
 
+#[derive(serde::Deserialize)]
+struct ReactMessageData {
+    message_id: String,
+    reaction: String,
+    action: ReactionAction,
+    is_group_chat: bool,
+    group_code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct EditMessageData {
+    message_id: String,
+    message: EncryptedMessage,
+    is_group_chat: bool,
+    group_code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct MessageReadData {
+    message_id: String,
+    is_group_chat: bool,
+    group_code: Option<String>,
+}
+
 #[derive(serde::Deserialize)]
 struct TypingData {
     is_group_chat: bool,
     group_code: Option<String>,
+    #[serde(default)]
+    activity: Activity, // typing_start/typing_stop without an activity mean plain typing
+}
+
+#[derive(serde::Deserialize)]
+struct KickMemberData {
+    target: String, // username or conn id of the member to remove
+}
+
+#[derive(serde::Deserialize)]
+struct PingPeerData {
+    target_id: Option<ConnId>, // a group member's conn id; omitted for the 1:1 partner
+}
+
+#[derive(serde::Deserialize)]
+struct UpdatePreferenceData {
+    preference: String,
+    language: Option<String>, // omitted to keep the current one; blank to drop it
+}
+
+#[derive(serde::Deserialize)]
+struct UpdatePresenceData {
+    status: PresenceStatus,
+}
+
+#[derive(serde::Deserialize)]
+struct BlockUserData {
+    user_id: Option<String>, // omitted to block the current 1:1 partner
+}
+
+#[derive(serde::Deserialize)]
+struct ReportUserData {
+    target: Option<String>, // username or conn id in a group; omitted for the 1:1 partner
+    reason: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct GetHistoryData {
+    group_code: Option<String>,
+    before_seq: Option<u64>, // page backwards from here; omitted for the newest messages
+    limit: Option<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct RenameGroupData {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct ActivityData {
+    activity: Activity,
+    active: bool,
+    is_group_chat: bool,
+    group_code: Option<String>,
+}
+
+/// Per-connection timing, read from secrets once at startup
+#[derive(Clone, Copy)]
+pub struct ConnectionTimeouts {
+    pub heartbeat_interval: Duration,
+    pub client_timeout: Duration,
+    pub join_grace: Duration,
+}
+
+/// The span a connection task runs in; `conn_id` is recorded once the chat server assigns one
+pub fn connection_span(ip: &str) -> tracing::Span {
+    tracing::info_span!("connection", conn_id = tracing::field::Empty, ip = %ip)
 }
 
 /// Handle WebSocket connections, process messages, and maintain connection health
@@ -52,27 +220,62 @@ pub async fn chat_ws(
     chat_server: ChatServerHandle,
     mut session: Session,
     msg_stream: MessageStream,
+    resume_token: Option<String>,
+    ip: String,
+    timeouts: ConnectionTimeouts,
 ) {
-    let max_payload_size = 5 * 1024 * 1024; // 5 MB
     // Increase permitted frame size from default (64KiB) to 5MB.
-    let mut msg_stream = msg_stream
-        .max_frame_size(max_payload_size)
+    let msg_stream = msg_stream
+        .max_frame_size(MAX_PAYLOAD_SIZE)
         .aggregate_continuations()
-        .max_continuation_size(max_payload_size);
+        .max_continuation_size(MAX_PAYLOAD_SIZE);
+    run_connection(chat_server, &mut session, msg_stream, resume_token, ip, timeouts).await;
+}
+
+// The connection loop behind `chat_ws`, over any client socket and aggregated message stream
+async fn run_connection(
+    chat_server: ChatServerHandle,
+    session: &mut impl ClientSocket,
+    mut msg_stream: impl Stream<Item = Result<AggregatedMessage, ProtocolError>> + Unpin,
+    resume_token: Option<String>,
+    ip: String,
+    timeouts: ConnectionTimeouts,
+) {
+    let ConnectionTimeouts { heartbeat_interval, client_timeout, join_grace } = timeouts;
 
     log::info!("WebSocket connection established");
     
     let mut last_heartbeat = Instant::now();
-    let mut interval = interval(HEARTBEAT_INTERVAL);
+    let mut interval = interval(heartbeat_interval);
     
     // Create a channel for this connection
-    let (conn_tx, mut conn_rx) = mpsc::unbounded_channel();
+    let (conn_tx, mut conn_rx) = mpsc::channel(keys::get_session_queue_capacity());
+    // Filled in when the server ends the session itself, just before it drops `conn_tx`
+    let (end_tx, mut end_rx) = oneshot::channel();
     
-    // Register with the chat server and get a connection ID
-    let conn_id = chat_server.connect(conn_tx).await;
+    // Register with the chat server and get a connection ID (the previous one when resuming)
+    let (conn_id, resumed) = match chat_server.connect(conn_tx, end_tx, resume_token, ip).await {
+        Ok(Some(registered)) => registered,
+        // Nothing was registered, so there is nothing to clean up
+        Ok(None) => {
+            let _ = session.close(DisconnectReason::TooManyConnections.close_reason()).await;
+            return;
+        }
+        Err(e) => {
+            log::error!("Refusing connection: {}", e);
+            let _ = session.close(DisconnectReason::ServerShutdown.close_reason()).await;
+            return;
+        }
+    };
+    // The caller opens this task's span before the id exists; fill it in so every later line carries it
+    tracing::Span::current().record("conn_id", conn_id.as_str());
     log::info!("Client connected with ID: {}", conn_id);
+
+    // A resumed session already joined before it dropped, so only fresh connections must join in time
+    let mut joined = resumed;
+    let join_deadline = Instant::now() + join_grace;
     
-    let close_reason = loop {
+    let disconnect_reason = loop {
         // Set up the futures we'll select between
         let tick = pin!(interval.tick());
         let msg_rx = pin!(conn_rx.recv());
@@ -87,351 +290,1145 @@ pub async fn chat_ws(
                 match agg_msg {
                     AggregatedMessage::Text(text) => {
                                         // Process text message normally
-                                        process_text_msg(&chat_server, &text, conn_id.clone()).await;
+                                        match process_text_msg(&chat_server, session, &text, conn_id.clone()).await {
+                                            Ok(join_accepted) => joined |= join_accepted,
+                                            Err(e) => {
+                                                log::error!("Dropping connection: {}", e);
+                                                break DisconnectReason::ServerShutdown;
+                                            }
+                                        }
                                     }
                     AggregatedMessage::Binary(data) => {
-                                        // Log or handle binary messages as needed
-                                        log::warn!("Unexpected binary message received: {} bytes", data.len());
+                                        // Encrypted file chunk with a routing header; see `FileChunk`
+                                        match FileChunk::parse(&data) {
+                                            Ok(chunk) => {
+                                                if let Err(e) = chat_server.relay_file_chunk(conn_id.clone(), chunk).await {
+                                                    log::error!("Dropping connection: {}", e);
+                                                    break DisconnectReason::ServerShutdown;
+                                                }
+                                            }
+                                            Err(reason) => {
+                                                log::warn!("Rejected binary frame of {} bytes: {}", data.len(), reason);
+                                                send_to_client(session, "binary_error", serde_json::json!({
+                                                    "reason": reason,
+                                                })).await;
+                                            }
+                                        }
                                     }
                     AggregatedMessage::Ping(bytes) => {
                                         // Respond to ping with pong
                                         if let Err(e) = session.pong(&bytes).await {
                                             log::error!("Failed to send pong: {}", e);
-                                            break None;
+                                            break DisconnectReason::ConnectionLost;
                                         }
                     }
-                    AggregatedMessage::Pong(bytes) => {
-                                        // Log or handle pong messages as needed
-                                        log::warn!("Unexpected pong message received: {} bytes", bytes.len());
+                    AggregatedMessage::Pong(_) => {
+                                        // Heartbeat reply; lets the server track liveness for presence
+                                        chat_server.heartbeat(conn_id.clone());
                     }
                     AggregatedMessage::Close(close_reason) => {
                                         // Log or handle close messages as needed
                                         log::warn!("Unexpected close message received: {:?}", close_reason);
-                                        break Some(close_reason);
+                                        break DisconnectReason::ClientClosed(close_reason);
                     }
                 }
             }
             // Client WebSocket stream error
             Either::Left((Either::Left((Some(Err(err)), _)), _)) => {
-                log::error!("WebSocket error: {}", err);
-                break None;
+                match err {
+                    // The transport itself failed; there is no one left to tell
+                    ProtocolError::Io(err) => {
+                        log::error!("WebSocket transport error: {}", err);
+                        break DisconnectReason::ConnectionLost;
+                    }
+                    ProtocolError::Overflow => {
+                        log::warn!("Client exceeded the {} byte payload limit", MAX_PAYLOAD_SIZE);
+                        send_to_client(session, "stream_error", serde_json::json!({
+                            "reason": "payload_too_large",
+                            "max_payload_size": MAX_PAYLOAD_SIZE,
+                        })).await;
+                        break DisconnectReason::PayloadTooLarge;
+                    }
+                    err => {
+                        log::error!("WebSocket protocol error: {}", err);
+                        send_to_client(session, "stream_error", serde_json::json!({
+                            "reason": "malformed_frame",
+                            "detail": err.to_string(),
+                        })).await;
+                        break DisconnectReason::ProtocolError;
+                    }
+                }
             }
             // Client WebSocket stream ended
             Either::Left((Either::Left((None, _)), _)) => {
                 log::info!("WebSocket connection closed by client");
-                break None;
+                break DisconnectReason::ConnectionLost;
             }
             // Messages from chat server to be sent to client
             Either::Left((Either::Right((Some(chat_msg), _)), _)) => {
-                if let Err(e) = session.text(chat_msg).await {
+                let sent = match chat_msg {
+                    Msg::Text(text) => session.text(text).await,
+                    Msg::Binary(bytes) => session.binary(bytes).await,
+                };
+                if let Err(e) = sent {
                     log::error!("Failed to send message to client: {}", e);
-                    break None;
+                    break DisconnectReason::ConnectionLost;
                 }
             }
             // All connection message senders were dropped
             Either::Left((Either::Right((None, _)), _)) => {
-                log::error!("All connection message senders were dropped; chat server may have panicked");
-                break None;
+                match end_rx.try_recv() {
+                    Ok(SessionEnd::RateLimitBan) => {
+                        log::info!("Chat server banned this connection for exceeding rate limits");
+                        break DisconnectReason::RateLimitBan;
+                    }
+                    Ok(SessionEnd::SlowConsumer) => {
+                        log::info!("Chat server dropped this connection for falling behind on its queue");
+                        break DisconnectReason::SlowConsumer;
+                    }
+                    Err(_) => {
+                        log::info!("Chat server dropped this connection's sender; it is shutting down");
+                        break DisconnectReason::ServerShutdown;
+                    }
+                }
             }
             // Heartbeat tick
             Either::Right((_, _)) => {
                 // Check if client is still responsive
-                if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
-                    log::info!("Client has not sent heartbeat in over {:?}; disconnecting", CLIENT_TIMEOUT);
-                    break None;
+                if Instant::now().duration_since(last_heartbeat) > client_timeout {
+                    log::info!("Client has not sent heartbeat in over {:?}; disconnecting", client_timeout);
+                    break DisconnectReason::Timeout;
+                }
+                if !joined && Instant::now() > join_deadline {
+                    log::info!("Client did not join a chat within {:?}; disconnecting", join_grace);
+                    send_to_client(session, "join_timeout", serde_json::json!({
+                        "grace_secs": join_grace.as_secs(),
+                    })).await;
+                    break DisconnectReason::JoinTimeout;
                 }
                 // Send heartbeat ping; if this fails, break the loop
                 if let Err(e) = session.ping(b"").await {
                     log::error!("Failed to send ping: {}", e);
-                    break None;
+                    break DisconnectReason::ConnectionLost;
                 }
             }
         }
     };
     
     // Clean up when the connection ends
-    chat_server.disconnect(conn_id);
+    chat_server.disconnect(conn_id, disconnect_reason.leave_reason());
     log::info!("WebSocket connection closed");
     
     // Attempt to close connection gracefully
-    let _ = session.close(Option::expect(close_reason, "No close reason provided")).await;
+    let _ = session.close(disconnect_reason.close_reason()).await;
+}
+
+// Group codes are short and alphanumeric; anything else is rejected before it reaches the server's maps
+fn is_valid_group_code(code: &str) -> bool {
+    !code.is_empty() && code.len() <= MAX_GROUP_CODE_LEN && code.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// Media types a call can carry; signaling that names anything else is refused
+const CALL_MEDIA_TYPES: &[&str] = &["audio", "video"];
+
+// Signaling events that carry `media`, and whether they must
+fn media_requirement(event: &str) -> Option<bool> {
+    match event {
+        "call_ring" | "call_media_changed" => Some(true),
+        "webrtc_offer" => Some(false),
+        _ => None,
+    }
+}
+
+/// The client end of a connection as the connection loop sees it: the actix session in production
+trait ClientSocket {
+    async fn text(&mut self, text: String) -> Result<(), Closed>;
+    async fn binary(&mut self, bytes: Vec<u8>) -> Result<(), Closed>;
+    async fn ping(&mut self, bytes: &[u8]) -> Result<(), Closed>;
+    async fn pong(&mut self, bytes: &[u8]) -> Result<(), Closed>;
+    async fn close(&mut self, reason: Option<CloseReason>) -> Result<(), Closed>;
 }
 
+impl ClientSocket for Session {
+    async fn text(&mut self, text: String) -> Result<(), Closed> {
+        Session::text(self, text).await
+    }
+
+    async fn binary(&mut self, bytes: Vec<u8>) -> Result<(), Closed> {
+        Session::binary(self, bytes).await
+    }
+
+    async fn ping(&mut self, bytes: &[u8]) -> Result<(), Closed> {
+        Session::ping(self, bytes).await
+    }
+
+    async fn pong(&mut self, bytes: &[u8]) -> Result<(), Closed> {
+        Session::pong(self, bytes).await
+    }
+
+    // Closing consumes an actix session, so close a handle to it; every clone shares the one socket
+    async fn close(&mut self, reason: Option<CloseReason>) -> Result<(), Closed> {
+        self.clone().close(reason).await
+    }
+}
+
+// Reply straight to this connection's client, bypassing the chat server
+async fn send_to_client(session: &mut impl ClientSocket, event: &str, data: serde_json::Value) {
+    let event = ServerEvent {
+        event: event.to_string(),
+        data,
+    };
+    if let Err(e) = session.text(serde_json::to_string(&event).unwrap()).await {
+        log::error!("Failed to send {} to client: {}", event.event, e);
+    }
+}
+
+// Tell the client one of its events could not be understood, echoing its request id
+async fn send_protocol_error(session: &mut impl ClientSocket, event: &str, request_id: &Option<String>, error: &str) {
+    send_to_client(session, "protocol_error", serde_json::json!({
+        "event": event,
+        "request_id": request_id,
+        "error": error,
+    })).await;
+}
+
+// Returns true when the event was a `join_chat` the server accepted, which ends the connection's join
+// grace period
 async fn process_text_msg(
     chat_server: &ChatServerHandle,
+    session: &mut impl ClientSocket,
     text: &str,
     conn_id: ConnId,
-) {
+) -> Result<bool, ServerUnavailable> {
     // Try to parse the message as a ClientEvent
-    if let Ok(client_event) = serde_json::from_str::<ClientEvent>(text) {
-        match client_event.event.as_str() {
-            "join_chat" => {
-                if let Ok(profile) = serde_json::from_value::<UserProfile>(client_event.data) {
-                    log::info!("User joining chat: {}", profile.username);
-                    chat_server.join_chat(conn_id, profile).await;
-                } else {
-                    log::error!("Failed to parse join_chat data");
-                }
+    let Ok(client_event) = serde_json::from_str::<ClientEvent>(text) else {
+        log::error!("Failed to parse message as ClientEvent: {}", text);
+        send_protocol_error(session, "unknown", &None, "malformed event").await;
+        return Ok(false);
+    };
+    // Every log line while handling the event carries its type and, when it names one, the room
+    let room = client_event.data.get("group_code").and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let span = tracing::info_span!("client_event", event = %client_event.event, room = %room);
+    handle_client_event(chat_server, session, client_event, conn_id).instrument(span).await
+}
+
+// Returns whether the event was a `join_chat` that left the connection matched, waiting or in a group
+async fn handle_client_event(
+    chat_server: &ChatServerHandle,
+    session: &mut impl ClientSocket,
+    client_event: ClientEvent,
+    conn_id: ConnId,
+) -> Result<bool, ServerUnavailable> {
+    let request_id = client_event.request_id.clone();
+    // Every event that names a group or private pair carries the code at the top level of `data`
+    for field in ["group_code", "pair_code"] {
+        if let Some(code) = client_event.data.get(field).and_then(|v| v.as_str()) {
+            if !is_valid_group_code(code) {
+                log::warn!("Rejected malformed {} from {} ({} bytes)", field, conn_id, code.len());
+                send_to_client(session, "invalid_group_code", serde_json::json!({
+                    "event": client_event.event,
+                    "field": field,
+                    "request_id": request_id,
+                })).await;
+                return Ok(false);
             }
+        }
+    }
+    if let Some(required) = media_requirement(&client_event.event) {
+        let media = client_event.data.get("media");
+        let valid = match media {
+            Some(media) => media.as_str().is_some_and(|media| CALL_MEDIA_TYPES.contains(&media)),
+            None => !required,
+        };
+        if !valid {
+            send_to_client(session, "webrtc_error", serde_json::json!({
+                "event": client_event.event,
+                "error": "invalid media",
+                "request_id": request_id,
+            })).await;
+            return Ok(false);
+        }
+    }
+    let mut joined = false;
+    match client_event.event.as_str() {
+        "join_chat" => {
+            if let Ok(profile) = serde_json::from_value::<UserProfile>(client_event.data) {
+                log::info!("User joining chat: {}", profile.username);
+                joined = chat_server.join_chat(conn_id, profile).await?;
+            } else {
+                log::error!("Failed to parse join_chat data");
+                send_protocol_error(session, "join_chat", &request_id, "invalid data").await;
+            }
+        }
+        
+        // Handle WebRTC signaling events
+        "webrtc_offer" => {
+            log::info!("Received WebRTC offer from client {}", conn_id);
+            let client_data = client_event.data.clone();
+            
+            // Log the full client_event for debugging
+            log::debug!("WebRTC offer client_event: {}", 
+                       serde_json::to_string_pretty(&client_event).unwrap_or_else(|_| "Invalid JSON".to_string()));
             
-            // Handle WebRTC signaling events
-            "webrtc_offer" => {
-                log::info!("Received WebRTC offer from client {}", conn_id);
-                let client_data = client_event.data.clone();
+            if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
+                // Extract relevant fields
+                let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+                let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+                let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
                 
-                // Log the full client_event for debugging
-                log::debug!("WebRTC offer client_event: {}", 
-                           serde_json::to_string_pretty(&client_event).unwrap_or_else(|_| "Invalid JSON".to_string()));
+                log::info!("WebRTC offer details: target_id={}, is_group_chat={}, has_group_code={}", 
+                    target_id, is_group_chat, group_code.is_some());
                 
-                if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
-                    // Extract relevant fields
-                    let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
-                    let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
-                    let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
-                    
-                    log::info!("WebRTC offer details: target_id={}, is_group_chat={}, has_group_code={}", 
-                        target_id, is_group_chat, group_code.is_some());
-                    
-                    // Verify the parsed data structure is what we expect
-                    log::debug!("Extracted WebRTC offer data fields: target_id={}, is_group_chat={}, group_code={:?}, has_offer={}", 
-                               target_id, is_group_chat, group_code, data.get("offer").is_some());
-                    
-                    // Forward the offer to the target client
-                    if !target_id.is_empty() {
-                        // For private chat
-                        if !is_group_chat {
-                            log::info!("Relaying WebRTC offer from {} to {} (private chat)", conn_id, target_id);
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_offer".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                false, 
-                                None
-                            ).await;
-                        } else if let Some(code) = group_code {
-                            log::info!("Relaying WebRTC offer from {} to {} (group chat: {})", conn_id, target_id, code);
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_offer".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                true, 
-                                Some(code)
-                            ).await;
-                        }
-                    } else {
-                        log::error!("WebRTC offer missing target_id");
+                // Verify the parsed data structure is what we expect
+                log::debug!("Extracted WebRTC offer data fields: target_id={}, is_group_chat={}, group_code={:?}, has_offer={}", 
+                           target_id, is_group_chat, group_code, data.get("offer").is_some());
+                
+                // Forward the offer to the target client
+                if !target_id.is_empty() {
+                    // For private chat
+                    if !is_group_chat {
+                        log::info!("Relaying WebRTC offer from {} to {} (private chat)", conn_id, target_id);
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_offer".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            false, 
+                            None
+                        ).await?;
+                    } else if let Some(code) = group_code {
+                        log::info!("Relaying WebRTC offer from {} to {} (group chat: {})", conn_id, target_id, code);
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_offer".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            true, 
+                            Some(code)
+                        ).await?;
                     }
                 } else {
-                    log::error!("Failed to parse webrtc_offer data: {}", 
-                        serde_json::to_string(&client_event.data).unwrap_or_default());
+                    log::error!("WebRTC offer missing target_id");
                 }
+            } else {
+                log::error!("Failed to parse webrtc_offer data: {}", 
+                    serde_json::to_string(&client_event.data).unwrap_or_default());
             }
-            
-            "webrtc_answer" => {
-                log::info!("Received WebRTC answer from client");
-                let client_data = client_event.data.clone();
-                if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
-                    // Extract relevant fields
-                    let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
-                    let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
-                    let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
-                    
-                    // Forward the answer to the target client
-                    if !target_id.is_empty() {
-                        // For private chat
-                        if !is_group_chat {
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_answer".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                false, 
-                                None
-                            ).await;
-                        } else if let Some(code) = group_code {
-                            // For group chat
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_answer".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                true, 
-                                Some(code)
-                            ).await;
-                        }
+        }
+        
+        "webrtc_answer" => {
+            log::info!("Received WebRTC answer from client");
+            let client_data = client_event.data.clone();
+            if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
+                // Extract relevant fields
+                let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+                let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+                let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
+                
+                // Forward the answer to the target client
+                if !target_id.is_empty() {
+                    // For private chat
+                    if !is_group_chat {
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_answer".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            false, 
+                            None
+                        ).await?;
+                    } else if let Some(code) = group_code {
+                        // For group chat
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_answer".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            true, 
+                            Some(code)
+                        ).await?;
                     }
-                } else {
-                    log::error!("Failed to parse webrtc_answer data");
                 }
+            } else {
+                log::error!("Failed to parse webrtc_answer data");
             }
-            
-            "webrtc_ice_candidate" => {
-                log::info!("Received WebRTC ICE candidate from client");
-                let client_data = client_event.data.clone();
-                if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
-                    // Extract relevant fields
-                    let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
-                    let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
-                    let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
-                    
-                    // Forward the ICE candidate to the target client
-                    if !target_id.is_empty() {
-                        // For private chat
-                        if !is_group_chat {
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_ice_candidate".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                false, 
-                                None
-                            ).await;
-                        } else if let Some(code) = group_code {
-                            // For group chat
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_ice_candidate".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                true, 
-                                Some(code)
-                            ).await;
-                        }
+        }
+        
+        "webrtc_ice_candidate" => {
+            log::info!("Received WebRTC ICE candidate from client");
+            let client_data = client_event.data.clone();
+            if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
+                // Extract relevant fields
+                let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+                let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+                let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
+                
+                // Forward the ICE candidate to the target client
+                if !target_id.is_empty() {
+                    // For private chat
+                    if !is_group_chat {
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_ice_candidate".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            false, 
+                            None
+                        ).await?;
+                    } else if let Some(code) = group_code {
+                        // For group chat
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_ice_candidate".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            true, 
+                            Some(code)
+                        ).await?;
                     }
-                } else {
-                    log::error!("Failed to parse webrtc_ice_candidate data");
                 }
+            } else {
+                log::error!("Failed to parse webrtc_ice_candidate data");
             }
-            
-            "webrtc_end_call" => {
-                log::info!("Received WebRTC end call from client");
-                let client_data = client_event.data.clone();
-                if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
-                    // Extract relevant fields
-                    let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
-                    let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
-                    let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
-                    
-                    // Forward the end call to the target client
-                    if !target_id.is_empty() {
-                        // For private chat
-                        if !is_group_chat {
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_end_call".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                false, 
-                                None
-                            ).await;
-                        } else if let Some(code) = group_code {
-                            // For group chat
-                            chat_server.relay_webrtc_event(
-                                conn_id.clone(), 
-                                "webrtc_end_call".to_string(), 
-                                target_id.to_string(), 
-                                client_event.data.clone(), 
-                                true, 
-                                Some(code)
-                            ).await;
-                        }
-                    }
-                } else {
-                    log::error!("Failed to parse webrtc_end_call data");
+        }
+
+        // Batched ICE candidates: { target_id, candidates: [...], ... } relayed as one event
+        "webrtc_ice_candidates" => {
+            log::info!("Received batched WebRTC ICE candidates from client");
+            let data = &client_event.data;
+            let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+            let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+            let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
+
+            if !data.get("candidates").is_some_and(|v| v.is_array()) {
+                log::error!("webrtc_ice_candidates missing candidates array");
+            } else if !target_id.is_empty() {
+                if !is_group_chat {
+                    chat_server.relay_webrtc_event(
+                        conn_id.clone(),
+                        "webrtc_ice_candidates".to_string(),
+                        target_id.to_string(),
+                        client_event.data.clone(),
+                        false,
+                        None
+                    ).await?;
+                } else if let Some(code) = group_code {
+                    chat_server.relay_webrtc_event(
+                        conn_id.clone(),
+                        "webrtc_ice_candidates".to_string(),
+                        target_id.to_string(),
+                        client_event.data.clone(),
+                        true,
+                        Some(code)
+                    ).await?;
                 }
             }
-            
-            "send_message" => {
-                if let Ok(data) = serde_json::from_value::<SendMessageData>(client_event.data) {
-                    // The message received here is assumed to be already encrypted by the frontend,
-                    // including type information within the encrypted payload if needed.
-                    let mut message = data.message;
-                    // Ensure reply_to is set if provided in the event data
-                    if message.reply_to.is_none() {
-                        message.reply_to = data.reply_to_id;
+        }
+
+        "webrtc_end_call" => {
+            log::info!("Received WebRTC end call from client");
+            let client_data = client_event.data.clone();
+            if let Ok(data) = serde_json::from_value::<serde_json::Value>(client_data) {
+                // Extract relevant fields
+                let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+                let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+                let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
+                
+                // Forward the end call to the target client
+                if !target_id.is_empty() {
+                    // For private chat
+                    if !is_group_chat {
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_end_call".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            false, 
+                            None
+                        ).await?;
+                    } else if let Some(code) = group_code {
+                        // For group chat
+                        chat_server.relay_webrtc_event(
+                            conn_id.clone(), 
+                            "webrtc_end_call".to_string(), 
+                            target_id.to_string(), 
+                            client_event.data.clone(), 
+                            true, 
+                            Some(code)
+                        ).await?;
                     }
-                    
-                    chat_server.send_message(
-                        conn_id,
-                        message, // Pass the EncryptedMessage directly
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse send_message data");
                 }
+            } else {
+                log::error!("Failed to parse webrtc_end_call data");
             }
-            "typing_start" => {
-                if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
-                    chat_server.typing_start(
-                        conn_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse typing_start data");
+        }
+        
+        // Call setup and media switches, relayed as-is to `target_id`:
+        //   call_ring          { target_id, media: "audio" | "video", call_id? } (+ caller, call_id from the server)
+        //   call_accept/reject { target_id, call_id }
+        //   call_media_changed { target_id, media: "audio" | "video" } e.g. downgrading video to audio mid-call
+        "call_ring" | "call_accept" | "call_reject" | "call_media_changed" => {
+            log::info!("Received {} from client", client_event.event);
+            let data = &client_event.data;
+            let target_id = data.get("target_id").and_then(|v| v.as_str()).unwrap_or("");
+            let is_group_chat = data.get("is_group_chat").and_then(|v| v.as_bool()).unwrap_or(false);
+            let group_code = data.get("group_code").and_then(|v| v.as_str()).map(String::from);
+
+            if !target_id.is_empty() {
+                if !is_group_chat {
+                    chat_server.relay_webrtc_event(
+                        conn_id.clone(),
+                        client_event.event.clone(),
+                        target_id.to_string(),
+                        client_event.data.clone(),
+                        false,
+                        None
+                    ).await?;
+                } else if let Some(code) = group_code {
+                    chat_server.relay_webrtc_event(
+                        conn_id.clone(),
+                        client_event.event.clone(),
+                        target_id.to_string(),
+                        client_event.data.clone(),
+                        true,
+                        Some(code)
+                    ).await?;
                 }
+            } else {
+                log::error!("{} missing target_id", client_event.event);
             }
-            "typing_stop" => {
-                if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
-                    chat_server.typing_stop(
-                        conn_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse typing_stop data");
+        }
+
+        "send_message" => {
+            if let Ok(data) = serde_json::from_value::<SendMessageData>(client_event.data) {
+                // The message received here is assumed to be already encrypted by the frontend,
+                // including type information within the encrypted payload if needed.
+                let mut message = data.message;
+                // Ensure reply_to is set if provided in the event data
+                if message.reply_to.is_none() {
+                    message.reply_to = data.reply_to_id;
                 }
+                
+                chat_server.send_message(
+                    conn_id,
+                    message, // Pass the EncryptedMessage directly
+                    data.is_group_chat,
+                    data.group_code,
+                    data.message_id,
+                    request_id,
+                ).await?;
+            } else {
+                log::error!("Failed to parse send_message data");
+                send_protocol_error(session, "send_message", &request_id, "invalid data").await;
             }
-            // Handle file sending start
-            "file_sending_start" => {
-                if let Ok(data) = serde_json::from_value::<FileStatusData>(client_event.data) {
-                     chat_server.file_sending_start(
-                        conn_id,
-                        data.file_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse file_sending_start data");
-                }
+        }
+        "message_read" => {
+            if let Ok(data) = serde_json::from_value::<MessageReadData>(client_event.data) {
+                chat_server.message_read(
+                    conn_id,
+                    data.message_id,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse message_read data");
+                send_protocol_error(session, "message_read", &request_id, "invalid data").await;
+            }
+        }
+        "react_message" => {
+            if let Ok(data) = serde_json::from_value::<ReactMessageData>(client_event.data) {
+                chat_server.react_message(
+                    conn_id,
+                    data.message_id,
+                    data.reaction,
+                    data.action,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse react_message data");
+                send_protocol_error(session, "react_message", &request_id, "invalid data").await;
+            }
+        }
+        "typing_start" => {
+            if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
+                chat_server.activity_update(
+                    conn_id,
+                    data.activity,
+                    true,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse typing_start data");
+                send_protocol_error(session, "typing_start", &request_id, "invalid data").await;
+            }
+        }
+        "typing_stop" => {
+            if let Ok(data) = serde_json::from_value::<TypingData>(client_event.data) {
+                chat_server.activity_update(
+                    conn_id,
+                    data.activity,
+                    false,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse typing_stop data");
+                send_protocol_error(session, "typing_stop", &request_id, "invalid data").await;
             }
-            // Handle file sending end
-            "file_sending_end" => {
-                 if let Ok(data) = serde_json::from_value::<FileStatusData>(client_event.data) {
-                     chat_server.file_sending_end(
+        }
+        "activity_update" => {
+            if let Ok(data) = serde_json::from_value::<ActivityData>(client_event.data) {
+                chat_server.activity_update(
+                    conn_id,
+                    data.activity,
+                    data.active,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse activity_update data");
+                send_protocol_error(session, "activity_update", &request_id, "invalid data").await;
+            }
+        }
+        // Handle file sending start
+        "file_sending_start" => {
+            if let Ok(data) = serde_json::from_value::<FileStatusData>(client_event.data) {
+                 chat_server.file_sending_start(
+                    conn_id,
+                    data.file_id,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse file_sending_start data");
+                send_protocol_error(session, "file_sending_start", &request_id, "invalid data").await;
+            }
+        }
+        // Handle file sending end
+        "file_sending_end" => {
+             if let Ok(data) = serde_json::from_value::<FileStatusData>(client_event.data) {
+                 chat_server.file_sending_end(
+                    conn_id,
+                    data.file_id,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse file_sending_end data");
+                send_protocol_error(session, "file_sending_end", &request_id, "invalid data").await;
+            }
+        }
+        "file_progress" => {
+            match serde_json::from_value::<FileProgressData>(client_event.data) {
+                Ok(data) if data.bytes_sent > data.total_bytes => {
+                    send_protocol_error(session, "file_progress", &request_id, "bytes_sent exceeds total_bytes").await;
+                }
+                Ok(data) => {
+                    chat_server.file_progress(
                         conn_id,
                         data.file_id,
+                        data.bytes_sent,
+                        data.total_bytes,
                         data.is_group_chat,
                         data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse file_sending_end data");
+                    ).await?;
                 }
-            }
-            // Handle delete message
-            "delete_message" => {
-                if let Ok(data) = serde_json::from_value::<DeleteMessageData>(client_event.data) {
-                    chat_server.delete_message(
-                        conn_id,
-                        data.message_id,
-                        data.is_group_chat,
-                        data.group_code,
-                    ).await;
-                } else {
-                    log::error!("Failed to parse delete_message data");
+                Err(_) => {
+                    log::error!("Failed to parse file_progress data");
+                    send_protocol_error(session, "file_progress", &request_id, "invalid data").await;
                 }
             }
-            "disconnect_chat" => {
-                chat_server.disconnect_chat(conn_id).await;
+        }
+        // Handle delete message
+        "delete_message" => {
+            if let Ok(data) = serde_json::from_value::<DeleteMessageData>(client_event.data) {
+                chat_server.delete_message(
+                    conn_id,
+                    data.message_id,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse delete_message data");
+                send_protocol_error(session, "delete_message", &request_id, "invalid data").await;
             }
-            _ => {
-                log::warn!("Unknown event type: {}", client_event.event);
+        }
+        "edit_message" => {
+            if let Ok(data) = serde_json::from_value::<EditMessageData>(client_event.data) {
+                chat_server.edit_message(
+                    conn_id,
+                    data.message_id,
+                    data.message,
+                    data.is_group_chat,
+                    data.group_code,
+                ).await?;
+            } else {
+                log::error!("Failed to parse edit_message data");
+                send_protocol_error(session, "edit_message", &request_id, "invalid data").await;
             }
         }
-    } else {
-        log::error!("Failed to parse message as ClientEvent: {}", text);
+        "disconnect_chat" => {
+            chat_server.disconnect_chat(conn_id).await?;
+        }
+        "skip_partner" => {
+            chat_server.skip_partner(conn_id).await?;
+        }
+        "cancel_search" => {
+            chat_server.cancel_search(conn_id).await?;
+        }
+        "update_preference" => {
+            if let Ok(data) = serde_json::from_value::<UpdatePreferenceData>(client_event.data) {
+                chat_server.update_preference(conn_id, data.preference, data.language).await?;
+            } else {
+                log::error!("Failed to parse update_preference data");
+                send_protocol_error(session, "update_preference", &request_id, "invalid data").await;
+            }
+        }
+        "leave_group" => {
+            chat_server.leave_group(conn_id).await?;
+        }
+        "rename_group" => {
+            if let Ok(data) = serde_json::from_value::<RenameGroupData>(client_event.data) {
+                chat_server.rename_group(conn_id, data.name).await?;
+            } else {
+                log::error!("Failed to parse rename_group data");
+                send_protocol_error(session, "rename_group", &request_id, "invalid data").await;
+            }
+        }
+        "list_groups" => {
+            chat_server.list_groups(conn_id).await?;
+        }
+        "get_history" => {
+            if let Ok(data) = serde_json::from_value::<GetHistoryData>(client_event.data) {
+                chat_server.get_history(conn_id, data.group_code, data.before_seq, data.limit).await?;
+            } else {
+                log::error!("Failed to parse get_history data");
+                send_protocol_error(session, "get_history", &request_id, "invalid data").await;
+            }
+        }
+        "my_rooms" => {
+            chat_server.my_rooms(conn_id).await?;
+        }
+        "server_info" => {
+            chat_server.server_info(conn_id).await?;
+        }
+        "online_count" => {
+            chat_server.online_count(conn_id).await?;
+        }
+        "partner_status" => {
+            chat_server.partner_status(conn_id).await?;
+        }
+        "ping_peer" => {
+            if let Ok(data) = serde_json::from_value::<PingPeerData>(client_event.data) {
+                let online = chat_server.ping_peer(conn_id, data.target_id.clone()).await?;
+                send_to_client(session, "peer_status", serde_json::json!({
+                    "target_id": data.target_id,
+                    "online": online,
+                    "request_id": request_id,
+                })).await;
+            } else {
+                log::error!("Failed to parse ping_peer data");
+                send_protocol_error(session, "ping_peer", &request_id, "invalid data").await;
+            }
+        }
+        "lock_group" => {
+            chat_server.lock_group(conn_id).await?;
+        }
+        "unlock_group" => {
+            chat_server.unlock_group(conn_id).await?;
+        }
+        "kick_member" => {
+            if let Ok(data) = serde_json::from_value::<KickMemberData>(client_event.data) {
+                chat_server.kick_member(conn_id, data.target).await?;
+            } else {
+                log::error!("Failed to parse kick_member data");
+                send_protocol_error(session, "kick_member", &request_id, "invalid data").await;
+            }
+        }
+        "update_presence" => {
+            if let Ok(data) = serde_json::from_value::<UpdatePresenceData>(client_event.data) {
+                chat_server.update_presence(conn_id, data.status).await?;
+            } else {
+                log::error!("Failed to parse update_presence data");
+                send_protocol_error(session, "update_presence", &request_id, "invalid data").await;
+            }
+        }
+        "block_user" => {
+            if let Ok(data) = serde_json::from_value::<BlockUserData>(client_event.data) {
+                chat_server.block_user(conn_id, data.user_id).await?;
+            } else {
+                log::error!("Failed to parse block_user data");
+                send_protocol_error(session, "block_user", &request_id, "invalid data").await;
+            }
+        }
+        "report_user" => {
+            if let Ok(data) = serde_json::from_value::<ReportUserData>(client_event.data) {
+                chat_server.report_user(conn_id, data.target, data.reason).await?;
+            } else {
+                log::error!("Failed to parse report_user data");
+                send_protocol_error(session, "report_user", &request_id, "invalid data").await;
+            }
+        }
+        _ => {
+            log::warn!("Unknown event type: {}", client_event.event);
+        }
+    }
+    Ok(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ChatServer;
+
+    /// Keeps every text frame the handler sends straight back to the client, and how it was closed
+    #[derive(Default)]
+    struct RecordingSocket {
+        sent: Vec<String>,
+        pings: usize,
+        closed: Option<Option<CloseReason>>,
+    }
+
+    impl ClientSocket for RecordingSocket {
+        async fn text(&mut self, text: String) -> Result<(), Closed> {
+            self.sent.push(text);
+            Ok(())
+        }
+
+        async fn binary(&mut self, _bytes: Vec<u8>) -> Result<(), Closed> {
+            Ok(())
+        }
+
+        async fn ping(&mut self, _bytes: &[u8]) -> Result<(), Closed> {
+            self.pings += 1;
+            Ok(())
+        }
+
+        async fn pong(&mut self, _bytes: &[u8]) -> Result<(), Closed> {
+            Ok(())
+        }
+
+        async fn close(&mut self, reason: Option<CloseReason>) -> Result<(), Closed> {
+            self.closed = Some(reason);
+            Ok(())
+        }
+    }
+
+    impl RecordingSocket {
+        // Every event sent since the last call, as (name, data)
+        fn events(&mut self) -> Vec<(String, serde_json::Value)> {
+            self.sent.drain(..)
+                .map(|text| {
+                    let mut event: serde_json::Value = serde_json::from_str(&text).unwrap();
+                    (event["event"].as_str().unwrap().to_string(), event["data"].take())
+                })
+                .collect()
+        }
+    }
+
+    fn code_of(reason: DisconnectReason) -> Option<CloseCode> {
+        reason.close_reason().map(|reason| reason.code)
+    }
+
+    #[test]
+    fn each_disconnect_reason_closes_with_its_own_code() {
+        assert_eq!(code_of(DisconnectReason::Timeout), Some(CloseCode::Other(4000)));
+        assert_eq!(code_of(DisconnectReason::TooManyConnections), Some(CloseCode::Other(4001)));
+        assert_eq!(code_of(DisconnectReason::JoinTimeout), Some(CloseCode::Other(4002)));
+        assert_eq!(code_of(DisconnectReason::ProtocolError), Some(CloseCode::Protocol));
+        assert_eq!(code_of(DisconnectReason::PayloadTooLarge), Some(CloseCode::Size));
+        assert_eq!(code_of(DisconnectReason::ServerShutdown), Some(CloseCode::Restart));
+        assert_eq!(code_of(DisconnectReason::RateLimitBan), Some(CloseCode::Policy));
+        assert_eq!(code_of(DisconnectReason::SlowConsumer), Some(CloseCode::Policy));
+        assert_eq!(code_of(DisconnectReason::ConnectionLost), None, "nobody is left to receive a close frame");
+    }
+
+    #[test]
+    fn a_client_close_is_echoed_back_unchanged() {
+        let reason = CloseReason { code: CloseCode::Away, description: Some("tab closed".to_string()) };
+        assert_eq!(DisconnectReason::ClientClosed(Some(reason.clone())).close_reason(), Some(reason));
+        assert_eq!(DisconnectReason::ClientClosed(None).close_reason(), None);
+    }
+
+    #[test]
+    fn ban_and_slow_consumer_closes_are_told_apart_by_description() {
+        let ban = DisconnectReason::RateLimitBan.close_reason().unwrap();
+        let slow = DisconnectReason::SlowConsumer.close_reason().unwrap();
+        assert_ne!(ban.description, slow.description);
+    }
+
+    const TIMEOUTS: ConnectionTimeouts = ConnectionTimeouts {
+        heartbeat_interval: Duration::from_secs(5),
+        client_timeout: Duration::from_secs(60),
+        join_grace: Duration::from_secs(30),
+    };
+
+    // Run a connection whose client sends `frames` and then hangs up, returning what it was sent
+    async fn run_client(frames: Vec<Result<AggregatedMessage, ProtocolError>>) -> RecordingSocket {
+        let mut socket = RecordingSocket::default();
+        let stream = futures_util::stream::iter(frames);
+        run_connection(ChatServer::start(), &mut socket, stream, None, "10.0.0.1".to_string(), TIMEOUTS).await;
+        socket
+    }
+
+    #[tokio::test]
+    async fn a_stream_that_just_ends_finishes_the_connection_without_a_panic() {
+        let socket = run_client(vec![]).await;
+        assert_eq!(socket.closed, Some(None));
+
+        let goodbye = CloseReason { code: CloseCode::Normal, description: None };
+        let socket = run_client(vec![Ok(AggregatedMessage::Close(Some(goodbye.clone())))]).await;
+        assert_eq!(socket.closed, Some(Some(goodbye)), "a client's close is echoed back");
+        let socket = run_client(vec![Ok(AggregatedMessage::Close(None))]).await;
+        assert_eq!(socket.closed, Some(None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_short_client_timeout_disconnects_an_idle_client() {
+        let timeouts = ConnectionTimeouts {
+            heartbeat_interval: Duration::from_secs(1),
+            client_timeout: Duration::from_secs(3),
+            join_grace: Duration::from_secs(600),
+        };
+        let mut socket = RecordingSocket::default();
+        // A client that stays connected but never sends anything, not even a pong
+        let idle = futures_util::stream::pending();
+        let started = Instant::now();
+        run_connection(ChatServer::start(), &mut socket, idle, None, "10.0.0.1".to_string(), timeouts).await;
+        assert_eq!(socket.closed.unwrap().unwrap().code, CloseCode::Other(4000));
+        let waited = started.elapsed();
+        assert!(waited > Duration::from_secs(3) && waited < Duration::from_secs(5), "closed just after the timeout, not the join grace: {:?}", waited);
+        assert!(socket.pings > 0, "it was pinged while it had the chance to answer");
+    }
+
+    // Everything the log subscriber writes, shared with the test that reads it back
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn log_lines_come_out_as_json_carrying_the_connection_id() {
+        // Another test may have installed the bridge already; either way `log` records now reach tracing
+        let _ = tracing_log::LogTracer::init();
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(crate::log_subscriber(move || writer.clone()));
+
+        let mut socket = RecordingSocket::default();
+        let stream = futures_util::stream::iter(vec![]);
+        run_connection(ChatServer::start(), &mut socket, stream, None, "10.0.0.1".to_string(), TIMEOUTS)
+            .instrument(connection_span("10.0.0.1"))
+            .await;
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let connected = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("every line is JSON"))
+            .find(|line| line["fields"]["message"].as_str().is_some_and(|message| message.starts_with("Client connected with ID")))
+            .expect("the connect line was logged");
+        let conn_id = connected["span"]["conn_id"].as_str().expect("the line carries the connection span");
+        assert_eq!(connected["fields"]["message"], format!("Client connected with ID: {}", conn_id));
+        assert_eq!(connected["span"]["ip"], "10.0.0.1");
+    }
+
+    // A session registered straight with the chat server, its frames kept on the returned receiver
+    async fn registered(chat_server: &ChatServerHandle) -> (ConnId, mpsc::Receiver<Msg>, oneshot::Receiver<SessionEnd>) {
+        let (tx, rx) = mpsc::channel(64);
+        let (end_tx, end_rx) = oneshot::channel();
+        let (conn, _) = chat_server.connect(tx, end_tx, None, "10.0.0.1".to_string()).await.unwrap().unwrap();
+        (conn, rx, end_rx)
+    }
+
+    fn group_profile(user_id: &str, join_method: &str, group_code: Option<&str>) -> UserProfile {
+        serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "username": user_id,
+            "preference": "any",
+            "gender": "male",
+            "room_type": "group",
+            "group_join_method": join_method,
+            "group_code": group_code,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_relayed_event_logs_the_sender_event_type_and_room() {
+        let _ = tracing_log::LogTracer::init();
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(crate::log_subscriber(move || writer.clone()));
+
+        let chat_server = ChatServer::start();
+        let (a, mut a_rx, _a_end) = registered(&chat_server).await;
+        let (b, _b_rx, _b_end) = registered(&chat_server).await;
+        chat_server.join_chat(a.clone(), group_profile("ua", "create", None)).await.unwrap();
+        let mut code = None;
+        while let Ok(Msg::Text(text)) = a_rx.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if event["event"] == "group_joined" {
+                code = event["data"]["groupCode"].as_str().map(str::to_string);
+            }
+        }
+        let code = code.expect("the creator is told the group code");
+        chat_server.join_chat(b.clone(), group_profile("ub", "join", Some(&code))).await.unwrap();
+        chat_server.relay_webrtc_event(a.clone(), "offer".to_string(), b, serde_json::json!({ "sdp": "v=0" }), true, Some(code.clone())).await.unwrap();
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let relayed = output.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("every line is JSON"))
+            .find(|line| line["span"]["name"] == "relay")
+            .expect("the relay was logged");
+        assert_eq!(relayed["span"]["sender_id"], a.as_str());
+        assert_eq!(relayed["span"]["event_type"], "offer");
+        assert_eq!(relayed["span"]["room"], code.as_str());
+    }
+
+    #[tokio::test]
+    async fn an_oversized_payload_is_reported_and_closed_with_the_size_code() {
+        let mut socket = run_client(vec![Err(ProtocolError::Overflow)]).await;
+        let sent = socket.events();
+        assert_eq!(sent.last().unwrap(), &("stream_error".to_string(), serde_json::json!({
+            "reason": "payload_too_large",
+            "max_payload_size": MAX_PAYLOAD_SIZE,
+        })));
+        assert_eq!(socket.closed.unwrap().unwrap().code, CloseCode::Size);
+    }
+
+    #[tokio::test]
+    async fn a_malformed_frame_is_reported_and_closed_with_the_protocol_code() {
+        let mut socket = run_client(vec![Err(ProtocolError::UnmaskedFrame)]).await;
+        let (name, data) = socket.events().pop().unwrap();
+        assert_eq!(name, "stream_error");
+        assert_eq!(data["reason"], "malformed_frame");
+        assert_eq!(data["detail"], ProtocolError::UnmaskedFrame.to_string());
+        assert_eq!(socket.closed.unwrap().unwrap().code, CloseCode::Protocol);
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn a_transport_failure_closes_without_a_notice() {
+        let failure = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset by peer");
+        let mut socket = run_client(vec![Err(ProtocolError::Io(failure))]).await;
+        assert!(socket.events().iter().all(|(name, _)| name != "stream_error"));
+        assert_eq!(socket.closed, Some(None), "there is nobody left to read a close reason");
+    }
+
+    #[test]
+    fn group_codes_must_be_short_and_ascii_alphanumeric() {
+        let overlong = "A".repeat(MAX_GROUP_CODE_LEN + 1);
+        let longest = "A".repeat(MAX_GROUP_CODE_LEN);
+        for (code, valid) in [
+            ("", false),
+            (overlong.as_str(), false),
+            ("ABC-12", false),
+            ("ABC 12", false),
+            ("../etc", false),
+            ("ÄBC123", false),
+            ("ABC１２３", false),
+            ("abc123", true),
+            ("X7K2QP", true),
+            (longest.as_str(), true),
+        ] {
+            assert_eq!(is_valid_group_code(code), valid, "{:?}", code);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_malformed_code_is_answered_with_invalid_group_code_before_reaching_the_server() {
+        // Any request that got through would fail against a server that is already gone
+        let chat_server = ChatServerHandle::disconnected();
+        let mut socket = RecordingSocket::default();
+        for (field, code) in [("group_code", "A".repeat(MAX_GROUP_CODE_LEN + 1)), ("pair_code", "AB;CD".to_string())] {
+            let text = serde_json::json!({
+                "event": "join_chat",
+                "data": { field: code },
+                "request_id": "r1",
+            }).to_string();
+            assert!(!process_text_msg(&chat_server, &mut socket, &text, "conn".to_string()).await.unwrap());
+            let sent = socket.events();
+            assert_eq!(sent.len(), 1, "{:?}", sent);
+            assert_eq!(sent[0].0, "invalid_group_code");
+            assert_eq!(sent[0].1, serde_json::json!({ "event": "join_chat", "field": field, "request_id": "r1" }));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_well_formed_code_is_passed_on_to_the_server() {
+        let chat_server = ChatServerHandle::disconnected();
+        let mut socket = RecordingSocket::default();
+        let text = serde_json::json!({
+            "event": "join_chat",
+            "data": {
+                "user_id": "u1",
+                "username": "ann",
+                "gender": "female",
+                "preference": "any",
+                "room_type": "group",
+                "group_join_method": "join",
+                "group_code": "X7K2QP",
+            },
+        }).to_string();
+        let result = process_text_msg(&chat_server, &mut socket, &text, "conn".to_string()).await;
+        assert!(matches!(result, Err(ServerUnavailable)), "the join went on to the server");
+        assert!(socket.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn request_id_comes_back_on_the_protocol_error_for_its_event() {
+        let chat_server = ChatServerHandle::disconnected();
+        let mut socket = RecordingSocket::default();
+        let text = serde_json::json!({ "event": "send_message", "data": { "oops": true }, "request_id": "req-7" }).to_string();
+        process_text_msg(&chat_server, &mut socket, &text, "conn".to_string()).await.unwrap();
+        let sent = socket.events();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "protocol_error");
+        assert_eq!(sent[0].1, serde_json::json!({ "event": "send_message", "request_id": "req-7", "error": "invalid data" }));
+
+        process_text_msg(&chat_server, &mut socket, "not json", "conn".to_string()).await.unwrap();
+        assert_eq!(socket.events()[0].1["request_id"], serde_json::Value::Null, "an unparsable frame has no id to echo");
+    }
+
+    #[tokio::test]
+    async fn request_id_comes_back_on_the_message_sent_ack() {
+        let chat_server = crate::server::ChatServer::start();
+        let (conn_tx, mut conn_rx) = mpsc::channel(16);
+        let (end_tx, _end_rx) = oneshot::channel();
+        let (conn, _) = chat_server.connect(conn_tx, end_tx, None, "10.0.0.1".to_string()).await.unwrap().unwrap();
+        let mut socket = RecordingSocket::default();
+        let join = serde_json::json!({
+            "event": "join_chat",
+            "data": { "user_id": "u1", "username": "ann", "gender": "female", "preference": "any", "room_type": "chat" },
+        }).to_string();
+        assert!(process_text_msg(&chat_server, &mut socket, &join, conn.clone()).await.unwrap());
+
+        let send = serde_json::json!({
+            "event": "send_message",
+            "data": {
+                "message": { "encrypted": "Y2lwaGVydGV4dA==", "nonce": "AAAAAAAAAAAAAAAA" },
+                "is_group_chat": false,
+                "message_id": "m1",
+            },
+            "request_id": "req-8",
+        }).to_string();
+        process_text_msg(&chat_server, &mut socket, &send, conn).await.unwrap();
+        let mut acks = Vec::new();
+        while let Ok(Msg::Text(text)) = conn_rx.try_recv() {
+            let event: serde_json::Value = serde_json::from_str(&text).unwrap();
+            if event["event"] == "message_sent" {
+                acks.push(event["data"].clone());
+            }
+        }
+        assert_eq!(acks.iter().map(|ack| ack["request_id"].clone()).collect::<Vec<_>>(), [serde_json::json!("req-8")]);
+    }
+}
@@ -1,9 +1,122 @@
 // keys.rs
 use shuttle_runtime::SecretStore;
+use std::str::FromStr;
 use std::sync::OnceLock;
+use std::time::Duration;
 
 static WHICH_NODE_ENV: OnceLock<String> = OnceLock::new();
 static ALLOWED_ORIGIN: OnceLock<String> = OnceLock::new();
+static ADMIN_TOKEN: OnceLock<String> = OnceLock::new();
+static ICE_SERVERS: OnceLock<Vec<String>> = OnceLock::new();
+static MATCH_TIMEOUT_SECS: OnceLock<u64> = OnceLock::new();
+static BROADEN_ON_MATCH_TIMEOUT: OnceLock<bool> = OnceLock::new();
+static REMATCH_COOLDOWN_SECS: OnceLock<u64> = OnceLock::new();
+static GROUP_HISTORY_SIZE: OnceLock<usize> = OnceLock::new();
+static ABUSE_REPORT_THRESHOLD: OnceLock<usize> = OnceLock::new();
+static MAX_FILE_TRANSFERS: OnceLock<usize> = OnceLock::new();
+static MAX_GROUP_SIZE: OnceLock<usize> = OnceLock::new();
+static MESSAGE_RATE: OnceLock<(f64, f64)> = OnceLock::new();
+static MAX_MESSAGE_SIZE: OnceLock<usize> = OnceLock::new();
+static MAX_CONNECTIONS_PER_IP: OnceLock<usize> = OnceLock::new();
+static CALL_RING_TIMEOUT_SECS: OnceLock<u64> = OnceLock::new();
+static TURN_SECRET: OnceLock<String> = OnceLock::new();
+static TURN_URLS: OnceLock<Vec<String>> = OnceLock::new();
+static TURN_TTL_SECS: OnceLock<u64> = OnceLock::new();
+static HEARTBEAT_INTERVAL_SECS: OnceLock<u64> = OnceLock::new();
+static CLIENT_TIMEOUT_SECS: OnceLock<u64> = OnceLock::new();
+static TYPING_TIMEOUT_SECS: OnceLock<u64> = OnceLock::new();
+static JOIN_GRACE_SECS: OnceLock<u64> = OnceLock::new();
+static SESSION_QUEUE_CAPACITY: OnceLock<usize> = OnceLock::new();
+static PRESENCE_AWAY_SECS: OnceLock<u64> = OnceLock::new();
+static TRUSTED_PROXY_HOPS: OnceLock<usize> = OnceLock::new();
+
+/// How long a user may wait for a match before waiting-related timeouts kick in
+const DEFAULT_MATCH_TIMEOUT_SECS: u64 = 60;
+
+/// Whether a waiter who hits the match timeout stops filtering partners by gender
+const DEFAULT_BROADEN_ON_MATCH_TIMEOUT: bool = false;
+
+/// How long two users who just parted are kept from being matched with each other again
+const DEFAULT_REMATCH_COOLDOWN_SECS: u64 = 120;
+
+/// How many encrypted messages a group that opted into history keeps for late joiners
+const DEFAULT_GROUP_HISTORY_SIZE: usize = 50;
+
+/// How many different users must report someone before they are blocked from matching
+const DEFAULT_ABUSE_REPORT_THRESHOLD: usize = 3;
+
+/// How many files one connection may be sending at the same time
+const DEFAULT_MAX_FILE_TRANSFERS: usize = 5;
+
+/// Outgoing frames queued per session before further frames are dropped
+const DEFAULT_SESSION_QUEUE_CAPACITY: usize = 256;
+
+/// Largest number of members any group may hold
+const DEFAULT_MAX_GROUP_SIZE: usize = 10;
+
+/// Messages a connection may send in one burst before rate limiting applies
+const DEFAULT_MESSAGE_BURST: f64 = 10.0;
+
+/// Messages per second a connection regains after using up its burst
+const DEFAULT_MESSAGE_REFILL_PER_SEC: f64 = 5.0;
+
+/// Largest `encrypted` payload (base64 bytes) relayed in a single message
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+/// Concurrent sessions allowed from one client IP
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 5;
+
+/// How long an outgoing call may ring before both sides get `call_timeout`
+const DEFAULT_CALL_RING_TIMEOUT_SECS: u64 = 30;
+
+/// How long generated TURN credentials stay valid
+const DEFAULT_TURN_TTL_SECS: u64 = 86400;
+
+/// How often heartbeat pings are sent to each client
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 5;
+
+/// How long before lack of client response causes a timeout
+const DEFAULT_CLIENT_TIMEOUT_SECS: u64 = 3600;
+
+/// How long a typing indicator stays up without a refresh, message or stop
+const DEFAULT_TYPING_TIMEOUT_SECS: u64 = 6;
+
+/// How long a user can go without sending messages or activity before they are shown as away
+const DEFAULT_PRESENCE_AWAY_SECS: u64 = 300;
+
+/// Reverse proxies in front of the server that append to X-Forwarded-For (Shuttle runs one)
+const DEFAULT_TRUSTED_PROXY_HOPS: usize = 1;
+
+/// How long a fresh connection may stay open without sending `join_chat`
+const DEFAULT_JOIN_GRACE_SECS: u64 = 30;
+
+/// STUN servers handed to clients when ICE_SERVERS is not configured
+const DEFAULT_ICE_SERVERS: &[&str] = &[
+    "stun:stun.l.google.com:19302",
+    "stun:stun1.l.google.com:19302",
+];
+
+// Parse an optional numeric or boolean secret, falling back to `default` when it is absent or malformed
+fn parse_or_default<T: FromStr>(secrets: &SecretStore, key: &str, default: T) -> T {
+    match secrets.get(key) {
+        Some(value) => value.trim().parse().unwrap_or_else(|_| {
+            log::warn!("{} is not a valid value; using the default", key);
+            default
+        }),
+        None => default,
+    }
+}
+
+// As parse_or_default, for limits where zero would switch the feature off entirely (refuse every
+// connection, time every waiter out at once); a zero is treated as a mistake and the default used
+fn parse_nonzero_or_default<T: FromStr + Default + PartialEq + Copy>(secrets: &SecretStore, key: &str, default: T) -> T {
+    let value = parse_or_default(secrets, key, default);
+    if value == T::default() {
+        log::warn!("{} must not be 0; using the default", key);
+        return default;
+    }
+    value
+}
 
 pub fn init_secrets(secrets: &SecretStore) {
     // Initialize WHICH_NODE_ENV
@@ -18,6 +131,115 @@ pub fn init_secrets(secrets: &SecretStore) {
     ALLOWED_ORIGIN.set(allowed_origin.clone())
         .expect("ALLOWED_ORIGIN already initialized");
 
+    // Initialize ADMIN_TOKEN (optional; admin routes are disabled without it)
+    if let Some(admin_token) = secrets.get("ADMIN_TOKEN") {
+        ADMIN_TOKEN.set(admin_token)
+            .expect("ADMIN_TOKEN already initialized");
+    }
+
+    // Initialize ICE_SERVERS (optional, comma-separated urls)
+    let ice_servers = match secrets.get("ICE_SERVERS") {
+        Some(urls) => urls.split(',').map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect(),
+        None => DEFAULT_ICE_SERVERS.iter().map(|url| url.to_string()).collect(),
+    };
+    ICE_SERVERS.set(ice_servers)
+        .expect("ICE_SERVERS already initialized");
+
+    // Initialize MATCH_TIMEOUT_SECS (optional; 0 is rejected)
+    MATCH_TIMEOUT_SECS.set(parse_nonzero_or_default(secrets, "MATCH_TIMEOUT_SECS", DEFAULT_MATCH_TIMEOUT_SECS))
+        .expect("MATCH_TIMEOUT_SECS already initialized");
+
+    // Initialize BROADEN_ON_MATCH_TIMEOUT (optional)
+    BROADEN_ON_MATCH_TIMEOUT.set(parse_or_default(secrets, "BROADEN_ON_MATCH_TIMEOUT", DEFAULT_BROADEN_ON_MATCH_TIMEOUT))
+        .expect("BROADEN_ON_MATCH_TIMEOUT already initialized");
+
+    // Initialize REMATCH_COOLDOWN_SECS (optional)
+    REMATCH_COOLDOWN_SECS.set(parse_or_default(secrets, "REMATCH_COOLDOWN_SECS", DEFAULT_REMATCH_COOLDOWN_SECS))
+        .expect("REMATCH_COOLDOWN_SECS already initialized");
+
+    // Initialize GROUP_HISTORY_SIZE (optional)
+    GROUP_HISTORY_SIZE.set(parse_or_default(secrets, "GROUP_HISTORY_SIZE", DEFAULT_GROUP_HISTORY_SIZE))
+        .expect("GROUP_HISTORY_SIZE already initialized");
+
+    // Initialize ABUSE_REPORT_THRESHOLD (optional; at least one report is always required)
+    ABUSE_REPORT_THRESHOLD.set(parse_or_default(secrets, "ABUSE_REPORT_THRESHOLD", DEFAULT_ABUSE_REPORT_THRESHOLD).max(1))
+        .expect("ABUSE_REPORT_THRESHOLD already initialized");
+
+    // Initialize MAX_FILE_TRANSFERS (optional; 0 is rejected)
+    MAX_FILE_TRANSFERS.set(parse_nonzero_or_default(secrets, "MAX_FILE_TRANSFERS", DEFAULT_MAX_FILE_TRANSFERS))
+        .expect("MAX_FILE_TRANSFERS already initialized");
+
+    // Initialize MAX_GROUP_SIZE (optional; a group always holds at least two members)
+    MAX_GROUP_SIZE.set(parse_or_default(secrets, "MAX_GROUP_SIZE", DEFAULT_MAX_GROUP_SIZE).max(2))
+        .expect("MAX_GROUP_SIZE already initialized");
+
+    // Initialize MESSAGE_BURST / MESSAGE_REFILL_PER_SEC (optional; both must be positive)
+    let burst: f64 = parse_or_default(secrets, "MESSAGE_BURST", DEFAULT_MESSAGE_BURST);
+    let refill: f64 = parse_or_default(secrets, "MESSAGE_REFILL_PER_SEC", DEFAULT_MESSAGE_REFILL_PER_SEC);
+    let burst = if burst >= 1.0 { burst } else { DEFAULT_MESSAGE_BURST };
+    let refill = if refill > 0.0 { refill } else { DEFAULT_MESSAGE_REFILL_PER_SEC };
+    MESSAGE_RATE.set((burst, refill))
+        .expect("MESSAGE_RATE already initialized");
+
+    // Initialize MAX_MESSAGE_SIZE (optional)
+    MAX_MESSAGE_SIZE.set(parse_or_default(secrets, "MAX_MESSAGE_SIZE", DEFAULT_MAX_MESSAGE_SIZE))
+        .expect("MAX_MESSAGE_SIZE already initialized");
+
+    // Initialize MAX_CONNECTIONS_PER_IP (optional; 0 is rejected)
+    MAX_CONNECTIONS_PER_IP.set(parse_nonzero_or_default(secrets, "MAX_CONNECTIONS_PER_IP", DEFAULT_MAX_CONNECTIONS_PER_IP))
+        .expect("MAX_CONNECTIONS_PER_IP already initialized");
+
+    // Initialize CALL_RING_TIMEOUT_SECS (optional)
+    CALL_RING_TIMEOUT_SECS.set(parse_or_default(secrets, "CALL_RING_TIMEOUT_SECS", DEFAULT_CALL_RING_TIMEOUT_SECS))
+        .expect("CALL_RING_TIMEOUT_SECS already initialized");
+
+    // Initialize TURN_SECRET / TURN_URLS (optional; /turn-credentials is disabled without both)
+    if let Some(turn_secret) = secrets.get("TURN_SECRET") {
+        TURN_SECRET.set(turn_secret)
+            .expect("TURN_SECRET already initialized");
+    }
+    let turn_urls = secrets.get("TURN_URLS")
+        .map(|urls| urls.split(',').map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect())
+        .unwrap_or_default();
+    TURN_URLS.set(turn_urls)
+        .expect("TURN_URLS already initialized");
+    TURN_TTL_SECS.set(parse_or_default(secrets, "TURN_TTL_SECS", DEFAULT_TURN_TTL_SECS))
+        .expect("TURN_TTL_SECS already initialized");
+
+    // Initialize HEARTBEAT_INTERVAL_SECS / CLIENT_TIMEOUT_SECS (optional); a timeout no longer
+    // than the ping interval would disconnect healthy clients, so refuse to start with one
+    let heartbeat_interval = parse_or_default(secrets, "HEARTBEAT_INTERVAL_SECS", DEFAULT_HEARTBEAT_INTERVAL_SECS).max(1);
+    let client_timeout = parse_or_default(secrets, "CLIENT_TIMEOUT_SECS", DEFAULT_CLIENT_TIMEOUT_SECS);
+    assert!(
+        client_timeout > heartbeat_interval,
+        "CLIENT_TIMEOUT_SECS ({}) must be greater than HEARTBEAT_INTERVAL_SECS ({})",
+        client_timeout,
+        heartbeat_interval,
+    );
+    HEARTBEAT_INTERVAL_SECS.set(heartbeat_interval)
+        .expect("HEARTBEAT_INTERVAL_SECS already initialized");
+    CLIENT_TIMEOUT_SECS.set(client_timeout)
+        .expect("CLIENT_TIMEOUT_SECS already initialized");
+
+    // Initialize TYPING_TIMEOUT_SECS (optional)
+    TYPING_TIMEOUT_SECS.set(parse_or_default(secrets, "TYPING_TIMEOUT_SECS", DEFAULT_TYPING_TIMEOUT_SECS))
+        .expect("TYPING_TIMEOUT_SECS already initialized");
+
+    // Initialize JOIN_GRACE_SECS (optional)
+    JOIN_GRACE_SECS.set(parse_or_default(secrets, "JOIN_GRACE_SECS", DEFAULT_JOIN_GRACE_SECS))
+        .expect("JOIN_GRACE_SECS already initialized");
+
+    // Initialize SESSION_QUEUE_CAPACITY (optional; a channel needs room for at least one frame)
+    SESSION_QUEUE_CAPACITY.set(parse_or_default(secrets, "SESSION_QUEUE_CAPACITY", DEFAULT_SESSION_QUEUE_CAPACITY).max(1))
+        .expect("SESSION_QUEUE_CAPACITY already initialized");
+
+    // Initialize PRESENCE_AWAY_SECS (optional)
+    PRESENCE_AWAY_SECS.set(parse_or_default(secrets, "PRESENCE_AWAY_SECS", DEFAULT_PRESENCE_AWAY_SECS))
+        .expect("PRESENCE_AWAY_SECS already initialized");
+
+    // Initialize TRUSTED_PROXY_HOPS (optional; 0 ignores X-Forwarded-For entirely)
+    TRUSTED_PROXY_HOPS.set(parse_or_default(secrets, "TRUSTED_PROXY_HOPS", DEFAULT_TRUSTED_PROXY_HOPS))
+        .expect("TRUSTED_PROXY_HOPS already initialized");
 }
 
 pub fn get_which_node_env_url() -> &'static str {
@@ -26,4 +248,141 @@ pub fn get_which_node_env_url() -> &'static str {
 
 pub fn get_allowed_origin() -> &'static str {
     ALLOWED_ORIGIN.get().expect("ALLOWED_ORIGIN not initialized")
-}
\ No newline at end of file
+}
+
+pub fn get_admin_token() -> Option<&'static str> {
+    ADMIN_TOKEN.get().map(String::as_str)
+}
+
+// The defaults until init_secrets runs, which unit tests never do
+pub fn get_ice_servers() -> &'static [String] {
+    ICE_SERVERS.get_or_init(|| DEFAULT_ICE_SERVERS.iter().map(|url| url.to_string()).collect())
+}
+
+// Single source of truth for waiting-related timeouts; defaults when the secret is absent
+pub fn get_match_timeout() -> Duration {
+    Duration::from_secs(MATCH_TIMEOUT_SECS.get().copied().unwrap_or(DEFAULT_MATCH_TIMEOUT_SECS))
+}
+
+pub fn get_max_file_transfers() -> usize {
+    MAX_FILE_TRANSFERS.get().copied().unwrap_or(DEFAULT_MAX_FILE_TRANSFERS)
+}
+
+pub fn get_max_group_size() -> usize {
+    MAX_GROUP_SIZE.get().copied().unwrap_or(DEFAULT_MAX_GROUP_SIZE)
+}
+
+// (burst, refill per second) for the per-connection message rate limiter
+pub fn get_message_rate() -> (f64, f64) {
+    MESSAGE_RATE.get().copied().unwrap_or((DEFAULT_MESSAGE_BURST, DEFAULT_MESSAGE_REFILL_PER_SEC))
+}
+
+pub fn get_max_message_size() -> usize {
+    MAX_MESSAGE_SIZE.get().copied().unwrap_or(DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+pub fn get_max_connections_per_ip() -> usize {
+    MAX_CONNECTIONS_PER_IP.get().copied().unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP)
+}
+
+pub fn get_call_ring_timeout() -> Duration {
+    Duration::from_secs(CALL_RING_TIMEOUT_SECS.get().copied().unwrap_or(DEFAULT_CALL_RING_TIMEOUT_SECS))
+}
+
+// Shared secret and relay urls for time-limited TURN credentials, when both are configured
+pub fn get_turn_config() -> Option<(&'static str, &'static [String])> {
+    let secret = TURN_SECRET.get()?;
+    let urls = TURN_URLS.get().filter(|urls| !urls.is_empty())?;
+    Some((secret.as_str(), urls.as_slice()))
+}
+
+pub fn get_turn_ttl() -> Duration {
+    Duration::from_secs(TURN_TTL_SECS.get().copied().unwrap_or(DEFAULT_TURN_TTL_SECS))
+}
+
+pub fn get_heartbeat_interval() -> Duration {
+    Duration::from_secs(HEARTBEAT_INTERVAL_SECS.get().copied().unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS))
+}
+
+pub fn get_client_timeout() -> Duration {
+    Duration::from_secs(CLIENT_TIMEOUT_SECS.get().copied().unwrap_or(DEFAULT_CLIENT_TIMEOUT_SECS))
+}
+
+pub fn get_typing_timeout() -> Duration {
+    Duration::from_secs(TYPING_TIMEOUT_SECS.get().copied().unwrap_or(DEFAULT_TYPING_TIMEOUT_SECS))
+}
+
+pub fn get_broaden_on_match_timeout() -> bool {
+    BROADEN_ON_MATCH_TIMEOUT.get().copied().unwrap_or(DEFAULT_BROADEN_ON_MATCH_TIMEOUT)
+}
+
+pub fn get_rematch_cooldown() -> Duration {
+    Duration::from_secs(REMATCH_COOLDOWN_SECS.get().copied().unwrap_or(DEFAULT_REMATCH_COOLDOWN_SECS))
+}
+
+pub fn get_group_history_size() -> usize {
+    GROUP_HISTORY_SIZE.get().copied().unwrap_or(DEFAULT_GROUP_HISTORY_SIZE)
+}
+
+pub fn get_abuse_report_threshold() -> usize {
+    ABUSE_REPORT_THRESHOLD.get().copied().unwrap_or(DEFAULT_ABUSE_REPORT_THRESHOLD)
+}
+
+pub fn get_join_grace() -> Duration {
+    Duration::from_secs(JOIN_GRACE_SECS.get().copied().unwrap_or(DEFAULT_JOIN_GRACE_SECS))
+}
+
+pub fn get_session_queue_capacity() -> usize {
+    SESSION_QUEUE_CAPACITY.get().copied().unwrap_or(DEFAULT_SESSION_QUEUE_CAPACITY)
+}
+
+pub fn get_presence_away_after() -> Duration {
+    Duration::from_secs(PRESENCE_AWAY_SECS.get().copied().unwrap_or(DEFAULT_PRESENCE_AWAY_SECS))
+}
+
+pub fn get_trusted_proxy_hops() -> usize {
+    TRUSTED_PROXY_HOPS.get().copied().unwrap_or(DEFAULT_TRUSTED_PROXY_HOPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn secrets(entries: &[(&str, &str)]) -> SecretStore {
+        SecretStore::new(entries.iter().map(|(key, value)| (key.to_string(), value.to_string().into())).collect::<BTreeMap<_, _>>())
+    }
+
+    #[test]
+    fn a_missing_secret_falls_back_to_the_default() {
+        let secrets = secrets(&[]);
+        assert_eq!(parse_or_default(&secrets, "MATCH_TIMEOUT_SECS", 60u64), 60);
+        assert!(!parse_or_default(&secrets, "BROADEN_ON_MATCH_TIMEOUT", false));
+    }
+
+    #[test]
+    fn a_malformed_secret_falls_back_to_the_default() {
+        let secrets = secrets(&[("MATCH_TIMEOUT_SECS", "soon"), ("MAX_GROUP_SIZE", "-3"), ("BROADEN_ON_MATCH_TIMEOUT", "yes"), ("MESSAGE_BURST", "")]);
+        assert_eq!(parse_or_default(&secrets, "MATCH_TIMEOUT_SECS", 60u64), 60);
+        assert_eq!(parse_or_default(&secrets, "MAX_GROUP_SIZE", 10usize), 10);
+        assert!(!parse_or_default(&secrets, "BROADEN_ON_MATCH_TIMEOUT", false));
+        assert_eq!(parse_or_default(&secrets, "MESSAGE_BURST", 10.0f64), 10.0);
+    }
+
+    #[test]
+    fn a_zero_limit_falls_back_to_the_default() {
+        let secrets = secrets(&[("MAX_CONNECTIONS_PER_IP", "0"), ("MATCH_TIMEOUT_SECS", " 0 "), ("MAX_FILE_TRANSFERS", "2")]);
+        assert_eq!(parse_nonzero_or_default(&secrets, "MAX_CONNECTIONS_PER_IP", 5usize), 5);
+        assert_eq!(parse_nonzero_or_default(&secrets, "MATCH_TIMEOUT_SECS", 60u64), 60);
+        assert_eq!(parse_nonzero_or_default(&secrets, "MAX_FILE_TRANSFERS", 3usize), 2);
+        assert_eq!(parse_or_default(&secrets, "MAX_CONNECTIONS_PER_IP", 5usize), 0, "only the nonzero variant rejects it");
+    }
+
+    #[test]
+    fn a_valid_secret_is_parsed_with_surrounding_whitespace_ignored() {
+        let secrets = secrets(&[("MATCH_TIMEOUT_SECS", " 90\n"), ("BROADEN_ON_MATCH_TIMEOUT", "true"), ("MESSAGE_BURST", "2.5")]);
+        assert_eq!(parse_or_default(&secrets, "MATCH_TIMEOUT_SECS", 60u64), 90);
+        assert!(parse_or_default(&secrets, "BROADEN_ON_MATCH_TIMEOUT", false));
+        assert_eq!(parse_or_default(&secrets, "MESSAGE_BURST", 10.0f64), 2.5);
+    }
+}
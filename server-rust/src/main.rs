@@ -3,47 +3,258 @@ mod handler;
 
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use actix_cors::Cors;
-use server::ChatServer;
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use server::{ChatServer, MAX_PAYLOAD_SIZE};
 use shuttle_actix_web::ShuttleActixWeb;
 use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use shuttle_runtime::SecretStore;
+use tracing::Instrument;
+use tracing_subscriber::{fmt::MakeWriter, util::SubscriberInitExt, EnvFilter};
 
 pub mod keys;
 
 // ### Server Setup
 
+/// How long /readyz waits for the chat server actor before declaring it unready
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Resolves on Ctrl-C, or on SIGTERM which is what a redeploy sends
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 async fn index() -> impl Responder {
     "Socket.io server for Random Tune Harmony chat is running"
 }
 
+// Liveness: answering at all means the process is up
+async fn healthz_route() -> HttpResponse {
+    HttpResponse::Ok().finish()
+}
+
+// Readiness: the chat server actor must answer a stats round trip in time; a panicked or wedged
+// command loop shows up here as 503
+async fn readyz_route(srv: web::Data<server::ChatServerHandle>) -> HttpResponse {
+    match tokio::time::timeout(READINESS_TIMEOUT, srv.stats()).await {
+        Ok(Ok(_)) => HttpResponse::Ok().finish(),
+        _ => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct MaintenanceParams {
+    enabled: bool,
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+// Admin routes require `Authorization: Bearer <ADMIN_TOKEN>`
+fn is_admin(req: &HttpRequest) -> bool {
+    let Some(admin_token) = keys::get_admin_token() else {
+        return false;
+    };
+    bearer_token(req).is_some_and(|token| tokens_match(token, admin_token))
+}
+
+// Constant-time token comparison: both sides go through the same HMAC and `verify_slice` compares the
+// digests without short-circuiting, so response timing says nothing about how much of a guess was right
+fn tokens_match(presented: &str, expected: &str) -> bool {
+    let digest = |token: &str| {
+        let mut mac = Hmac::<Sha1>::new_from_slice(b"yaps-admin-token").expect("HMAC accepts keys of any length");
+        mac.update(token.as_bytes());
+        mac
+    };
+    digest(presented).verify_slice(&digest(expected).finalize().into_bytes()).is_ok()
+}
+
+// Toggle read-only mode: existing chats keep working but new joins are refused
+async fn maintenance_route(
+    req: HttpRequest,
+    params: web::Json<MaintenanceParams>,
+    srv: web::Data<server::ChatServerHandle>,
+) -> HttpResponse {
+    if !is_admin(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if srv.set_maintenance(params.enabled).await.is_err() {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    log::info!("Maintenance mode set to {}", params.enabled);
+    HttpResponse::Ok().json(serde_json::json!({ "maintenance": params.enabled }))
+}
+
+// Per-session outgoing queue depths; same auth as the other admin routes since it lists conn ids
+async fn queues_route(req: HttpRequest, srv: web::Data<server::ChatServerHandle>) -> HttpResponse {
+    if !is_admin(&req) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match srv.queue_depths().await {
+        Ok(queues) => HttpResponse::Ok().json(queues),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+// Time-limited TURN credentials using the standard REST scheme shared with coturn's `use-auth-secret`:
+// username is "<expiry unix time>:<random id>", credential is base64(HMAC-SHA1(secret, username)).
+// Only handed to a live chat session, which proves itself with `Authorization: Bearer <resume_token>`
+// from its welcome event; otherwise anyone could use the relay.
+async fn turn_credentials_route(req: HttpRequest, srv: web::Data<server::ChatServerHandle>) -> HttpResponse {
+    let Some(token) = bearer_token(&req) else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    match srv.has_live_session(token.to_string()).await {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().finish(),
+        Err(_) => return HttpResponse::ServiceUnavailable().finish(),
+    }
+    let Some((secret, urls)) = keys::get_turn_config() else {
+        return HttpResponse::ServiceUnavailable().finish();
+    };
+    let ttl = keys::get_turn_ttl();
+    let expires_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default() + ttl;
+    let (username, credential) = turn_credentials(secret, expires_at);
+    HttpResponse::Ok().json(serde_json::json!({
+        "urls": urls,
+        "username": username,
+        "credential": credential,
+        "ttl": ttl.as_secs(),
+    }))
+}
+
+fn turn_credentials(secret: &str, expires_at: Duration) -> (String, String) {
+    let username = format!("{}:{}", expires_at.as_secs(), uuid::Uuid::new_v4().simple());
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    let credential = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+    (username, credential)
+}
+
+// Public counts for the landing page; a single cheap actor round trip
+async fn stats_route(srv: web::Data<server::ChatServerHandle>) -> HttpResponse {
+    match srv.stats().await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(_) => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct WsParams {
+    resume_token: Option<String>,
+}
+
+// Best-effort client address, used for the per-IP session cap; behind Shuttle's proxy this comes from
+// X-Forwarded-For
+fn client_ip(req: &HttpRequest) -> String {
+    let header = req.headers()
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    forwarded_client_ip(header, keys::get_trusted_proxy_hops()).unwrap_or_else(|| {
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+// Each trusted proxy appends the address it saw, so the client is the entry `trusted_hops` from the
+// right; anything further left was written by the client itself and can't be believed
+fn forwarded_client_ip(header: Option<&str>, trusted_hops: usize) -> Option<String> {
+    let from_right = trusted_hops.checked_sub(1)?;
+    header?
+        .rsplit(',')
+        .nth(from_right)
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+}
+
 async fn ws_route(
     req: HttpRequest,
     body: web::Payload,
+    params: web::Query<WsParams>,
     srv: web::Data<server::ChatServerHandle>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    let ip = client_ip(&req);
+
     // Upgrade the HTTP connection to a WebSocket connection
     let (response, session, stream) = actix_ws::handle(&req, body)?;
     
     // Spawn a task to handle the WebSocket connection
     let chat_server = srv.get_ref().clone();
-    actix_web::rt::spawn(handler::chat_ws(chat_server, session, stream));
+    let resume_token = params.into_inner().resume_token;
+    let span = handler::connection_span(&ip);
+    actix_web::rt::spawn(handler::chat_ws(
+        chat_server,
+        session,
+        stream,
+        resume_token,
+        ip,
+        handler::ConnectionTimeouts {
+            heartbeat_interval: keys::get_heartbeat_interval(),
+            client_timeout: keys::get_client_timeout(),
+            join_grace: keys::get_join_grace(),
+        },
+    ).instrument(span));
     
     Ok(response)
 }
 
+// One JSON object per line, with the enclosing connection / event spans as fields
+fn log_subscriber<W>(writer: W) -> impl tracing::Subscriber + Send + Sync
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt()
+        .json()
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_writer(writer)
+        .finish()
+}
+
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_runtime::Secrets] secrets: SecretStore,
 ) -> ShuttleActixWeb<impl FnOnce(&mut web::ServiceConfig) + Send + Clone + 'static> {
+    // try_init also installs the `log` bridge (the tracing-log feature), so `log::` call sites pick up
+    // the span context too
+    let _ = log_subscriber(std::io::stdout).try_init();
+
     // 0) Initialize secrets from Shuttle SecretStore
     keys::init_secrets(&secrets);
 
     let which_node_env = keys::get_which_node_env_url();
     let allowed_origin = keys::get_allowed_origin();
-    let max_payload_size = 5 * 1024 * 1024; // 5 MB
 
     // Create a chat server
     let chat_server = ChatServer::start();
+
+    // Let connected clients know before the process goes away
+    let shutdown_handle = chat_server.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutdown signal received");
+        let _ = shutdown_handle.shutdown().await;
+    });
     
     // Define the config function to set up routes
     let config = move |cfg: &mut web::ServiceConfig| {
@@ -74,11 +285,142 @@ async fn main(
             web::scope("")
                 .wrap(cors)
                 .app_data(web::Data::new(chat_server.clone()))
-                .app_data(web::PayloadConfig::new(max_payload_size))
+                .app_data(web::PayloadConfig::new(MAX_PAYLOAD_SIZE))
                 .route("/", web::get().to(index))
+                .route("/healthz", web::get().to(healthz_route))
+                .route("/readyz", web::get().to(readyz_route))
                 .route("/ws/", web::get().to(ws_route))
+                .route("/stats", web::get().to(stats_route))
+                .route("/turn-credentials", web::get().to(turn_credentials_route))
+                .route("/admin/maintenance", web::post().to(maintenance_route))
+                .route("/admin/queues", web::get().to(queues_route))
         );
     };
     
     Ok(config.into())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test::{call_service, init_service, TestRequest}, App};
+
+    #[actix_web::test]
+    async fn healthz_answers_without_the_chat_server() {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(server::ChatServerHandle::disconnected()))
+            .route("/healthz", web::get().to(healthz_route))).await;
+        let res = call_service(&app, TestRequest::get().uri("/healthz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn readyz_is_ok_while_the_chat_server_answers() {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(ChatServer::start()))
+            .route("/readyz", web::get().to(readyz_route))).await;
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn stats_carries_only_the_public_counts() {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(ChatServer::start()))
+            .route("/stats", web::get().to(stats_route))).await;
+        let res = call_service(&app, TestRequest::get().uri("/stats").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({ "online": 0, "waiting": 0, "groups": 0 }));
+    }
+
+    #[actix_web::test]
+    async fn readyz_is_unavailable_once_the_chat_server_is_gone() {
+        let app = init_service(App::new()
+            .app_data(web::Data::new(server::ChatServerHandle::disconnected()))
+            .route("/readyz", web::get().to(readyz_route))).await;
+        let res = call_service(&app, TestRequest::get().uri("/readyz").to_request()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn turn_credentials_are_refused_without_a_live_session() {
+        let chat_server = ChatServer::start();
+        let app = init_service(App::new()
+            .app_data(web::Data::new(chat_server.clone()))
+            .route("/turn-credentials", web::get().to(turn_credentials_route))).await;
+        let res = call_service(&app, TestRequest::get().uri("/turn-credentials").to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::get().uri("/turn-credentials")
+            .insert_header((actix_web::http::header::AUTHORIZATION, "Bearer not-a-session"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+        // A live session gets past the check; this test build just has no TURN relay configured
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let (end_tx, _end_rx) = tokio::sync::oneshot::channel();
+        chat_server.connect(tx, end_tx, None, "198.51.100.4".to_string()).await.unwrap().unwrap();
+        let server::Msg::Text(welcome) = rx.recv().await.unwrap() else {
+            panic!("welcome is a text frame");
+        };
+        let welcome: serde_json::Value = serde_json::from_str(&welcome).unwrap();
+        let token = welcome["data"]["resume_token"].as_str().unwrap();
+        let req = TestRequest::get().uri("/turn-credentials")
+            .insert_header((actix_web::http::header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn tokens_match_only_the_exact_token() {
+        assert!(tokens_match("s3cret-admin-token", "s3cret-admin-token"));
+        assert!(!tokens_match("s3cret-admin-tokeN", "s3cret-admin-token"));
+        assert!(!tokens_match("s3cret", "s3cret-admin-token"));
+        assert!(!tokens_match("", "s3cret-admin-token"));
+    }
+
+    #[test]
+    fn turn_username_carries_the_expiry_and_the_credential_signs_it() {
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + keys::get_turn_ttl();
+        let (username, credential) = turn_credentials("coturn-secret", expires_at);
+        let (expiry, id) = username.split_once(':').unwrap();
+        assert_eq!(expiry.parse::<u64>().unwrap(), expires_at.as_secs());
+        assert!(!id.is_empty());
+
+        let mut mac = Hmac::<Sha1>::new_from_slice(b"coturn-secret").unwrap();
+        mac.update(username.as_bytes());
+        let signature = base64::engine::general_purpose::STANDARD.decode(credential).unwrap();
+        assert!(mac.verify_slice(&signature).is_ok());
+    }
+
+    #[test]
+    fn turn_usernames_are_unique_per_request() {
+        let expires_at = Duration::from_secs(1_700_000_000);
+        assert_ne!(turn_credentials("secret", expires_at), turn_credentials("secret", expires_at));
+    }
+
+    #[test]
+    fn forwarded_client_ip_takes_the_entry_the_proxy_appended() {
+        let header = Some("6.6.6.6, 198.51.100.4");
+        assert_eq!(forwarded_client_ip(header, 1).as_deref(), Some("198.51.100.4"));
+    }
+
+    #[test]
+    fn forwarded_client_ip_skips_each_trusted_hop() {
+        let header = Some("6.6.6.6,198.51.100.4, 10.0.0.2");
+        assert_eq!(forwarded_client_ip(header, 2).as_deref(), Some("198.51.100.4"));
+    }
+
+    #[test]
+    fn forwarded_client_ip_is_ignored_without_trusted_proxies() {
+        assert_eq!(forwarded_client_ip(Some("198.51.100.4"), 0), None);
+    }
+
+    #[test]
+    fn forwarded_client_ip_needs_an_entry_per_hop() {
+        assert_eq!(forwarded_client_ip(Some("198.51.100.4"), 2), None);
+        assert_eq!(forwarded_client_ip(Some(" , "), 1), None);
+        assert_eq!(forwarded_client_ip(None, 1), None);
+    }
+}
@@ -1,15 +1,394 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
 use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::{interval, Instant, MissedTickBehavior};
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
+use base64::Engine as _;
+use futures_util::FutureExt as _;
+use crate::keys;
 
 // Type aliases for clarity
 pub type ConnId = String;
 pub type RoomId = String;
-pub type Msg = String;
+
+/// One outgoing frame for a session: JSON events as text, relayed file chunks as binary
+pub enum Msg {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Msg {
+    fn from(text: String) -> Self {
+        Msg::Text(text)
+    }
+}
+
+/// Consecutive frames a session may drop on a full queue before it is disconnected as too slow
+const SLOW_CLIENT_DROP_LIMIT: u32 = 32;
+
+/// Share of a session's queue in use, in percent, at which it counts as a slow consumer
+const SLOW_CONSUMER_QUEUE_PERCENT: usize = 75;
+
+/// Frames dropped on full session queues since the process started, across actor restarts
+static DROPPED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// How often critical frames waiting on a full session queue are retried
+const CRITICAL_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a critical frame may wait for room in a full queue before it is dropped after all
+const CRITICAL_RETRY_WINDOW: Duration = Duration::from_millis(500);
+
+/// Most critical frames kept waiting per session; any more are dropped like sheddable ones
+const CRITICAL_RETRY_LIMIT: usize = 64;
+
+/// Messages in a row a connection may keep sending while rate limited before it is banned
+const RATE_LIMIT_BAN_STRIKES: u32 = 20;
+
+/// Why the server itself ended a session, handed to the connection before its sender is dropped
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SessionEnd {
+    /// The connection kept sending after being rate limited
+    RateLimitBan,
+    /// The client read too slowly and its queue stayed full for `SLOW_CLIENT_DROP_LIMIT` frames
+    SlowConsumer,
+}
+
+impl SessionEnd {
+    fn leave_reason(self) -> LeaveReason {
+        match self {
+            SessionEnd::RateLimitBan => LeaveReason::Banned,
+            SessionEnd::SlowConsumer => LeaveReason::Error,
+        }
+    }
+}
+
+/// Where `SessionTx::send_critical` put a frame
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CriticalSend {
+    /// In the session's queue, on its way to the client
+    Queued,
+    /// Waiting for room behind a full queue; dropped if none frees up within CRITICAL_RETRY_WINDOW
+    Parked,
+}
+
+/// Sending half of a session's outgoing queue. The queue is bounded so a stalled client can't make the
+/// server buffer without limit. Sheddable frames (typing, presence, progress) that don't fit are
+/// dropped; critical ones (chat messages, call signalling) wait briefly for room before they are.
+struct SessionTx {
+    tx: mpsc::Sender<Msg>,
+    end_tx: oneshot::Sender<SessionEnd>, // tells the connection why, when the server cuts it off
+    dropped: Cell<u32>, // consecutive frames dropped because the queue was full
+    retrying: RefCell<VecDeque<(Msg, Instant)>>, // critical frames waiting for room, oldest first, with when they were first tried
+    backed_up: Cell<bool>, // over the slow-consumer threshold at the last check, so the warning is logged once
+    parked: Arc<AtomicUsize>, // the server's count of frames waiting in any session's `retrying`
+}
+
+impl SessionTx {
+    fn new(tx: mpsc::Sender<Msg>, end_tx: oneshot::Sender<SessionEnd>, parked: Arc<AtomicUsize>) -> Self {
+        SessionTx {
+            tx,
+            end_tx,
+            dropped: Cell::new(0),
+            retrying: RefCell::new(VecDeque::new()),
+            backed_up: Cell::new(false),
+            parked,
+        }
+    }
+
+    fn record_drop(&self) {
+        self.dropped.set(self.dropped.get() + 1);
+        DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Frames accepted for this session but not yet written out, including critical ones still waiting
+    fn queue_depth(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity() + self.retrying.borrow().len()
+    }
+
+    fn is_slow_consumer(&self) -> bool {
+        self.queue_depth() * 100 >= self.tx.max_capacity() * SLOW_CONSUMER_QUEUE_PERCENT
+    }
+
+    // Queue a frame without blocking the server loop; a full queue counts towards the slow-client limit
+    fn send(&self, msg: Msg) -> Result<(), TrySendError<Msg>> {
+        let result = self.tx.try_send(msg);
+        match &result {
+            Ok(()) => self.dropped.set(0),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped.get() == 0 {
+                    log::warn!("Session queue is full; dropping frames until the client catches up");
+                }
+                self.record_drop();
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+        result
+    }
+
+    // Queue a frame that must not be lost to one full queue. It goes out behind any critical frames
+    // already waiting, and is retried for up to CRITICAL_RETRY_WINDOW before it counts as dropped.
+    // Only `Queued` means the frame is in the session's queue; a `Parked` one may still be dropped.
+    fn send_critical(&self, msg: Msg) -> Result<CriticalSend, TrySendError<Msg>> {
+        let mut retrying = self.retrying.borrow_mut();
+        let msg = if retrying.is_empty() {
+            match self.tx.try_send(msg) {
+                Ok(()) => {
+                    self.dropped.set(0);
+                    return Ok(CriticalSend::Queued);
+                }
+                Err(TrySendError::Full(msg)) => msg,
+                Err(err) => return Err(err),
+            }
+        } else {
+            msg
+        };
+        if retrying.len() >= CRITICAL_RETRY_LIMIT {
+            self.record_drop();
+            return Err(TrySendError::Full(msg));
+        }
+        retrying.push_back((msg, Instant::now()));
+        self.parked.fetch_add(1, Ordering::Relaxed);
+        Ok(CriticalSend::Parked)
+    }
+
+    // Move waiting critical frames into the queue while it has room; any that waited out the retry
+    // window are dropped and count towards the slow-client limit
+    fn retry_critical(&self) {
+        let mut retrying = self.retrying.borrow_mut();
+        while let Some((msg, first_tried)) = retrying.pop_front() {
+            if first_tried.elapsed() > CRITICAL_RETRY_WINDOW {
+                log::warn!("Dropping a critical frame that found the session queue full for {:?}", CRITICAL_RETRY_WINDOW);
+                self.record_drop();
+                continue;
+            }
+            match self.tx.try_send(msg) {
+                Ok(()) => self.dropped.set(0),
+                Err(TrySendError::Full(msg)) => {
+                    retrying.push_front((msg, first_tried));
+                    break;
+                }
+                Err(TrySendError::Closed(_)) => {
+                    retrying.clear();
+                    break;
+                }
+            }
+        }
+    }
+
+    fn is_stalled(&self) -> bool {
+        self.dropped.get() >= SLOW_CLIENT_DROP_LIMIT
+    }
+}
+
+/// Version byte leading every binary file-chunk frame
+pub const FILE_CHUNK_VERSION: u8 = 1;
+
+/// Flag bit set when a file-chunk frame is routed to a group rather than the 1:1 partner
+const FILE_CHUNK_FLAG_GROUP: u8 = 0b0000_0001;
+
+/// One encrypted file chunk carried in a binary WebSocket frame, so large files skip base64-in-JSON.
+///
+/// Wire layout, both directions:
+///
+/// ```text
+/// [version: u8 = 1][flags: u8][route_len: u8][route][id_len: u8][file_id][ciphertext...]
+/// ```
+///
+/// `flags` bit 0 marks a group chunk. From the client, `route` is the group code (empty means the
+/// sender's current group, and is always empty for 1:1); when relayed, the server rewrites it to
+/// the sender's username. `file_id` is the id announced with `file_sending_started`.
+pub struct FileChunk {
+    pub is_group_chat: bool,
+    pub route: String,
+    pub file_id: String,
+    pub ciphertext: Vec<u8>,
+}
+
+impl FileChunk {
+    // Split a client frame into header fields and ciphertext; the error is the `binary_error` reason
+    pub fn parse(frame: &[u8]) -> Result<Self, &'static str> {
+        let [version, flags, rest @ ..] = frame else {
+            return Err("truncated_header");
+        };
+        if *version != FILE_CHUNK_VERSION {
+            return Err("unsupported_version");
+        }
+        let (route, rest) = Self::take_field(rest)?;
+        let (file_id, ciphertext) = Self::take_field(rest)?;
+        if file_id.is_empty() {
+            return Err("missing_file_id");
+        }
+        Ok(FileChunk {
+            is_group_chat: flags & FILE_CHUNK_FLAG_GROUP != 0,
+            route,
+            file_id,
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+
+    // Read one length-prefixed UTF-8 field off the front of the buffer
+    fn take_field(buf: &[u8]) -> Result<(String, &[u8]), &'static str> {
+        let [len, rest @ ..] = buf else {
+            return Err("truncated_header");
+        };
+        let len = *len as usize;
+        if rest.len() < len {
+            return Err("truncated_header");
+        }
+        let field = std::str::from_utf8(&rest[..len]).map_err(|_| "invalid_header")?;
+        Ok((field.to_string(), &rest[len..]))
+    }
+
+    // Serialize the chunk back into the wire layout; field lengths were bounded when parsed or set
+    pub fn encode(&self) -> Vec<u8> {
+        let flags = if self.is_group_chat { FILE_CHUNK_FLAG_GROUP } else { 0 };
+        let route = &self.route.as_bytes()[..self.route.len().min(u8::MAX as usize)];
+        let mut frame = Vec::with_capacity(4 + route.len() + self.file_id.len() + self.ciphertext.len());
+        frame.extend_from_slice(&[FILE_CHUNK_VERSION, flags, route.len() as u8]);
+        frame.extend_from_slice(route);
+        frame.push(self.file_id.len() as u8);
+        frame.extend_from_slice(self.file_id.as_bytes());
+        frame.extend_from_slice(&self.ciphertext);
+        frame
+    }
+}
+
+/// How long a dropped 1:1 user keeps their pairing while waiting to resume
+const RESUME_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Most messages kept for an away session; older ones are dropped first
+const MAX_HELD_MESSAGES: usize = 50;
+
+/// How long the server remembers who sent a message id, for delete/edit ownership checks
+const MESSAGE_AUTHOR_TTL: Duration = Duration::from_secs(3600);
+
+/// Most message ids remembered at once; the oldest is forgotten first
+const MAX_TRACKED_MESSAGES: usize = 10_000;
+
+/// How often the server sweeps for expired state
+const HOUSEKEEPING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest `reply_to` id accepted from clients; ids outside `0..=MAX_REPLY_TO_ID` are stripped
+const MAX_REPLY_TO_ID: i32 = 1_000_000;
+
+/// How long a computed online count is reused, and the minimum gap between a client's online_count requests
+const ONLINE_COUNT_CACHE_TTL: Duration = Duration::from_secs(2);
+
+/// How often each group is told which of its members are still responsive
+const GROUP_PRESENCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A member whose socket has not answered a heartbeat for this long is reported as stale
+const SOFT_LIVENESS_THRESHOLD: Duration = Duration::from_secs(15);
+
+/// How long a freshly created single-member group is preferred by random joiners over other open groups
+const FRESH_GROUP_WINDOW: Duration = Duration::from_secs(10);
+
+/// Longest user id accepted in `block_user`; real ids are UUIDs
+const MAX_BLOCKED_ID_LEN: usize = 64;
+
+/// Most user ids one user can block; blocking another forgets the oldest
+const MAX_BLOCKS_PER_USER: usize = 100;
+
+/// Most users with a block list at once; a new list pushes out the one left untouched longest
+const MAX_BLOCK_LISTS: usize = 10_000;
+
+/// How long a block list is kept after its owner last added to it
+const BLOCK_LIST_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Most reported users tallied at once; a new one pushes out the tally left untouched longest
+const MAX_REPORTED_USERS: usize = 10_000;
+
+/// How long reports against a user are kept after the last one arrived
+const ABUSE_REPORT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Longest report reason kept for the log; the rest is cut off
+const MAX_REPORT_REASON_LEN: usize = 200;
+
+/// Decoded nonce length clients use for AES-256-GCM
+const NONCE_LEN: usize = 12;
+
+/// Nonces remembered per sender for replay detection; the oldest is forgotten first
+const MAX_TRACKED_NONCES: usize = 1000;
+
+/// How many recent 1:1 match waits feed the estimate sent with `waiting_for_match`
+const MATCH_WAIT_SAMPLES: usize = 20;
+
+/// How long an unused private pairing code stays valid
+const PAIR_CODE_TTL: Duration = Duration::from_secs(300);
+
+/// A competing 1:1 offer arriving within this window of the first one is treated as glare
+const GLARE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Longest username (in characters) that is relayed to other clients
+const MAX_USERNAME_LEN: usize = 32;
+
+/// Longest group name (in characters) accepted at creation or rename
+const MAX_GROUP_NAME_LEN: usize = 40;
+
+/// Longest reaction (in characters); enough for multi-codepoint emoji like flags and ZWJ sequences
+const MAX_REACTION_LEN: usize = 16;
+
+/// Largest websocket frame or HTTP payload accepted
+pub const MAX_PAYLOAD_SIZE: usize = 5 * 1024 * 1024; // 5 MB
+
+/// Client protocol versions this server can talk to
+const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// Protocol features this server understands, advertised to clients in `welcome`
+const SERVER_CAPABILITIES: &[&str] = &[
+    "session_resume",
+    "partner_status",
+    "group_lock",
+    "room_state",
+    "webrtc_ice_candidates",
+    "online_count",
+    "my_rooms",
+    "private_pair",
+    "request_id",
+    "call_glare",
+    "group_resume",
+    "activity_update",
+    "server_info",
+    "skip_partner",
+    "cancel_search",
+    "update_preference",
+    "leave_group",
+    "kick_member",
+    "list_groups",
+    "group_names",
+    "message_delivered",
+    "message_read",
+    "call_ringing",
+    "call_timeout",
+    "group_peers",
+    "group_joined",
+    "call_media",
+    "message_ownership",
+    "edit_message",
+    "message_reaction",
+    "language_matching",
+    "match_timeout",
+    "server_shutting_down",
+    "group_history",
+    "report_user",
+    "block_user",
+    "ping_peer",
+    "replay_protection",
+    "binary_file_chunks",
+    "file_progress",
+    "group_typing_update",
+    "presence",
+    "member_left",
+];
 
 // Message types
 #[derive(Serialize, Deserialize, Clone)]
@@ -19,6 +398,46 @@ pub struct EncryptedMessage {
     pub reply_to: Option<i32>,
 }
 
+/// What a user is doing right now, shown to their partner or group as a live indicator
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Activity {
+    #[default]
+    Typing,
+    RecordingAudio,
+    RecordingVideo,
+    Uploading,
+}
+
+/// Whether a user is at the keyboard, as shown to their partner or group
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    #[default]
+    Active,
+    Away,
+}
+
+/// Whether a `react_message` adds or withdraws a reaction
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReactionAction {
+    Add,
+    Remove,
+}
+
+/// Why a user left a conversation, reported to their partner in `partner_disconnected` and to the rest
+/// of a group in `member_left`
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    Left,
+    Timeout,
+    Kicked,
+    Banned,
+    Error,
+}
+
 #[derive(Deserialize)]
 pub struct UserProfile {
     pub user_id: String,
@@ -28,6 +447,113 @@ pub struct UserProfile {
     pub room_type: String,
     pub group_code: Option<String>,
     pub group_join_method: Option<String>,
+    pub max_members: Option<usize>, // requested cap when creating a group, bounded by the configured MAX_GROUP_SIZE
+    pub pair_join_method: Option<String>, // "create" or "join" to pair 1:1 by code instead of random matching
+    pub pair_code: Option<String>,
+    pub group_name: Option<String>, // optional display name shown in the lobby when creating a group
+    pub language: Option<String>, // e.g. "uz"; when set, 1:1 matches share it until the match timeout passes
+    pub keep_history: Option<bool>, // when creating a group, keep recent ciphertext for members who join later
+}
+
+// "both" is accepted from older clients as a synonym for "any" so both share one waiting bucket
+fn normalize_preference(preference: &str) -> &str {
+    match preference {
+        "any" | "both" => "any",
+        other => other,
+    }
+}
+
+// Language tags compare case-insensitively; a blank tag means "no preference"
+fn normalize_language(language: Option<&str>) -> Option<String> {
+    language
+        .map(|language| language.trim().to_lowercase())
+        .filter(|language| !language.is_empty())
+}
+
+/// Waiting-pool queue key: waiters are queued by their own gender and by whom they are looking for, so a
+/// match only has to look at the few queues whose key is compatible instead of every waiter
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct WaitKey {
+    gender: String,
+    preference: String,
+}
+
+// The waiter at `position` when the queues are laid end to end; the caller keeps it below their total
+fn nth_waiter<'a>(queues: &[&'a Vec<ConnId>], mut position: usize) -> &'a ConnId {
+    for list in queues {
+        if position < list.len() {
+            return &list[position];
+        }
+        position -= list.len();
+    }
+    unreachable!("position is below the number of queued waiters")
+}
+
+// Whether someone with this (normalized) preference is happy to be paired with this gender
+fn preference_accepts(preference: &str, gender: &str) -> bool {
+    preference == "any" || preference == gender
+}
+
+// Drop control characters and markup brackets from a requested username, trim it and bound its length.
+// May come back empty, in which case the caller picks a default.
+fn sanitize_username(raw: &str) -> String {
+    let cleaned: String = raw.chars()
+        .filter(|c| !c.is_control() && !matches!(c, '<' | '>'))
+        .collect();
+    let truncated: String = cleaned.trim().chars().take(MAX_USERNAME_LEN).collect();
+    truncated.trim_end().to_string()
+}
+
+// A name no other group member is using: the requested one, or it with " (2)", " (3)", ... appended,
+// shortening the requested part so the result still fits in MAX_USERNAME_LEN
+fn unique_display_name(requested: &str, taken: &[String]) -> String {
+    if !taken.iter().any(|name| name == requested) {
+        return requested.to_string();
+    }
+    (2..)
+        .map(|n| {
+            let suffix = format!(" ({})", n);
+            let base: String = requested.chars().take(MAX_USERNAME_LEN - suffix.len()).collect();
+            format!("{}{}", base.trim_end(), suffix)
+        })
+        .find(|candidate| !taken.contains(candidate))
+        .expect("a group holds finitely many names")
+}
+
+// Group names must be 1-40 characters once trimmed; unlike usernames they are rejected rather than truncated
+fn validate_group_name(raw: &str) -> Result<String, &'static str> {
+    if raw.chars().any(char::is_control) {
+        return Err("control_characters");
+    }
+    let name = raw.trim();
+    if name.is_empty() {
+        return Err("empty");
+    }
+    if name.chars().count() > MAX_GROUP_NAME_LEN {
+        return Err("too_long");
+    }
+    Ok(name.to_string())
+}
+
+// A reaction is one short token, typically a single emoji; whitespace would let clients smuggle in text
+fn is_valid_reaction(reaction: &str) -> bool {
+    !reaction.is_empty()
+        && reaction.chars().count() <= MAX_REACTION_LEN
+        && !reaction.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+// The `receive_message` event as relayed to the partner or group, or held for an away recipient
+fn receive_message_payload(message: &EncryptedMessage, sender: &str, message_id: &Option<String>) -> String {
+    let event = ServerEvent {
+        event: "receive_message".to_string(),
+        data: serde_json::json!({
+            "message": message,
+            "sender": sender,
+            "reply_to": message.reply_to,
+            "message_id": message_id,
+        }),
+    };
+    serde_json::to_string(&event).unwrap()
 }
 
 // Data structures
@@ -35,18 +561,162 @@ pub struct UserProfile {
 struct User {
     id: ConnId, // socket id
     user_id: String,
-    username: String,
+    username: String, // what others see; inside a group it may carry a " (2)" suffix
+    chosen_username: String, // as sanitised at join_chat; group suffixes are always worked out from this
     gender: String,
     preference: String,
     room_type: String,
     partner_id: Option<ConnId>,
+    partner_left_reason: Option<LeaveReason>, // why the partner went while this user was away, told on resume
     group_id: Option<RoomId>,
+    language: Option<String>,
+    presence: PresenceStatus,
+    auto_away: bool, // set when the server marked the user away for inactivity, cleared by their next activity
+    last_active: Instant,
 }
 
 struct Group {
     code: RoomId,
-    members: Vec<ConnId>, // socket ids
-    usernames: Vec<String>,
+    members: Vec<(ConnId, String)>, // (socket id, display name) in join order
+    admin: ConnId, // creator, or the longest-standing member once they leave
+    locked: bool, // locked groups accept no new members
+    created_at: Instant,
+    max_members: usize,
+    name: Option<String>,
+    history: Option<VecDeque<HistoryEntry>>, // None unless the creator opted in; oldest first
+    next_seq: u64,
+}
+
+impl Group {
+    fn member_ids(&self) -> impl Iterator<Item = &ConnId> {
+        self.members.iter().map(|(id, _)| id)
+    }
+
+    fn has_member(&self, conn: &ConnId) -> bool {
+        self.members.iter().any(|(id, _)| id == conn)
+    }
+
+    // Display names in join order, as sent in `group_members_update`
+    fn usernames(&self) -> Vec<String> {
+        self.members.iter().map(|(_, username)| username.clone()).collect()
+    }
+
+    // A member named by conn id, or failing that by display name
+    fn find_member(&self, target: &str) -> Option<&ConnId> {
+        self.members.iter()
+            .find(|(id, _)| id == target)
+            .or_else(|| self.members.iter().find(|(_, username)| username == target))
+            .map(|(id, _)| id)
+    }
+}
+
+/// One buffered group message; only the ciphertext and routing details, never plaintext
+#[derive(Serialize, Clone)]
+struct HistoryEntry {
+    seq: u64,
+    sender: String,
+    message: EncryptedMessage,
+    message_id: Option<String>,
+}
+
+/// The chat server's command loop is gone, so a `ChatServerHandle` request could not be answered
+#[derive(Debug)]
+pub struct ServerUnavailable;
+
+impl std::fmt::Display for ServerUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("chat server is unavailable")
+    }
+}
+
+impl std::error::Error for ServerUnavailable {}
+
+impl From<mpsc::error::SendError<Command>> for ServerUnavailable {
+    fn from(_: mpsc::error::SendError<Command>) -> Self {
+        ServerUnavailable
+    }
+}
+
+impl From<oneshot::error::RecvError> for ServerUnavailable {
+    fn from(_: oneshot::error::RecvError) -> Self {
+        ServerUnavailable
+    }
+}
+
+/// Aggregate counts for the public stats endpoint; never anything identifying
+#[derive(Serialize)]
+pub struct ServerStats {
+    pub online: usize,
+    pub waiting: usize,
+    pub groups: usize,
+}
+
+/// Backpressure across all sessions, for the admin queue report
+#[derive(Serialize)]
+pub struct QueueReport {
+    pub slow_consumers: usize, // sessions whose queue is past SLOW_CONSUMER_QUEUE_PERCENT full
+    pub max_queue_depth: usize,
+    pub dropped_frames: u64,
+    pub sessions: Vec<SessionQueue>,
+}
+
+/// One session's outgoing queue, for the admin queue report
+#[derive(Serialize)]
+pub struct SessionQueue {
+    pub conn_id: ConnId,
+    pub depth: usize,
+    pub capacity: usize,
+    pub dropped: u32, // consecutive drops towards SLOW_CLIENT_DROP_LIMIT
+}
+
+// Per-connection send allowance: holds up to `burst` tokens, refilled continuously
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+    strikes: u32, // messages rejected in a row; reset by the next accepted one
+}
+
+// Reports against one user, counted by the reporters' IP addresses
+struct ReportTally {
+    reporter_ips: HashSet<String>,
+    updated_at: Instant,
+}
+
+// User ids one user never wants to be matched with, oldest first
+struct BlockList {
+    blocked: VecDeque<String>,
+    updated_at: Instant,
+}
+
+// Recently seen nonces from one sender, bounded so a long chat can't grow it without limit
+#[derive(Default)]
+struct NonceWindow {
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl NonceWindow {
+    // Remember a nonce; false if it was already seen
+    fn insert(&mut self, nonce: &str) -> bool {
+        if self.seen.contains(nonce) {
+            return false;
+        }
+        if self.order.len() >= MAX_TRACKED_NONCES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(nonce.to_string());
+        self.order.push_back(nonce.to_string());
+        true
+    }
+}
+
+// Nonces are relayed opaquely, but must at least be base64 for the 12-byte GCM IV clients generate
+fn is_valid_nonce(nonce: &str) -> bool {
+    base64::engine::general_purpose::STANDARD
+        .decode(nonce)
+        .is_ok_and(|bytes| bytes.len() == NONCE_LEN)
 }
 
 // Server messages
@@ -61,55 +731,77 @@ pub struct ServerEvent {
 pub struct ClientEvent {
     pub event: String,
     pub data: Value,
+    // Optional client-chosen id echoed back in the matching ack or error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
 // Commands that can be sent to the chat server
 enum Command {
     Connect {
-        conn_tx: mpsc::UnboundedSender<Msg>,
-        res_tx: oneshot::Sender<ConnId>,
+        conn_tx: mpsc::Sender<Msg>,
+        end_tx: oneshot::Sender<SessionEnd>,
+        resume_token: Option<String>,
+        ip: String,
+        res_tx: oneshot::Sender<Option<(ConnId, bool)>>,
     },
     Disconnect {
         conn: ConnId,
+        reason: LeaveReason,
+    },
+    Heartbeat {
+        conn: ConnId,
     },
     JoinChat {
         conn: ConnId,
         profile: UserProfile,
-        res_tx: oneshot::Sender<()>,
+        res_tx: oneshot::Sender<bool>,
     },
     SendMessage {
         conn: ConnId,
         message: EncryptedMessage,
         is_group_chat: bool,
         group_code: Option<String>,
+        message_id: Option<String>,
+        request_id: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
-    TypingStart {
+    ActivityUpdate {
         conn: ConnId,
+        activity: Activity,
+        active: bool,
         is_group_chat: bool,
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
-    TypingStop {
+    FileSendingStart {
         conn: ConnId,
+        file_id: String,
         is_group_chat: bool,
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
-    FileSendingStart {
+    FileSendingEnd {
         conn: ConnId,
         file_id: String,
         is_group_chat: bool,
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
-    FileSendingEnd {
+    FileProgress {
         conn: ConnId,
         file_id: String,
+        bytes_sent: u64,
+        total_bytes: u64,
         is_group_chat: bool,
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
+    RelayFileChunk {
+        conn: ConnId,
+        chunk: FileChunk,
+        res_tx: oneshot::Sender<()>,
+    },
     DeleteMessage {
         conn: ConnId,
         message_id: String,
@@ -117,50 +809,261 @@ enum Command {
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
+    EditMessage {
+        conn: ConnId,
+        message_id: String,
+        message: EncryptedMessage,
+        is_group_chat: bool,
+        group_code: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
     DisconnectChat {
         conn: ConnId,
         res_tx: oneshot::Sender<()>,
     },
-    GetSessionTx {
-        conn_id: ConnId,
-        res_tx: oneshot::Sender<Option<mpsc::UnboundedSender<Msg>>>,
+    SkipPartner {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
     },
-    RelayWebRTCEvent {
-        sender_id: ConnId,
-        event_type: String,
-        target_id: String,
-        data: Value,
+    CancelSearch {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    UpdatePreference {
+        conn: ConnId,
+        preference: String,
+        language: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
+    LeaveGroup {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    KickMember {
+        conn: ConnId,
+        target: String,
+        res_tx: oneshot::Sender<()>,
+    },
+    ReportUser {
+        conn: ConnId,
+        target: Option<String>,
+        reason: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
+    UpdatePresence {
+        conn: ConnId,
+        status: PresenceStatus,
+        res_tx: oneshot::Sender<()>,
+    },
+    BlockUser {
+        conn: ConnId,
+        user_id: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
+    ListGroups {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    GetStats {
+        res_tx: oneshot::Sender<ServerStats>,
+    },
+    QueueDepths {
+        res_tx: oneshot::Sender<QueueReport>,
+    },
+    HasLiveSession {
+        token: String,
+        res_tx: oneshot::Sender<bool>,
+    },
+    GetHistory {
+        conn: ConnId,
+        group_code: Option<String>,
+        before_seq: Option<u64>,
+        limit: Option<usize>,
+        res_tx: oneshot::Sender<()>,
+    },
+    Shutdown {
+        res_tx: oneshot::Sender<()>,
+    },
+    RenameGroup {
+        conn: ConnId,
+        name: String,
+        res_tx: oneshot::Sender<()>,
+    },
+    MessageRead {
+        conn: ConnId,
+        message_id: String,
         is_group_chat: bool,
         group_code: Option<String>,
         res_tx: oneshot::Sender<()>,
     },
-}
-
-// Chat server implementation
-pub struct ChatServer {
-    sessions: HashMap<ConnId, mpsc::UnboundedSender<Msg>>,
-    users: HashMap<ConnId, User>,
-    waiting_users: HashMap<String, Vec<ConnId>>, // preference -> Vec<socket_id>
-    groups: HashMap<RoomId, Group>,
-}
-
-impl ChatServer {
-    pub fn new() -> Self {
+    ReactMessage {
+        conn: ConnId,
+        message_id: String,
+        reaction: String,
+        action: ReactionAction,
+        is_group_chat: bool,
+        group_code: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
+    PartnerStatus {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    MyRooms {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    GetServerInfo {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    OnlineCount {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    SetMaintenance {
+        enabled: bool,
+        res_tx: oneshot::Sender<()>,
+    },
+    LockGroup {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    UnlockGroup {
+        conn: ConnId,
+        res_tx: oneshot::Sender<()>,
+    },
+    PingPeer {
+        conn: ConnId,
+        target_id: Option<ConnId>,
+        res_tx: oneshot::Sender<bool>,
+    },
+    RelayWebRTCEvent {
+        sender_id: ConnId,
+        event_type: String,
+        target_id: String,
+        data: Value,
+        is_group_chat: bool,
+        group_code: Option<String>,
+        res_tx: oneshot::Sender<()>,
+    },
+}
+
+// Chat server implementation
+pub struct ChatServer {
+    sessions: HashMap<ConnId, SessionTx>,
+    users: HashMap<ConnId, User>,
+    waiting_users: HashMap<WaitKey, Vec<ConnId>>, // (gender, preference) -> waiters in arrival order
+    waiting_since: HashMap<ConnId, Instant>, // socket id -> when it was queued for a 1:1 match
+    match_timed_out: HashSet<ConnId>, // waiters already sent match_timeout
+    broaden_on_match_timeout: bool,
+    recent_partings: HashMap<(String, String), Instant>, // sorted pair of user ids -> when their 1:1 chat ended
+    rematch_cooldown: Duration,
+    group_history_size: usize,
+    abuse_reports: HashMap<String, ReportTally>, // reported user id -> where the reports came from
+    abuse_report_threshold: usize,
+    blocked_user_ids: HashSet<String>, // reported past the threshold; never matched or let into groups again
+    user_blocks: HashMap<String, BlockList>, // user id -> user ids they never want to be matched with
+    recent_match_waits: VecDeque<Duration>, // how long the queued side of recent matches waited, newest last
+    groups: HashMap<RoomId, Group>,
+    session_tokens: HashMap<ConnId, String>, // socket id -> resume token
+    resume_tokens: HashMap<String, ConnId>, // resume token -> socket id
+    away_users: HashMap<ConnId, Instant>, // socket id -> resume deadline
+    maintenance: bool, // when set, new joins are refused but existing chats keep relaying
+    online_count_cache: Option<(Instant, usize)>,
+    online_count_requests: HashMap<ConnId, Instant>, // socket id -> last answered request
+    last_heartbeat: HashMap<ConnId, Instant>, // socket id -> last heartbeat reply
+    last_presence_broadcast: Instant,
+    pair_codes: HashMap<String, (ConnId, Instant)>, // private pairing code -> (creator socket id, expiry)
+    match_timeout: Duration, // waiting-related timeouts all derive from this
+    pending_offers: HashMap<(ConnId, ConnId), Instant>, // (caller, callee) -> when the unanswered offer arrived
+    file_transfers: HashMap<ConnId, HashSet<String>>, // in-flight file ids per sender
+    max_file_transfers: usize,
+    max_group_size: usize, // largest number of members any group may hold
+    message_buckets: HashMap<ConnId, TokenBucket>,
+    seen_nonces: HashMap<ConnId, NonceWindow>,
+    message_rate: (f64, f64), // (burst, refill per second)
+    max_message_size: usize, // bytes of the `encrypted` field relayed per message
+    connections_per_ip: HashMap<String, usize>,
+    conn_ips: HashMap<ConnId, String>,
+    max_connections_per_ip: usize,
+    ringing_calls: HashMap<(ConnId, String), (ConnId, Instant)>, // (caller, call id) -> (callee, expires_at)
+    call_ring_timeout: Duration,
+    held_messages: HashMap<ConnId, VecDeque<Msg>>, // receive_message events for away sessions, flushed on resume
+    message_authors: HashMap<(ConnId, String), Instant>, // (author, message id) -> when it was sent
+    typing_deadlines: HashMap<ConnId, (bool, Option<String>, Instant)>, // typist -> (is_group_chat, group_code, expires_at)
+    typing_timeout: Duration,
+    presence_away_after: Duration,
+    group_typing_dirty: HashSet<RoomId>, // groups whose typist set may have changed since the last flush
+    group_typing_sent: HashMap<RoomId, Vec<String>>, // last typist set sent to each group
+    parked_frames: Arc<AtomicUsize>, // critical frames waiting for room; the retry timer only runs while nonzero
+}
+
+impl ChatServer {
+    pub fn new() -> Self {
         Self {
             sessions: HashMap::new(),
             users: HashMap::new(),
             waiting_users: HashMap::new(),
+            waiting_since: HashMap::new(),
+            match_timed_out: HashSet::new(),
+            broaden_on_match_timeout: keys::get_broaden_on_match_timeout(),
+            recent_partings: HashMap::new(),
+            rematch_cooldown: keys::get_rematch_cooldown(),
+            group_history_size: keys::get_group_history_size(),
+            abuse_reports: HashMap::new(),
+            abuse_report_threshold: keys::get_abuse_report_threshold(),
+            blocked_user_ids: HashSet::new(),
+            user_blocks: HashMap::new(),
+            recent_match_waits: VecDeque::with_capacity(MATCH_WAIT_SAMPLES),
             groups: HashMap::new(),
+            session_tokens: HashMap::new(),
+            resume_tokens: HashMap::new(),
+            away_users: HashMap::new(),
+            maintenance: false,
+            online_count_cache: None,
+            online_count_requests: HashMap::new(),
+            last_heartbeat: HashMap::new(),
+            last_presence_broadcast: Instant::now(),
+            pair_codes: HashMap::new(),
+            match_timeout: keys::get_match_timeout(),
+            pending_offers: HashMap::new(),
+            file_transfers: HashMap::new(),
+            max_file_transfers: keys::get_max_file_transfers(),
+            max_group_size: keys::get_max_group_size(),
+            message_buckets: HashMap::new(),
+            seen_nonces: HashMap::new(),
+            message_rate: keys::get_message_rate(),
+            max_message_size: keys::get_max_message_size(),
+            connections_per_ip: HashMap::new(),
+            conn_ips: HashMap::new(),
+            max_connections_per_ip: keys::get_max_connections_per_ip(),
+            ringing_calls: HashMap::new(),
+            call_ring_timeout: keys::get_call_ring_timeout(),
+            held_messages: HashMap::new(),
+            message_authors: HashMap::new(),
+            typing_deadlines: HashMap::new(),
+            typing_timeout: keys::get_typing_timeout(),
+            presence_away_after: keys::get_presence_away_after(),
+            group_typing_dirty: HashSet::new(),
+            group_typing_sent: HashMap::new(),
+            parked_frames: Arc::default(),
         }
     }
 
     pub fn start() -> ChatServerHandle {
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let server = Self::new();
+        let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel();
 
-        // Spawn a task to run the server
+        // Spawn a task to run the server. A panic in the command loop only loses the in-memory state:
+        // the receiver outlives it, so the same handles keep working against a fresh server.
         tokio::spawn(async move {
-            server.run(cmd_rx).await.unwrap();
+            loop {
+                match AssertUnwindSafe(Self::new().run(&mut cmd_rx)).catch_unwind().await {
+                    Ok(Ok(())) => break,
+                    Ok(Err(e)) => log::error!("Chat server stopped with an error: {}; restarting", e),
+                    Err(_) => log::error!("Chat server panicked; restarting with empty state"),
+                }
+            }
         });
 
         ChatServerHandle { cmd_tx }
@@ -174,700 +1077,5201 @@ impl ChatServer {
             .collect()
     }
     
-    async fn handle_disconnect(&mut self, conn: &ConnId) {
-        if let Some(user) = self.users.remove(conn) {
-            if user.room_type == "group" {
-                if let Some(group_id) = user.group_id {
-                    if let Some(group) = self.groups.get_mut(&group_id) {
-                        group.members.retain(|id| id != conn);
-                        group.usernames.retain(|name| name != &user.username);
-                        if group.members.is_empty() {
-                            self.groups.remove(&group_id);
-                        } else {
-                            for member_id in &group.members {
-                                if let Some(tx) = self.sessions.get(member_id) {
-                                    let event = ServerEvent {
-                                        event: "user_left_group".to_string(),
-                                        data: serde_json::json!(user.username),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-
-                                    let event = ServerEvent {
-                                        event: "group_members_update".to_string(),
-                                        data: serde_json::json!(group.usernames.clone()),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                }
-            } else {
-                if let Some(partner_id) = user.partner_id {
-                    if let Some(tx) = self.sessions.get(&partner_id) {
-                        let event = ServerEvent {
-                            event: "partner_disconnected".to_string(),
-                            data: serde_json::json!({}),
-                        };
-                        let _ = tx.send(serde_json::to_string(&event).unwrap());
-                    }
-                    if let Some(partner) = self.users.get_mut(&partner_id) {
-                        partner.partner_id = None;
-                    }
-                }
+    // Serialize an event and send it to a single session, returning whether it was delivered
+    fn send_event(&self, conn: &ConnId, event: &str, data: Value) -> bool {
+        match self.sessions.get(conn) {
+            Some(tx) => {
+                let event = ServerEvent {
+                    event: event.to_string(),
+                    data,
+                };
+                tx.send(serde_json::to_string(&event).unwrap().into()).is_ok()
+            }
+            None => false,
+        }
+    }
+
+    // Register a new session, or re-bind an away session when a valid resume token is presented.
+    // Returns the conn id and whether it was a resume; None when the client's IP already holds the
+    // maximum number of sessions.
+    async fn handle_connect(&mut self, conn_tx: mpsc::Sender<Msg>, end_tx: oneshot::Sender<SessionEnd>, resume_token: Option<String>, ip: String) -> Option<(ConnId, bool)> {
+        if let Some(conn_id) = resume_token.and_then(|token| self.resume_tokens.get(&token).cloned()) {
+            let within_grace = matches!(self.away_users.get(&conn_id), Some(deadline) if *deadline > Instant::now());
+            if within_grace {
+                self.away_users.remove(&conn_id);
+                self.sessions.insert(conn_id.clone(), SessionTx::new(conn_tx, end_tx, self.parked_frames.clone()));
+                self.last_heartbeat.insert(conn_id.clone(), Instant::now());
+                log::info!("Session {} resumed", conn_id);
+                self.send_welcome(&conn_id, true);
+                self.resume_pairing(&conn_id).await;
+                self.flush_held_messages(&conn_id);
+                // The held session never gave up its slot, so it is not counted again
+                return Some((conn_id, true));
             }
         }
-        for list in self.waiting_users.values_mut() {
-            list.retain(|id| id != conn);
+
+        let connections = self.connections_per_ip.entry(ip.clone()).or_default();
+        if *connections >= self.max_connections_per_ip {
+            log::warn!("Refusing connection from {}: {} sessions already open", ip, connections);
+            return None;
         }
+        *connections += 1;
+
+        let conn_id = Uuid::new_v4().to_string();
+        self.conn_ips.insert(conn_id.clone(), ip);
+        let token = Uuid::new_v4().simple().to_string();
+        self.sessions.insert(conn_id.clone(), SessionTx::new(conn_tx, end_tx, self.parked_frames.clone()));
+        self.last_heartbeat.insert(conn_id.clone(), Instant::now());
+        self.resume_tokens.insert(token.clone(), conn_id.clone());
+        self.session_tokens.insert(conn_id.clone(), token);
+        self.send_welcome(&conn_id, false);
+        Some((conn_id, false))
     }
 
-    async fn find_match(&mut self, conn: &ConnId) {
-        if let Some(user) = self.users.get(conn) {
-            let preference = &user.preference;
-            let match_pool: Vec<ConnId> = self.waiting_users.get(preference).cloned().unwrap_or_default()
-                .into_iter()
-                .filter(|id| {
-                    if let Some(potential_match) = self.users.get(id) {
-                        match preference.as_str() {
-                            "male" => potential_match.gender == "male",
-                            "female" => potential_match.gender == "female",
-                            _ => false,
-                        }
-                    } else {
-                        false
-                    }
-                })
-                .collect();
-            
-            if !match_pool.is_empty() {
-                let random_index = rand::random::<usize>() % match_pool.len();
-                let partner_id = match_pool[random_index].clone();
-                self.connect_users(conn, &partner_id).await;
-            } else {
-                self.waiting_users.entry(preference.clone()).or_insert_with(Vec::new).push(conn.to_string());
-                if let Some(tx) = self.sessions.get(conn) {
-                    let event = ServerEvent {
-                        event: "waiting_for_match".to_string(),
-                        data: serde_json::json!({}),
-                    };
-                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                }
+    // First event on every connection: everything the client needs before `join_chat`
+    fn send_welcome(&self, conn: &ConnId, resumed: bool) {
+        let ice_servers: Vec<Value> = keys::get_ice_servers().iter()
+            .map(|url| serde_json::json!({ "urls": url }))
+            .collect();
+        self.send_event(conn, "welcome", serde_json::json!({
+            "conn_id": conn,
+            "version": env!("CARGO_PKG_VERSION"),
+            "capabilities": SERVER_CAPABILITIES,
+            "ice_servers": ice_servers,
+            "resume_token": self.session_tokens.get(conn),
+            "resumed": resumed,
+        }));
+    }
+
+    // Let both sides of a restored 1:1 pairing, or the rest of a group, know the user is back
+    async fn resume_pairing(&mut self, conn: &ConnId) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if user.room_type == "group" {
+            // The member never left the roster, so there is no join to announce; just resync the client
+            if let Some(group_id) = user.group_id.clone() {
+                let username = user.username.clone();
+                self.send_room_state(conn, &group_id);
+                self.broadcast_to_group(&group_id, "member_reconnected", serde_json::json!({
+                    "groupCode": group_id,
+                    "username": username,
+                }), Some(conn));
+            }
+            return;
+        }
+        match user.partner_id.clone() {
+            Some(partner_id) => {
+                self.send_event(conn, "partner_reconnected", serde_json::json!({}));
+                self.send_event(&partner_id, "partner_reconnected", serde_json::json!({}));
+            }
+            None => {
+                // The partner gave up on us while we were away; say why, then start looking for someone new
+                let reason = self.users.get_mut(conn)
+                    .and_then(|user| user.partner_left_reason.take())
+                    .unwrap_or(LeaveReason::Left);
+                self.send_event(conn, "partner_disconnected", serde_json::json!({ "reason": reason }));
+                self.find_match(conn).await;
             }
         }
     }
 
-    async fn connect_users(&mut self, user1_id: &ConnId, user2_id: &ConnId) {
-        if let Some(user1) = self.users.get_mut(user1_id) {
-            user1.partner_id = Some(user2_id.to_string());
+    // Report whether the caller's 1:1 partner is connected, away (within the resume window) or gone
+    fn send_partner_status(&self, conn: &ConnId) {
+        let Some(partner_id) = self.users.get(conn).and_then(|user| user.partner_id.as_ref()) else {
+            self.send_event(conn, "no_partner", serde_json::json!({}));
+            return;
+        };
+        let status = if self.away_users.contains_key(partner_id) {
+            "away"
+        } else if self.sessions.contains_key(partner_id) {
+            "connected"
+        } else {
+            "disconnected"
+        };
+        self.send_event(conn, "partner_status", serde_json::json!({ "status": status }));
+    }
+
+    // Whether a peer has a live socket right now. Only the caller's partner (the default) or fellow
+    // group members can be checked; anyone else reads as offline so conn ids can't be probed.
+    fn is_peer_online(&self, conn: &ConnId, target_id: Option<&str>) -> bool {
+        let Some(user) = self.users.get(conn) else {
+            return false;
+        };
+        let is_peer = |id: &str| {
+            user.partner_id.as_deref() == Some(id)
+                || user.group_id.as_ref()
+                    .and_then(|group_id| self.groups.get(group_id))
+                    .is_some_and(|group| group.member_ids().any(|member_id| member_id == id))
+        };
+        match target_id.or(user.partner_id.as_deref()) {
+            Some(target_id) if target_id != conn && is_peer(target_id) => self.sessions.contains_key(target_id),
+            _ => false,
         }
-        if let Some(user2) = self.users.get_mut(user2_id) {
-            user2.partner_id = Some(user1_id.to_string());
+    }
+
+    // Tell a (possibly reconnecting) client which partner and groups it currently belongs to
+    fn send_my_rooms(&self, conn: &ConnId) {
+        let user = self.users.get(conn);
+        let partner = user
+            .and_then(|user| user.partner_id.as_ref())
+            .and_then(|partner_id| self.users.get(partner_id))
+            .map(|partner| serde_json::json!({
+                "username": partner.username,
+                "target_id": partner.id,
+            }));
+        let groups: Vec<Value> = user
+            .and_then(|user| user.group_id.as_ref())
+            .and_then(|group_id| self.groups.get(group_id))
+            .map(|group| serde_json::json!({
+                "groupCode": group.code,
+                "members": group.usernames(),
+            }))
+            .into_iter()
+            .collect();
+        self.send_event(conn, "my_rooms", serde_json::json!({
+            "partner": partner,
+            "groups": groups,
+        }));
+    }
+
+    // Describe the limits this deployment enforces so clients can adapt their UI
+    fn send_server_info(&self, conn: &ConnId) {
+        self.send_event(conn, "server_info", serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol_versions": PROTOCOL_VERSIONS,
+            "max_payload_size": MAX_PAYLOAD_SIZE,
+            "max_message_size": self.max_message_size,
+            "max_group_size": self.max_group_size,
+            "max_username_length": MAX_USERNAME_LEN,
+            "max_file_transfers": self.max_file_transfers,
+            "match_timeout_secs": self.match_timeout.as_secs(),
+            "resume_grace_secs": RESUME_GRACE_PERIOD.as_secs(),
+            "group_chat_enabled": true,
+            "maintenance": self.maintenance,
+        }));
+    }
+
+    // Answer an anonymous online count request; callers asking again too soon are ignored
+    fn send_online_count(&mut self, conn: &ConnId) {
+        let now = Instant::now();
+        if self.online_count_requests.get(conn).is_some_and(|last| now.duration_since(*last) < ONLINE_COUNT_CACHE_TTL) {
+            return;
         }
-        for list in self.waiting_users.values_mut() {
-            list.retain(|id| id != user1_id && id != user2_id);
+        self.online_count_requests.insert(conn.clone(), now);
+
+        let count = match self.online_count_cache {
+            Some((computed_at, count)) if now.duration_since(computed_at) < ONLINE_COUNT_CACHE_TTL => count,
+            _ => {
+                let count = self.sessions.len();
+                self.online_count_cache = Some((now, count));
+                count
+            }
+        };
+        self.send_event(conn, "online_count", serde_json::json!({ "count": count }));
+    }
+
+    // Keep a chat message for a session inside its resume window instead of dropping it
+    fn hold_message(&mut self, conn: &ConnId, msg: Msg) {
+        let held = self.held_messages.entry(conn.clone()).or_default();
+        if held.len() >= MAX_HELD_MESSAGES {
+            held.pop_front();
         }
-        if let Some(tx1) = self.sessions.get(user1_id) {
-            let event = ServerEvent {
-                event: "chat_started".to_string(),
-                data: serde_json::json!({}),
-            };
-            let _ = tx1.send(serde_json::to_string(&event).unwrap());
+        held.push_back(msg);
+    }
+
+    fn flush_held_messages(&mut self, conn: &ConnId) {
+        let Some(held) = self.held_messages.remove(conn) else {
+            return;
+        };
+        if let Some(tx) = self.sessions.get(conn) {
+            log::info!("Delivering {} held messages to {}", held.len(), conn);
+            for msg in held {
+                let _ = tx.send_critical(msg);
+            }
+        }
+    }
+
+    // Remember that this connection sent a message id; nothing about the content is kept
+    fn record_message_author(&mut self, conn: &ConnId, message_id: String) {
+        if self.message_authors.len() >= MAX_TRACKED_MESSAGES {
+            if let Some(oldest) = self.message_authors.iter().min_by_key(|(_, sent_at)| **sent_at).map(|(key, _)| key.clone()) {
+                self.message_authors.remove(&oldest);
+            }
+        }
+        self.message_authors.insert((conn.clone(), message_id), Instant::now());
+    }
+
+    fn is_message_author(&self, conn: &ConnId, message_id: &str) -> bool {
+        self.message_authors.get(&(conn.clone(), message_id.to_string()))
+            .is_some_and(|sent_at| sent_at.elapsed() < MESSAGE_AUTHOR_TTL)
+    }
+
+    fn expire_message_authors(&mut self) {
+        self.message_authors.retain(|_, sent_at| sent_at.elapsed() < MESSAGE_AUTHOR_TTL);
+    }
+
+    // Spend one send token, or report how long until the next one is available
+    fn take_message_token(&mut self, conn: &ConnId) -> Result<(), Duration> {
+        let (burst, refill_per_sec) = self.message_rate;
+        let now = Instant::now();
+        let bucket = self.message_buckets.entry(conn.clone()).or_insert(TokenBucket {
+            tokens: burst,
+            updated_at: now,
+            strikes: 0,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.strikes = 0;
+            Ok(())
+        } else {
+            bucket.strikes += 1;
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / refill_per_sec))
         }
-        if let Some(tx2) = self.sessions.get(user2_id) {
-            let event = ServerEvent {
-                event: "chat_started".to_string(),
-                data: serde_json::json!({}),
+    }
+
+    // Relay an activity indicator to the partner or the rest of the group. A 1:1 partner also gets typing
+    // under its legacy event names; groups get typing only through the consolidated `group_typing_update`.
+    fn relay_activity(&mut self, conn: &ConnId, activity: Activity, active: bool, is_group_chat: bool, group_code: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if is_group_chat {
+            let Some(group_id) = group_code.or(user.group_id.clone()) else {
+                return;
             };
-            let _ = tx2.send(serde_json::to_string(&event).unwrap());
+            if activity == Activity::Typing {
+                self.group_typing_dirty.insert(group_id);
+                return;
+            }
+            self.broadcast_to_group(&group_id, "activity_update", serde_json::json!({
+                "activity": activity,
+                "active": active,
+                "username": user.username,
+            }), Some(conn));
+        } else if let Some(partner_id) = &user.partner_id {
+            self.send_event(partner_id, "activity_update", serde_json::json!({
+                "activity": activity,
+                "active": active,
+            }));
+            let legacy_event = match (activity, active) {
+                (Activity::Typing, true) => Some("typing_started"),
+                (Activity::Typing, false) => Some("typing_stopped"),
+                _ => None,
+            };
+            if let Some(event) = legacy_event {
+                self.send_event(partner_id, event, serde_json::json!({}));
+            }
         }
     }
 
-    async fn create_new_group(&mut self, conn: &ConnId) {
-        let group_code = self.generate_group_code();
-        if let Some(user) = self.users.get_mut(conn) {
-            let group = Group {
-                code: group_code.clone(),
-                members: vec![conn.to_string()],
-                usernames: vec![user.username.clone()],
+    // Forget a typist's deadline, e.g. because they sent the message; a group typist drops out of the
+    // consolidated set on the next flush
+    fn clear_typing(&mut self, conn: &ConnId) {
+        if let Some((true, group_code, _)) = self.typing_deadlines.remove(conn) {
+            if let Some(group_id) = group_code.or_else(|| self.users.get(conn).and_then(|user| user.group_id.clone())) {
+                self.group_typing_dirty.insert(group_id);
+            }
+        }
+    }
+
+    // Send each group whose typists changed the full set of usernames now typing. Runs on the housekeeping
+    // tick, so rapid toggles within one tick collapse into a single update, or none if the set ends up unchanged.
+    fn flush_group_typing(&mut self) {
+        for group_id in std::mem::take(&mut self.group_typing_dirty) {
+            let Some(group) = self.groups.get(&group_id) else {
+                self.group_typing_sent.remove(&group_id);
+                continue;
             };
-            self.groups.insert(group_code.clone(), group);
-            user.group_id = Some(group_code.clone());
-            if let Some(tx) = self.sessions.get(conn) {
-                let event = ServerEvent {
-                    event: "chat_started".to_string(),
-                    data: serde_json::json!({ "groupCode": group_code.clone() }),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+            let mut usernames: Vec<String> = self.typing_deadlines.iter()
+                .filter(|(conn, (is_group_chat, group_code, _))| {
+                    *is_group_chat
+                        && group.has_member(conn)
+                        && group_code.as_ref().is_none_or(|code| *code == group_id)
+                })
+                .filter_map(|(conn, _)| self.users.get(conn).map(|user| user.username.clone()))
+                .collect();
+            usernames.sort();
+            if self.group_typing_sent.get(&group_id).map_or(usernames.is_empty(), |sent| *sent == usernames) {
+                continue;
+            }
+            self.broadcast_to_group(&group_id, "group_typing_update", serde_json::json!({
+                "groupCode": group_id,
+                "usernames": usernames,
+            }), None);
+            if usernames.is_empty() {
+                self.group_typing_sent.remove(&group_id);
+            } else {
+                self.group_typing_sent.insert(group_id, usernames);
+            }
+        }
+    }
 
-                let event = ServerEvent {
-                    event: "group_members_update".to_string(),
-                    data: serde_json::json!(vec![user.username.clone()]),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+    // Store a presence change and tell the partner or the rest of the group; repeats are not relayed
+    fn set_presence(&mut self, conn: &ConnId, status: PresenceStatus, auto_away: bool) {
+        let Some(user) = self.users.get_mut(conn) else {
+            return;
+        };
+        user.auto_away = auto_away;
+        if user.presence == status {
+            return;
+        }
+        user.presence = status;
+        let data = serde_json::json!({
+            "username": user.username,
+            "status": status,
+        });
+        if let Some(group_id) = user.group_id.clone() {
+            self.broadcast_to_group(&group_id, "presence_update", data, Some(conn));
+        } else if let Some(partner_id) = user.partner_id.clone() {
+            self.send_event(&partner_id, "presence_update", data);
+        }
+    }
+
+    // Note that the user did something; brings them back from an automatic away
+    fn touch_presence(&mut self, conn: &ConnId) {
+        let Some(user) = self.users.get_mut(conn) else {
+            return;
+        };
+        user.last_active = Instant::now();
+        if user.auto_away {
+            self.set_presence(conn, PresenceStatus::Active, false);
+        }
+    }
+
+    // Mark users who have been idle past PRESENCE_AWAY_SECS as away
+    fn expire_presence(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<ConnId> = self.users.values()
+            .filter(|user| user.presence == PresenceStatus::Active && now.duration_since(user.last_active) >= self.presence_away_after)
+            .map(|user| user.id.clone())
+            .collect();
+        for conn in idle {
+            log::debug!("Marking {} away after {:?} without activity", conn, self.presence_away_after);
+            self.set_presence(&conn, PresenceStatus::Away, true);
+        }
+    }
+
+    // Clear typing indicators whose client went quiet without sending typing_stop
+    fn expire_typing(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<_> = self.typing_deadlines.iter()
+            .filter(|(_, (_, _, expires_at))| *expires_at <= now)
+            .map(|(conn, (is_group_chat, group_code, _))| (conn.clone(), *is_group_chat, group_code.clone()))
+            .collect();
+        for (conn, is_group_chat, group_code) in expired {
+            self.typing_deadlines.remove(&conn);
+            log::debug!("Typing indicator for {} timed out", conn);
+            self.relay_activity(&conn, Activity::Typing, false, is_group_chat, group_code);
+        }
+    }
+
+    // Route a read receipt back to the sender side; nothing is stored, the id is only passed along
+    fn relay_read_receipt(&self, conn: &ConnId, message_id: &str, is_group_chat: bool, group_code: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if is_group_chat {
+            if let Some(group_id) = group_code.or(user.group_id.clone()) {
+                self.broadcast_to_group(&group_id, "message_read", serde_json::json!({
+                    "message_id": message_id,
+                    "username": user.username,
+                }), Some(conn));
             }
+        } else if let Some(partner_id) = &user.partner_id {
+            self.send_event(partner_id, "message_read", serde_json::json!({ "message_id": message_id }));
         }
     }
 
-    async fn join_group_by_code(&mut self, conn: &ConnId, group_code: &str) {
-        if let Some(group) = self.groups.get_mut(group_code) {
-            if let Some(user) = self.users.get_mut(conn) {
-                group.members.push(conn.to_string());
-                group.usernames.push(user.username.clone());
-                user.group_id = Some(group_code.to_string());
-                for member_id in &group.members {
-                    if let Some(tx) = self.sessions.get(member_id) {
-                        let event = ServerEvent {
-                            event: "group_members_update".to_string(),
-                            data: serde_json::json!(group.usernames.clone()),
-                        };
-                        let _ = tx.send(serde_json::to_string(&event).unwrap());
-                        if member_id != conn {
-                            let event = ServerEvent {
-                                event: "user_joined_group".to_string(),
-                                data: serde_json::json!(user.username.clone()),
-                            };
-                            let _ = tx.send(serde_json::to_string(&event).unwrap());
-                        }
-                    }
-                }
-                if let Some(tx) = self.sessions.get(conn) {
-                    let event = ServerEvent {
-                        event: "chat_started".to_string(),
-                        data: serde_json::json!({ "groupCode": group_code.to_string() }),
-                    };
-                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                }
+    // Fan a reaction out to the conversation, reactor included; reactions are never stored
+    fn relay_reaction(&self, conn: &ConnId, message_id: &str, reaction: &str, action: ReactionAction, is_group_chat: bool, group_code: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if !is_valid_reaction(reaction) {
+            self.send_event(conn, "invalid_reaction", serde_json::json!({ "message_id": message_id }));
+            return;
+        }
+        let data = serde_json::json!({
+            "message_id": message_id,
+            "reaction": reaction,
+            "action": action,
+            "username": user.username,
+        });
+        if is_group_chat {
+            if let Some(group_id) = group_code.or(user.group_id.clone()) {
+                self.broadcast_to_group(&group_id, "message_reaction", data, None);
             }
         } else {
-            if let Some(tx) = self.sessions.get(conn) {
-                let event = ServerEvent {
-                    event: "group_not_found".to_string(),
-                    data: serde_json::json!({}),
-                };
-                let _ = tx.send(serde_json::to_string(&event).unwrap());
+            if let Some(partner_id) = &user.partner_id {
+                self.send_event(partner_id, "message_reaction", data.clone());
             }
+            self.send_event(conn, "message_reaction", data);
         }
     }
 
-    async fn join_random_group(&mut self, conn: &ConnId) {
-        let group_code_option = {
-            let available_groups: Vec<&Group> = self.groups.values().filter(|g| !g.members.is_empty()).collect();
-            if available_groups.is_empty() {
-                None
-            } else {
-                let random_index = rand::random::<usize>() % available_groups.len();
-                Some(available_groups[random_index].code.clone())
+    // Route an edited ciphertext to everyone who saw the original, the author included so other tabs stay in sync
+    fn relay_message_edit(&self, conn: &ConnId, message_id: &str, message: EncryptedMessage, is_group_chat: bool, group_code: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        let data = serde_json::json!({
+            "message_id": message_id,
+            "message": message,
+        });
+        if is_group_chat {
+            if let Some(group_id) = group_code.or(user.group_id.clone()) {
+                self.broadcast_to_group(&group_id, "message_edited", data, None);
+            }
+        } else {
+            if let Some(partner_id) = &user.partner_id {
+                self.send_event(partner_id, "message_edited", data.clone());
             }
+            self.send_event(conn, "message_edited", data);
+        }
+    }
+
+    // Group-routed commands may only target a group the sender belongs to. The group is the one named by
+    // the client, falling back to the sender's current group; 1:1 traffic always passes.
+    fn is_group_member_for(&self, conn: &ConnId, is_group_chat: bool, group_code: &Option<String>, action: &str) -> bool {
+        if !is_group_chat {
+            return true;
+        }
+        let group_id = group_code.clone().or_else(|| self.users.get(conn).and_then(|user| user.group_id.clone()));
+        let is_member = group_id.as_ref()
+            .and_then(|group_id| self.groups.get(group_id))
+            .is_some_and(|group| group.has_member(conn));
+        if !is_member {
+            log::warn!("Rejected {} from {} into group {:?} it is not a member of", action, conn, group_id);
+            self.send_event(conn, "unauthorized", serde_json::json!({
+                "action": action,
+                "groupCode": group_id,
+            }));
+        }
+        is_member
+    }
+
+    // Pass a transfer progress update on to the receivers only; nothing is stored, so a dropped update
+    // just means the next one moves the bar further
+    fn relay_file_progress(&self, conn: &ConnId, file_id: String, bytes_sent: u64, total_bytes: u64, is_group_chat: bool, group_code: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
         };
-        
-        match group_code_option {
-            Some(code) => self.join_group_by_code(conn, &code).await,
-            None => self.create_new_group(conn).await,
+        let data = serde_json::json!({
+            "fileId": file_id,
+            "bytes_sent": bytes_sent,
+            "total_bytes": total_bytes,
+            "username": user.username.clone(),
+        });
+        if is_group_chat {
+            if let Some(group_id) = group_code.or(user.group_id.clone()) {
+                self.broadcast_to_group(&group_id, "file_progress", data, Some(conn));
+            }
+        } else if let Some(partner_id) = &user.partner_id {
+            self.send_event(partner_id, "file_progress", data);
         }
     }
 
-    async fn run(mut self, mut cmd_rx: mpsc::UnboundedReceiver<Command>) -> Result<(), Box<dyn std::error::Error>> {
-        while let Some(cmd) = cmd_rx.recv().await {
-            match cmd {
-                Command::Connect { conn_tx, res_tx } => {
-                    let conn_id = Uuid::new_v4().to_string();
-                    self.sessions.insert(conn_id.clone(), conn_tx);
-                    let _ = res_tx.send(conn_id);
-                }
-                Command::Disconnect { conn } => {
-                    self.handle_disconnect(&conn).await;
+    // Forward a binary file chunk as-is to the partner or the other group members. Chunks are only
+    // accepted for a file the sender announced with file_sending_start, and are not held for away sessions.
+    fn relay_file_chunk(&self, conn: &ConnId, mut chunk: FileChunk) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if !self.file_transfers.get(conn).is_some_and(|in_flight| in_flight.contains(&chunk.file_id)) {
+            self.send_event(conn, "binary_error", serde_json::json!({
+                "reason": "unknown_file",
+                "fileId": chunk.file_id,
+            }));
+            return;
+        }
+        let group_code = Some(chunk.route.clone()).filter(|code| !code.is_empty());
+        if !self.is_group_member_for(conn, chunk.is_group_chat, &group_code, "file_chunk") {
+            return;
+        }
+        let recipients: Vec<&ConnId> = if chunk.is_group_chat {
+            group_code.or(user.group_id.clone())
+                .and_then(|group_id| self.groups.get(&group_id))
+                .map(|group| group.member_ids().filter(|member_id| *member_id != conn).collect())
+                .unwrap_or_default()
+        } else {
+            user.partner_id.iter().collect()
+        };
+        chunk.route = user.username.clone();
+        let frame = chunk.encode();
+        for member_id in recipients {
+            if let Some(tx) = self.sessions.get(member_id) {
+                let _ = tx.send(Msg::Binary(frame.clone()));
+            }
+        }
+    }
+
+    // Only users in an active 1:1 pairing or a group are held for resume; everyone else is cleaned up immediately
+    fn is_resumable(&self, conn: &ConnId) -> bool {
+        self.session_tokens.contains_key(conn)
+            && self.users.get(conn).is_some_and(|user| match &user.group_id {
+                Some(group_id) => self.groups.contains_key(group_id),
+                None => user.partner_id.is_some(),
+            })
+    }
+
+    // Drop the socket but keep the pairing or group seat alive for the grace period
+    fn hold_session(&mut self, conn: &ConnId) {
+        self.sessions.remove(conn);
+        self.away_users.insert(conn.clone(), Instant::now() + RESUME_GRACE_PERIOD);
+        log::info!("Session {} is away; holding pairing for {:?}", conn, RESUME_GRACE_PERIOD);
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        if let Some(group_id) = user.group_id.clone() {
+            let username = user.username.clone();
+            self.broadcast_to_group(&group_id, "member_away", serde_json::json!({
+                "groupCode": group_id,
+                "username": username,
+            }), Some(conn));
+        } else if let Some(partner_id) = user.partner_id.clone() {
+            self.send_event(&partner_id, "partner_away", serde_json::json!({}));
+        }
+    }
+
+    // Fully remove a session and everything tied to it
+    async fn end_session(&mut self, conn: &ConnId, reason: LeaveReason) {
+        // Before the user is gone, so a group typist can still be traced to their group
+        self.clear_typing(conn);
+        self.handle_disconnect(conn, reason).await;
+        self.sessions.remove(conn);
+        self.away_users.remove(conn);
+        self.online_count_requests.remove(conn);
+        self.last_heartbeat.remove(conn);
+        self.message_buckets.remove(conn);
+        self.seen_nonces.remove(conn);
+        self.held_messages.remove(conn);
+        self.message_authors.retain(|(author, _), _| author != conn);
+        if let Some(ip) = self.conn_ips.remove(conn) {
+            if let Some(connections) = self.connections_per_ip.get_mut(&ip) {
+                *connections -= 1;
+                if *connections == 0 {
+                    self.connections_per_ip.remove(&ip);
                 }
-                Command::JoinChat { conn, profile, res_tx } => {
-                    let user = User {
-                        id: conn.clone(),
-                        user_id: profile.user_id.clone(),
-                        username: if profile.username.is_empty() { format!("User-{}", profile.user_id[..5].to_string()) } else { profile.username.clone() },
-                        gender: profile.gender.clone(),
-                        preference: profile.preference.clone(),
-                        room_type: profile.room_type.clone(),
-                        partner_id: None,
-                        group_id: None,
-                    };
-                    self.users.insert(conn.clone(), user);
-                    if profile.room_type == "group" {
-                        let join_method = profile.group_join_method.unwrap_or("random".to_string());
-                        if join_method == "create" {
-                            self.create_new_group(&conn).await;
-                        } else if join_method == "join" && profile.group_code.is_some() {
-                            self.join_group_by_code(&conn, &profile.group_code.unwrap()).await;
-                        } else {
-                            self.join_random_group(&conn).await;
-                        }
-                    } else {
-                        self.find_match(&conn).await;
-                    }
-                    let _ = res_tx.send(());
+            }
+        }
+        if let Some(token) = self.session_tokens.remove(conn) {
+            self.resume_tokens.remove(&token);
+        }
+    }
+
+    // End a session from the server side, handing its connection the reason before the sender is dropped
+    async fn close_session(&mut self, conn: &ConnId, end: SessionEnd) {
+        if let Some(tx) = self.sessions.remove(conn) {
+            let _ = tx.end_tx.send(end);
+        }
+        self.end_session(conn, end.leave_reason()).await;
+    }
+
+    // Periodically tell every group which members still answer heartbeats
+    fn broadcast_group_presence(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_presence_broadcast) < GROUP_PRESENCE_INTERVAL {
+            return;
+        }
+        self.last_presence_broadcast = now;
+
+        for group in self.groups.values() {
+            let members: Vec<Value> = group.members.iter()
+                .map(|(member_id, username)| {
+                    let stale = self.last_heartbeat.get(member_id)
+                        .is_none_or(|seen| now.duration_since(*seen) > SOFT_LIVENESS_THRESHOLD);
+                    let status = self.users.get(member_id).map(|user| user.presence).unwrap_or_default();
+                    serde_json::json!({ "username": username, "stale": stale, "status": status })
+                })
+                .collect();
+            let data = serde_json::json!({ "groupCode": group.code, "members": members });
+            for member_id in group.member_ids() {
+                self.send_event(member_id, "group_presence", data.clone());
+            }
+        }
+    }
+
+    async fn expire_away_sessions(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<ConnId> = self.away_users.iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(conn, _)| conn.clone())
+            .collect();
+        for conn in expired {
+            log::info!("Resume window for session {} expired", conn);
+            self.end_session(&conn, LeaveReason::Timeout).await;
+        }
+    }
+
+    // Give every session's waiting critical frames another try at its queue, then recount what is still
+    // waiting; the recount also forgets frames that left with a closed session
+    fn retry_critical_sends(&self) {
+        let mut parked = 0;
+        for tx in self.sessions.values() {
+            tx.retry_critical();
+            parked += tx.retrying.borrow().len();
+        }
+        self.parked_frames.store(parked, Ordering::Relaxed);
+    }
+
+    // Tell every session the server is going away, then drop their senders, which is what tells each
+    // connection task to send its close frame. A backed-up session still gets the usual retry window
+    // to make room for the notice, since nothing retries it once the loop stops.
+    async fn announce_shutdown(&mut self) {
+        log::info!("Shutting down; notifying {} sessions", self.sessions.len());
+        let event = ServerEvent {
+            event: "server_shutting_down".to_string(),
+            data: serde_json::json!({}),
+        };
+        let msg = serde_json::to_string(&event).unwrap();
+        for tx in self.sessions.values() {
+            let _ = tx.send_critical(msg.clone().into());
+        }
+        let deadline = Instant::now() + CRITICAL_RETRY_WINDOW;
+        while self.parked_frames.load(Ordering::Relaxed) > 0 && Instant::now() <= deadline {
+            tokio::time::sleep(CRITICAL_RETRY_INTERVAL).await;
+            self.retry_critical_sends();
+        }
+        self.sessions.clear();
+    }
+
+    // Warn once when a session's queue backs up past the slow-consumer threshold, well before the drop
+    // policy cuts it off; it warns again only after catching up and falling behind once more
+    fn warn_slow_consumers(&self) {
+        for (conn, tx) in &self.sessions {
+            let slow = tx.is_slow_consumer();
+            if slow && !tx.backed_up.get() {
+                log::warn!("Session {} is a slow consumer: {} of {} frames queued", conn, tx.queue_depth(), tx.tx.max_capacity());
+            }
+            tx.backed_up.set(slow);
+        }
+    }
+
+    // Drop sessions that kept their queue full for too long. Dropping the sender lets the connection task
+    // flush what is already queued and close; the pairing is not held, since the client is not keeping up.
+    async fn disconnect_stalled_sessions(&mut self) {
+        let stalled: Vec<ConnId> = self.sessions.iter()
+            .filter(|(_, tx)| tx.is_stalled())
+            .map(|(conn, _)| conn.clone())
+            .collect();
+        for conn in stalled {
+            log::warn!("Disconnecting session {}: dropped {} frames in a row on a full queue", conn, SLOW_CLIENT_DROP_LIMIT);
+            self.close_session(&conn, SessionEnd::SlowConsumer).await;
+        }
+    }
+
+    async fn handle_disconnect(&mut self, conn: &ConnId, reason: LeaveReason) {
+        self.remove_user_from_group(conn, reason);
+        // Group members never have a partner, so this only concerns 1:1 users
+        if let Some(user) = self.users.remove(conn) {
+            if let Some(partner_id) = user.partner_id {
+                if let Some(partner_user_id) = self.users.get(&partner_id).map(|partner| partner.user_id.clone()) {
+                    self.remember_parting(&user.user_id, &partner_user_id);
                 }
-                Command::SendMessage { conn, message, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "receive_message".to_string(),
-                                                    data: serde_json::json!({
-                                                        "message": message.clone(),
-                                                        "sender": user.username.clone(),
-                                                        "reply_to": message.reply_to
-                                                    }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "receive_message".to_string(),
-                                        data: serde_json::json!({
-                                            "message": message.clone(),
-                                            "sender": user.username.clone(),
-                                            "reply_to": message.reply_to
-                                        }),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
+                if let Some(tx) = self.sessions.get(&partner_id) {
+                    let event = ServerEvent {
+                        event: "partner_disconnected".to_string(),
+                        data: serde_json::json!({ "reason": reason }),
+                    };
+                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
                 }
-                Command::TypingStart { conn, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "typing_started".to_string(),
-                                                    data: serde_json::json!({ "username": user.username.clone() }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "typing_started".to_string(),
-                                        data: serde_json::json!({}),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
+                let partner_away = self.away_users.contains_key(&partner_id);
+                if let Some(partner) = self.users.get_mut(&partner_id) {
+                    partner.partner_id = None;
+                    partner.partner_left_reason = partner_away.then_some(reason);
                 }
-                Command::TypingStop { conn, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent {
-                                                    event: "typing_stopped".to_string(),
-                                                    data: serde_json::json!({ "username": user.username.clone() }),
-                                                };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent {
-                                        event: "typing_stopped".to_string(),
-                                        data: serde_json::json!({}),
-                                    };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
+                // Don't leave the survivor stranded; find_match queues them and sends waiting_for_match.
+                // An away survivor is requeued by resume_pairing once they are back, not matched blind now.
+                if !partner_away {
+                    self.find_match(&partner_id).await;
                 }
-                Command::FileSendingStart { conn, file_id, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        let event_name = "file_sending_started".to_string();
-                        let event_data = serde_json::json!({
-                            "fileId": file_id,
-                            "username": user.username.clone()
-                        });
+            }
+        }
+        self.remove_from_waiting(&[conn]);
+        self.pair_codes.retain(|_, (creator, _)| creator != conn);
+        self.pending_offers.retain(|(caller, callee), _| caller != conn && callee != conn);
+        self.file_transfers.remove(conn);
+        self.ringing_calls.retain(|(caller, _), (callee, _)| caller != conn && callee != conn);
+    }
 
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent { event: event_name, data: event_data };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
-                }
-                Command::FileSendingEnd { conn, file_id, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        let event_name = "file_sending_ended".to_string();
-                        let event_data = serde_json::json!({
-                            "fileId": file_id,
-                            "username": user.username.clone()
-                        });
+    // Take a user out of their group, whether they disconnect, leave or are kicked. Also sweeps any other
+    // group still listing the conn, and does nothing the second time round. Returns the group they were in.
+    fn remove_user_from_group(&mut self, conn: &ConnId, reason: LeaveReason) -> Option<RoomId> {
+        let recorded = self.users.get_mut(conn).and_then(|user| {
+            // Any clash suffix belonged to that group only
+            user.username = user.chosen_username.clone();
+            user.group_id.take()
+        });
+        let listed: Vec<RoomId> = self.groups.values()
+            .filter(|group| group.has_member(conn))
+            .map(|group| group.code.clone())
+            .collect();
+        for group_id in &listed {
+            self.remove_from_group(conn, group_id, reason);
+        }
+        self.file_transfers.remove(conn);
+        recorded.or_else(|| listed.into_iter().next())
+    }
 
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if member_id != &conn {
-                                            if let Some(tx) = self.sessions.get(member_id) {
-                                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
-                                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent { event: event_name, data: event_data };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                        }
-                    }
-                    let _ = res_tx.send(());
+    // Drop one member from a group's roster, handing admin on and telling whoever is left; an emptied
+    // group is removed
+    fn remove_from_group(&mut self, conn: &ConnId, group_id: &RoomId, reason: LeaveReason) {
+        if let Some(group) = self.groups.get_mut(group_id) {
+            let Some(index) = group.members.iter().position(|(id, _)| id == conn) else {
+                return;
+            };
+            let (_, username) = group.members.remove(index);
+            if group.members.is_empty() {
+                self.groups.remove(group_id);
+                self.group_typing_sent.remove(group_id);
+            } else {
+                if &group.admin == conn {
+                    group.admin = group.members[0].0.clone();
                 }
-                Command::DeleteMessage { conn, message_id, is_group_chat, group_code, res_tx } => {
-                    if let Some(user) = self.users.get(&conn) {
-                        let event_name = "message_deleted".to_string();
-                        let event_data = serde_json::json!({ "messageId": message_id });
+                for member_id in group.member_ids() {
+                    if let Some(tx) = self.sessions.get(member_id) {
+                        let event = ServerEvent {
+                            event: "user_left_group".to_string(),
+                            data: serde_json::json!(username),
+                        };
+                        let _ = tx.send(serde_json::to_string(&event).unwrap().into());
 
-                        if is_group_chat {
-                            let group_id = group_code.or(user.group_id.clone());
-                            if let Some(group_id) = group_id {
-                                if let Some(group) = self.groups.get(&group_id) {
-                                    for member_id in &group.members {
-                                        if let Some(tx) = self.sessions.get(member_id) {
-                                            let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
-                                            let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                        }
-                                    }
-                                }
-                            }
-                        } else {
-                            if let Some(partner_id) = &user.partner_id {
-                                if let Some(tx) = self.sessions.get(partner_id) {
-                                    let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
-                                    let _ = tx.send(serde_json::to_string(&event).unwrap());
-                                }
-                            }
-                            if let Some(tx) = self.sessions.get(&conn) {
-                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
-                                let _ = tx.send(serde_json::to_string(&event).unwrap());
-                            }
-                        }
+                        let event = ServerEvent {
+                            event: "member_left".to_string(),
+                            data: serde_json::json!({ "username": username, "reason": reason }),
+                        };
+                        let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+
+                        let event = ServerEvent {
+                            event: "group_members_update".to_string(),
+                            data: serde_json::json!(group.usernames()),
+                        };
+                        let _ = tx.send(serde_json::to_string(&event).unwrap().into());
                     }
-                    let _ = res_tx.send(());
-                }
-                Command::DisconnectChat { conn, res_tx } => {
-                    self.handle_disconnect(&conn).await;
-                    let _ = res_tx.send(());
-                }
-                Command::GetSessionTx { conn_id, res_tx } => {
-                    let tx = self.sessions.get(&conn_id).cloned();
-                    let _ = res_tx.send(tx);
-                }
-                Command::RelayWebRTCEvent { sender_id, event_type, target_id, data, is_group_chat, group_code, res_tx } => {
-                    self.relay_webrtc_event(sender_id, event_type, target_id, data, is_group_chat, group_code).await;
-                    let _ = res_tx.send(());
                 }
+                self.broadcast_group_peers(group_id);
+            }
+        }
+    }
+
+    // Leave the current group but keep the connection, so the user can join another room right away
+    fn leave_group(&mut self, conn: &ConnId) {
+        let Some(group_id) = self.remove_user_from_group(conn, LeaveReason::Left) else {
+            self.send_event(conn, "not_in_group", serde_json::json!({}));
+            return;
+        };
+        self.send_event(conn, "left_group", serde_json::json!({ "groupCode": group_id }));
+    }
+
+    // Remove connections from every waiting bucket and tell everyone queued behind them their new position
+    fn remove_from_waiting(&mut self, conns: &[&ConnId]) {
+        let mut shifted = Vec::new();
+        for conn in conns {
+            self.waiting_since.remove(*conn);
+            self.match_timed_out.remove(*conn);
+        }
+        for (bucket, list) in self.waiting_users.iter_mut() {
+            if let Some(first_removed) = list.iter().position(|id| conns.contains(&id)) {
+                list.retain(|id| !conns.contains(&id));
+                shifted.push((bucket.clone(), first_removed));
             }
         }
-        Ok(())
+        for (bucket, from) in shifted {
+            self.send_waiting_positions(&bucket, from);
+        }
+    }
+
+    // Take a waiter out of the pool, along with any private pairing code they are holding open. The
+    // session stays up, so the client can change preferences and join_chat again.
+    fn cancel_search(&mut self, conn: &ConnId) {
+        let holds_pair_code = self.pair_codes.values().any(|(creator, _)| creator == conn);
+        if self.waiting_position(conn).is_none() && !holds_pair_code {
+            self.send_event(conn, "not_waiting", serde_json::json!({}));
+            return;
+        }
+        // Also forgets the wait start, so no match_timeout fires for this search
+        self.remove_from_waiting(&[conn]);
+        self.pair_codes.retain(|_, (creator, _)| creator != conn);
+        log::info!("Session {} cancelled its search", conn);
+        self.send_event(conn, "search_cancelled", serde_json::json!({}));
+    }
+
+    // Swap a searching user's gender preference (and language, when given; blank clears it) and re-queue
+    // them under the new criteria. Once matched or in a group the criteria no longer apply.
+    async fn update_preference(&mut self, conn: &ConnId, preference: &str, language: Option<&str>) {
+        let Some(user) = self.users.get_mut(conn) else {
+            self.send_event(conn, "not_waiting", serde_json::json!({}));
+            return;
+        };
+        if user.partner_id.is_some() || user.group_id.is_some() {
+            self.send_event(conn, "preference_locked", serde_json::json!({}));
+            return;
+        }
+        user.preference = normalize_preference(preference).to_string();
+        if language.is_some() {
+            user.language = normalize_language(language);
+        }
+        // Moves to the bucket for the new criteria but keeps the original wait start, so the user neither
+        // loses their turn nor restarts the language timeout. A pair code held open gives way to the pool.
+        let waiting_since = self.waiting_since.get(conn).copied();
+        let timed_out = self.match_timed_out.contains(conn);
+        self.remove_from_waiting(&[conn]);
+        self.pair_codes.retain(|_, (creator, _)| creator != conn);
+        self.find_match(conn).await;
+        if let Some(since) = waiting_since.filter(|_| self.waiting_since.contains_key(conn)) {
+            self.waiting_since.insert(conn.clone(), since);
+            if timed_out {
+                self.match_timed_out.insert(conn.clone());
+            }
+        }
+    }
+
+    // Send `waiting_count` to each waiter in a bucket starting at index `from`
+    fn send_waiting_positions(&self, bucket: &WaitKey, from: usize) {
+        if let Some(list) = self.waiting_users.get(bucket) {
+            for (index, id) in list.iter().enumerate().skip(from) {
+                self.send_event(id, "waiting_count", serde_json::json!({
+                    "position": index + 1,
+                    "count": list.len(),
+                }));
+            }
+        }
+    }
+
+    // Whether a join_chat left the connection somewhere: paired, in a group, queued or holding a pair code.
+    // A refused join (locked group, bad pair code, maintenance) leaves none of these set.
+    fn has_joined(&self, conn: &ConnId) -> bool {
+        let placed = self.users.get(conn).is_some_and(|user| user.partner_id.is_some() || user.group_id.is_some());
+        placed
+            || self.waiting_position(conn).is_some()
+            || self.pair_codes.values().any(|(creator, _)| creator == conn)
+    }
+
+    // 1-based position of a connection in whichever waiting bucket holds it
+    fn waiting_position(&self, conn: &ConnId) -> Option<usize> {
+        self.waiting_users.values()
+            .find_map(|list| list.iter().position(|id| id == conn))
+            .map(|index| index + 1)
+    }
+
+    // Rough guess at the remaining wait: the average of recent match waits, or half the match timeout
+    // before any matches have been made
+    fn estimated_wait(&self) -> Duration {
+        if self.recent_match_waits.is_empty() {
+            return self.match_timeout / 2;
+        }
+        self.recent_match_waits.iter().sum::<Duration>() / self.recent_match_waits.len() as u32
+    }
+
+    fn waiting_for_match_data(&self, position: usize) -> Value {
+        serde_json::json!({
+            "position": position,
+            "estimated_wait_seconds": self.estimated_wait().as_secs(),
+        })
+    }
+
+    // A waiter who has gone unmatched for the whole match timeout stops insisting on their language,
+    // and on their gender preference too when BROADEN_ON_MATCH_TIMEOUT is set
+    fn waited_past_timeout(&self, conn: &ConnId) -> bool {
+        self.waiting_since.get(conn).is_some_and(|since| since.elapsed() >= self.match_timeout)
+    }
+
+    // Keyed by user id rather than socket id so a reconnect doesn't reset the cooldown
+    fn parting_key(user_id_a: &str, user_id_b: &str) -> (String, String) {
+        if user_id_a <= user_id_b {
+            (user_id_a.to_string(), user_id_b.to_string())
+        } else {
+            (user_id_b.to_string(), user_id_a.to_string())
+        }
+    }
+
+    fn remember_parting(&mut self, user_id_a: &str, user_id_b: &str) {
+        self.recent_partings.insert(Self::parting_key(user_id_a, user_id_b), Instant::now());
+    }
+
+    fn parted_recently(&self, a: &User, b: &User) -> bool {
+        self.recent_partings.get(&Self::parting_key(&a.user_id, &b.user_id))
+            .is_some_and(|parted_at| parted_at.elapsed() < self.rematch_cooldown)
+    }
+
+    fn expire_recent_partings(&mut self) {
+        let cooldown = self.rematch_cooldown;
+        self.recent_partings.retain(|_, parted_at| parted_at.elapsed() < cooldown);
+    }
+
+    fn has_blocked(&self, user: &User, other: &User) -> bool {
+        self.user_blocks.get(&user.user_id).is_some_and(|list| list.blocked.contains(&other.user_id))
+    }
+
+    // Whether any waiter in this queue could pass `accepts_gender` both ways with `user`. Waiters who
+    // broadened past the match timeout may accept anyone, so with broadening on only our side is checked.
+    fn may_pair_with_queue(&self, user: &User, key: &WaitKey) -> bool {
+        let we_accept = preference_accepts(&user.preference, &key.gender)
+            || (self.broaden_on_match_timeout && self.waited_past_timeout(&user.id));
+        let they_accept = preference_accepts(&key.preference, &user.gender) || self.broaden_on_match_timeout;
+        we_accept && they_accept
+    }
+
+    // A waiter `user` can be paired with, looking only at the queues whose key is compatible. With `random`
+    // every waiter there who passes `can_pair` is equally likely; otherwise whoever has waited longest wins.
+    fn find_waiting_partner(&self, user: &User, random: bool) -> Option<ConnId> {
+        let queues: Vec<&Vec<ConnId>> = self.waiting_users.iter()
+            .filter(|(key, _)| self.may_pair_with_queue(user, key))
+            .map(|(_, list)| list)
+            .collect();
+        let eligible = |id: &ConnId| *id != user.id && self.users.get(id).is_some_and(|other| self.can_pair(user, other));
+        if !random {
+            return queues.iter()
+                .flat_map(|list| list.iter())
+                .filter(|id| eligible(id))
+                .min_by_key(|id| self.waiting_since.get(*id))
+                .cloned();
+        }
+        // Draw positions without replacement, a Fisher-Yates shuffle done lazily with only the swapped
+        // slots remembered. The usual eligible first draw costs one lookup per compatible queue.
+        let total: usize = queues.iter().map(|list| list.len()).sum();
+        let mut rng = thread_rng();
+        let mut swapped: HashMap<usize, usize> = HashMap::new();
+        for drawn in 0..total {
+            let slot = rng.gen_range(drawn..total);
+            let position = swapped.get(&slot).copied().unwrap_or(slot);
+            swapped.insert(slot, swapped.get(&drawn).copied().unwrap_or(drawn));
+            let id = nth_waiter(&queues, position);
+            if eligible(id) {
+                return Some(id.clone());
+            }
+        }
+        None
+    }
+
+    // Whether this user's own gender preference is satisfied by `other`'s gender
+    fn accepts_gender(&self, user: &User, other: &User) -> bool {
+        preference_accepts(&user.preference, &other.gender)
+            || (self.broaden_on_match_timeout && self.waited_past_timeout(&user.id))
+    }
+
+    // Whether two users may be paired 1:1: mutual preference, no recent parting, and a shared language
+    // unless either side has none set or has waited long enough to drop it
+    fn can_pair(&self, a: &User, b: &User) -> bool {
+        let languages_match = match (&a.language, &b.language) {
+            (Some(language_a), Some(language_b)) => language_a == language_b
+                || self.waited_past_timeout(&a.id)
+                || self.waited_past_timeout(&b.id),
+            _ => true,
+        };
+        !self.blocked_user_ids.contains(&a.user_id)
+            && !self.blocked_user_ids.contains(&b.user_id)
+            && !self.parted_recently(a, b)
+            && !self.has_blocked(a, b)
+            && !self.has_blocked(b, a)
+            && self.accepts_gender(a, b)
+            && self.accepts_gender(b, a)
+            && languages_match
+    }
+
+    // Tell each waiter once that the match timeout passed; they stay queued so the client can offer
+    // to retry or change preferences while the relaxed search carries on
+    fn notify_match_timeouts(&mut self) {
+        let timed_out: Vec<ConnId> = self.waiting_since.keys()
+            .filter(|id| self.waited_past_timeout(id) && !self.match_timed_out.contains(*id))
+            .cloned()
+            .collect();
+        for conn in timed_out {
+            self.match_timed_out.insert(conn.clone());
+            self.send_event(&conn, "match_timeout", serde_json::json!({
+                "waited_secs": self.match_timeout.as_secs(),
+                "broadened": self.broaden_on_match_timeout,
+            }));
+        }
+    }
+
+    // Pair waiters whose filters just lapsed; nobody new has to arrive for them to match
+    async fn match_relaxed_waiters(&mut self) {
+        let relaxed: Vec<ConnId> = self.waiting_since.keys()
+            .filter(|id| self.waited_past_timeout(id))
+            .filter(|id| self.broaden_on_match_timeout || self.users.get(*id).is_some_and(|user| user.language.is_some()))
+            .cloned()
+            .collect();
+        for conn in relaxed {
+            // An earlier pairing in this sweep may already have taken this waiter
+            let Some(user) = self.users.get(&conn).filter(|_| self.waiting_since.contains_key(&conn)) else {
+                continue;
+            };
+            if let Some(partner_id) = self.find_waiting_partner(user, false) {
+                log::info!("Matching {} with relaxed filters after the match timeout", conn);
+                self.connect_users(&conn, &partner_id).await;
+            }
+        }
+    }
+
+    async fn find_match(&mut self, conn: &ConnId) {
+        if let Some(user) = self.users.get(conn) {
+            // Once chat_started has gone out, a waiting_for_match would be stale and confuse the client
+            if user.partner_id.is_some() {
+                return;
+            }
+            let key = WaitKey {
+                gender: user.gender.clone(),
+                preference: user.preference.clone(),
+            };
+            if !self.sessions.contains_key(conn) {
+                // Never pair or queue a connection whose socket is already gone; nobody would hear chat_started
+                log::warn!("Not matching {}: session is not connected", conn);
+            } else if let Some(partner_id) = self.find_waiting_partner(user, true) {
+                self.connect_users(conn, &partner_id).await;
+            } else if let Some(position) = self.waiting_position(conn) {
+                // Already queued; a second entry would inflate the pool and could match the user with themselves
+                self.send_event(conn, "waiting_for_match", self.waiting_for_match_data(position));
+            } else {
+                let bucket = self.waiting_users.entry(key).or_default();
+                bucket.push(conn.to_string());
+                let position = bucket.len();
+                self.waiting_since.insert(conn.clone(), Instant::now());
+                if let Some(tx) = self.sessions.get(conn) {
+                    let event = ServerEvent {
+                        event: "waiting_for_match".to_string(),
+                        data: self.waiting_for_match_data(position),
+                    };
+                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                }
+            }
+        }
+    }
+
+    // End the current 1:1 pairing and put both users back into matchmaking
+    async fn skip_partner(&mut self, conn: &ConnId) {
+        let Some(partner_id) = self.users.get(conn).and_then(|user| user.partner_id.clone()) else {
+            self.send_event(conn, "no_partner", serde_json::json!({}));
+            return;
+        };
+        for id in [conn, &partner_id] {
+            if let Some(user) = self.users.get_mut(id) {
+                user.partner_id = None;
+            }
+        }
+        // The rematch cooldown is what keeps the two from being paired again straight away
+        let user_ids: Vec<String> = [conn, &partner_id].iter()
+            .filter_map(|id| self.users.get(*id).map(|user| user.user_id.clone()))
+            .collect();
+        if let [user_id_a, user_id_b] = user_ids.as_slice() {
+            self.remember_parting(user_id_a, user_id_b);
+        }
+        self.pending_offers.retain(|(caller, callee), _| caller != conn && callee != conn);
+        self.send_event(&partner_id, "partner_skipped", serde_json::json!({}));
+        self.find_match(conn).await;
+        self.find_match(&partner_id).await;
+    }
+
+    // Issue a one-time code that lets a specific person pair with this user directly
+    fn create_private_pair(&mut self, conn: &ConnId) {
+        let code = loop {
+            let code = self.generate_group_code();
+            if !self.pair_codes.contains_key(&code) {
+                break code;
+            }
+        };
+        self.pair_codes.insert(code.clone(), (conn.clone(), Instant::now() + PAIR_CODE_TTL));
+        self.send_event(conn, "private_pair_created", serde_json::json!({
+            "pairCode": code,
+            "expiresIn": PAIR_CODE_TTL.as_secs(),
+        }));
+    }
+
+    // Redeem a private pairing code, connecting the joiner with the code's creator
+    async fn join_private_pair(&mut self, conn: &ConnId, code: &str) {
+        let creator = match self.pair_codes.get(code) {
+            Some((creator, expires_at)) if *expires_at > Instant::now() && creator != conn => creator.clone(),
+            _ => {
+                self.send_event(conn, "pair_code_invalid", serde_json::json!({ "pairCode": code }));
+                return;
+            }
+        };
+        self.pair_codes.remove(code);
+        self.connect_users(conn, &creator).await;
+    }
+
+    fn expire_pair_codes(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(String, ConnId)> = self.pair_codes.iter()
+            .filter(|(_, (_, expires_at))| *expires_at <= now)
+            .map(|(code, (creator, _))| (code.clone(), creator.clone()))
+            .collect();
+        for (code, creator) in expired {
+            self.pair_codes.remove(&code);
+            self.send_event(&creator, "pair_code_expired", serde_json::json!({ "pairCode": code }));
+        }
+    }
+
+    // A ring nobody answered ends for both sides instead of leaving either UI hanging
+    fn expire_ringing_calls(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(ConnId, String, ConnId)> = self.ringing_calls.iter()
+            .filter(|(_, (_, expires_at))| *expires_at <= now)
+            .map(|((caller, call_id), (callee, _))| (caller.clone(), call_id.clone(), callee.clone()))
+            .collect();
+        for (caller, call_id, callee) in expired {
+            self.ringing_calls.remove(&(caller.clone(), call_id.clone()));
+            self.send_event(&caller, "call_timeout", serde_json::json!({ "call_id": call_id, "target_id": callee }));
+            self.send_event(&callee, "call_timeout", serde_json::json!({ "call_id": call_id, "target_id": caller }));
+        }
+    }
+
+    async fn connect_users(&mut self, user1_id: &ConnId, user2_id: &ConnId) {
+        if let Some(user1) = self.users.get_mut(user1_id) {
+            user1.partner_id = Some(user2_id.to_string());
+        }
+        if let Some(user2) = self.users.get_mut(user2_id) {
+            user2.partner_id = Some(user1_id.to_string());
+        }
+        // Usually only one side was queued; the newcomer who triggered the match didn't wait at all
+        let waited = [user1_id, user2_id].iter()
+            .filter_map(|id| self.waiting_since.get(*id))
+            .map(|since| since.elapsed())
+            .max();
+        if let Some(waited) = waited {
+            if self.recent_match_waits.len() == MATCH_WAIT_SAMPLES {
+                self.recent_match_waits.pop_front();
+            }
+            self.recent_match_waits.push_back(waited);
+        }
+        self.remove_from_waiting(&[user1_id, user2_id]);
+        // Each side learns who the other is and which id to address WebRTC signalling to
+        for (id, partner_id) in [(user1_id, user2_id), (user2_id, user1_id)] {
+            let partner_username = self.users.get(partner_id).map(|partner| partner.username.clone());
+            if let Some(tx) = self.sessions.get(id) {
+                let event = ServerEvent {
+                    event: "chat_started".to_string(),
+                    data: serde_json::json!({
+                        "partner_username": partner_username,
+                        "target_id": partner_id,
+                    }),
+                };
+                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+            }
+        }
+    }
+
+    async fn create_new_group(&mut self, conn: &ConnId, max_members: Option<usize>, name: Option<String>, keep_history: bool) {
+        let group_code = self.generate_group_code();
+        let max_members = max_members.unwrap_or(self.max_group_size).clamp(2, self.max_group_size);
+        if let Some(user) = self.users.get_mut(conn) {
+            let group = Group {
+                code: group_code.clone(),
+                members: vec![(conn.to_string(), user.username.clone())],
+                admin: conn.to_string(),
+                locked: false,
+                created_at: Instant::now(),
+                max_members,
+                name,
+                history: keep_history.then(VecDeque::new),
+                next_seq: 1,
+            };
+            self.groups.insert(group_code.clone(), group);
+            user.group_id = Some(group_code.clone());
+            if let Some(tx) = self.sessions.get(conn) {
+                let event = ServerEvent {
+                    event: "chat_started".to_string(),
+                    data: serde_json::json!({ "groupCode": group_code.clone() }),
+                };
+                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+
+                let event = ServerEvent {
+                    event: "group_members_update".to_string(),
+                    data: serde_json::json!(vec![user.username.clone()]),
+                };
+                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+            }
+            self.send_group_info(conn, &group_code);
+            self.send_group_joined(conn, &group_code);
+            self.broadcast_group_peers(&group_code);
+        }
+    }
+
+    async fn join_group_by_code(&mut self, conn: &ConnId, group_code: &str) {
+        if self.groups.get(group_code).is_some_and(|group| group.locked) {
+            self.send_event(conn, "group_locked", serde_json::json!({ "groupCode": group_code }));
+            return;
+        }
+        if self.groups.get(group_code).is_some_and(|group| group.members.len() >= group.max_members) {
+            self.send_event(conn, "group_full", serde_json::json!({ "groupCode": group_code }));
+            return;
+        }
+        if let Some(group) = self.groups.get_mut(group_code) {
+            if let Some(user) = self.users.get_mut(conn) {
+                user.username = unique_display_name(&user.chosen_username, &group.usernames());
+                group.members.push((conn.to_string(), user.username.clone()));
+                user.group_id = Some(group_code.to_string());
+                for member_id in group.member_ids() {
+                    if let Some(tx) = self.sessions.get(member_id) {
+                        let event = ServerEvent {
+                            event: "group_members_update".to_string(),
+                            data: serde_json::json!(group.usernames()),
+                        };
+                        let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                        if member_id != conn {
+                            let event = ServerEvent {
+                                event: "user_joined_group".to_string(),
+                                data: serde_json::json!(user.username.clone()),
+                            };
+                            let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                        }
+                    }
+                }
+                if let Some(tx) = self.sessions.get(conn) {
+                    // Carries the display name actually used, which differs from the requested one on a clash
+                    let event = ServerEvent {
+                        event: "chat_started".to_string(),
+                        data: serde_json::json!({
+                            "groupCode": group_code.to_string(),
+                            "username": user.username.clone(),
+                        }),
+                    };
+                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                }
+            }
+            self.send_room_state(conn, group_code);
+            self.send_group_info(conn, group_code);
+            self.send_group_joined(conn, group_code);
+            self.broadcast_group_peers(group_code);
+        } else {
+            if let Some(tx) = self.sessions.get(conn) {
+                let event = ServerEvent {
+                    event: "group_not_found".to_string(),
+                    data: serde_json::json!({}),
+                };
+                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+            }
+        }
+    }
+
+    // Send a joining member everything needed to render the room in one shot
+    fn send_room_state(&self, conn: &ConnId, group_id: &str) {
+        let Some(group) = self.groups.get(group_id) else {
+            return;
+        };
+        let admin = group.members.iter()
+            .find(|(id, _)| id == &group.admin)
+            .map(|(_, username)| username);
+        self.send_event(conn, "room_state", serde_json::json!({
+            "groupCode": group.code,
+            "members": group.usernames(),
+            "admin": admin,
+            "locked": group.locked,
+            "maxMembers": group.max_members,
+            "name": group.name,
+        }));
+    }
+
+    fn group_info(group: &Group) -> Value {
+        serde_json::json!({
+            "groupCode": group.code,
+            "name": group.name,
+            "history": group.history.is_some(),
+        })
+    }
+
+    // Append a relayed message to the group's backlog, if it keeps one; senders outside the group are ignored
+    fn record_history(&mut self, conn: &ConnId, group_id: &str, sender: String, message: EncryptedMessage, message_id: Option<String>) {
+        let history_size = self.group_history_size;
+        let Some(group) = self.groups.get_mut(group_id).filter(|group| group.has_member(conn)) else {
+            return;
+        };
+        let seq = group.next_seq;
+        let Some(history) = group.history.as_mut() else {
+            return;
+        };
+        group.next_seq += 1;
+        if history.len() >= history_size {
+            history.pop_front();
+        }
+        history.push_back(HistoryEntry { seq, sender, message, message_id });
+    }
+
+    // Keep the backlog in line with a delete (`replacement` None) or edit of the author's own message
+    fn update_history(&mut self, conn: &ConnId, message_id: &str, replacement: Option<EncryptedMessage>) {
+        let Some((group_id, username)) = self.users.get(conn)
+            .and_then(|user| Some((user.group_id.clone()?, user.username.clone()))) else {
+            return;
+        };
+        let Some(history) = self.groups.get_mut(&group_id).and_then(|group| group.history.as_mut()) else {
+            return;
+        };
+        let is_target = |entry: &HistoryEntry| entry.sender == username && entry.message_id.as_deref() == Some(message_id);
+        match replacement {
+            Some(message) => {
+                if let Some(entry) = history.iter_mut().find(|entry| is_target(entry)) {
+                    entry.message = message;
+                }
+            }
+            None => history.retain(|entry| !is_target(entry)),
+        }
+    }
+
+    // Page backwards through a group's backlog: up to `limit` messages older than `before_seq`, oldest first
+    fn send_history(&self, conn: &ConnId, group_code: Option<String>, before_seq: Option<u64>, limit: Option<usize>) {
+        let group_id = group_code.or_else(|| self.users.get(conn).and_then(|user| user.group_id.clone()));
+        let Some(group) = group_id.and_then(|group_id| self.groups.get(&group_id)).filter(|group| group.has_member(conn)) else {
+            self.send_event(conn, "not_in_group", serde_json::json!({}));
+            return;
+        };
+        let Some(history) = &group.history else {
+            self.send_event(conn, "chat_history", serde_json::json!({
+                "groupCode": group.code,
+                "enabled": false,
+                "messages": [],
+                "has_more": false,
+            }));
+            return;
+        };
+        let limit = limit.unwrap_or(self.group_history_size).clamp(1, self.group_history_size.max(1));
+        let older: Vec<&HistoryEntry> = history.iter()
+            .filter(|entry| before_seq.is_none_or(|before| entry.seq < before))
+            .collect();
+        let start = older.len().saturating_sub(limit);
+        self.send_event(conn, "chat_history", serde_json::json!({
+            "groupCode": group.code,
+            "enabled": true,
+            "messages": &older[start..],
+            "has_more": start > 0,
+        }));
+    }
+
+    // Tell one member the group's display details
+    fn send_group_info(&self, conn: &ConnId, group_id: &str) {
+        if let Some(group) = self.groups.get(group_id) {
+            self.send_event(conn, "group_info", Self::group_info(group));
+        }
+    }
+
+    // Map each member's username to the conn id the others use as `target_id` for mesh WebRTC.
+    // Only members of the group receive it, so ids never leave the room.
+    fn broadcast_group_peers(&self, group_id: &str) {
+        let Some(group) = self.groups.get(group_id) else {
+            return;
+        };
+        let peers = self.group_peers(group);
+        self.broadcast_to_group(&group.code, "group_peers_update", serde_json::json!({
+            "groupCode": group.code,
+            "peers": peers,
+        }), None);
+    }
+
+    // Each member's username with the conn id others address them by
+    fn group_peers(&self, group: &Group) -> Vec<Value> {
+        group.member_ids()
+            .filter_map(|member_id| self.users.get(member_id))
+            .map(|member| serde_json::json!({
+                "username": member.username,
+                "peer_id": member.id,
+            }))
+            .collect()
+    }
+
+    // Tell only the member who just created or joined a group their own peer id and who else is there,
+    // so they can start WebRTC connections straight away; the others get `user_joined_group`
+    fn send_group_joined(&self, conn: &ConnId, group_id: &str) {
+        let (Some(group), Some(user)) = (self.groups.get(group_id), self.users.get(conn)) else {
+            return;
+        };
+        self.send_event(conn, "group_joined", serde_json::json!({
+            "groupCode": group.code,
+            "self_id": conn,
+            "username": user.username,
+            "peers": self.group_peers(group),
+        }));
+    }
+
+    // Let the group admin change the display name, re-announcing it to every member
+    fn rename_group(&mut self, conn: &ConnId, name: &str) {
+        let Some(group_id) = self.users.get(conn).and_then(|user| user.group_id.clone()) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        if &group.admin != conn {
+            self.send_event(conn, "unauthorized", serde_json::json!({ "action": "rename_group" }));
+            return;
+        }
+        let name = match validate_group_name(name) {
+            Ok(name) => name,
+            Err(reason) => {
+                self.send_event(conn, "invalid_group_name", serde_json::json!({ "reason": reason }));
+                return;
+            }
+        };
+        group.name = Some(name);
+        let info = Self::group_info(group);
+        self.broadcast_to_group(&group_id, "group_info", info, None);
+    }
+
+    // Send an event to every member of a group, optionally skipping one member
+    fn broadcast_to_group(&self, group_id: &RoomId, event: &str, data: Value, skip: Option<&ConnId>) {
+        if let Some(group) = self.groups.get(group_id) {
+            for member_id in group.member_ids() {
+                if Some(member_id) != skip {
+                    self.send_event(member_id, event, data.clone());
+                }
+            }
+        }
+    }
+
+    // Lock or unlock the caller's group; only the group admin may do this
+    fn set_group_locked(&mut self, conn: &ConnId, locked: bool) {
+        let Some(group_id) = self.users.get(conn).and_then(|user| user.group_id.clone()) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        let Some(group) = self.groups.get_mut(&group_id) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        if &group.admin != conn {
+            self.send_event(conn, "unauthorized", serde_json::json!({
+                "action": if locked { "lock_group" } else { "unlock_group" },
+            }));
+            return;
+        }
+        group.locked = locked;
+        let event = if locked { "group_locked" } else { "group_unlocked" };
+        self.broadcast_to_group(&group_id, event, serde_json::json!({ "groupCode": group_id }), None);
+    }
+
+    // Let the group admin remove a member, addressed by username or conn id
+    fn kick_member(&mut self, conn: &ConnId, target: &str) {
+        let Some(group_id) = self.users.get(conn).and_then(|user| user.group_id.clone()) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        let Some(group) = self.groups.get(&group_id) else {
+            self.send_event(conn, "group_not_found", serde_json::json!({}));
+            return;
+        };
+        if &group.admin != conn {
+            self.send_event(conn, "unauthorized", serde_json::json!({ "action": "kick_member" }));
+            return;
+        }
+        let target_conn = group.find_member(target)
+            .filter(|member_id| *member_id != conn)
+            .cloned();
+        let Some(target_conn) = target_conn else {
+            self.send_event(conn, "member_not_found", serde_json::json!({ "target": target }));
+            return;
+        };
+        self.send_event(&target_conn, "kicked_from_group", serde_json::json!({ "groupCode": group_id }));
+        self.remove_user_from_group(&target_conn, LeaveReason::Kicked);
+    }
+
+    // Count a report against the 1:1 partner, or a group member named by `target`. Reports are counted
+    // per reporter IP, not the client-chosen user id, so rejoining under new ids adds nothing.
+    fn report_user(&mut self, conn: &ConnId, target: Option<&str>, reason: Option<&str>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        let reported_conn = match (&user.group_id, target) {
+            (Some(group_id), Some(target)) => self.groups.get(group_id).and_then(|group| {
+                group.find_member(target).cloned()
+            }),
+            (Some(_), None) => None,
+            (None, _) => user.partner_id.clone(),
+        };
+        let Some(reported) = reported_conn.as_ref().and_then(|id| self.users.get(id)).filter(|reported| reported.id != *conn) else {
+            self.send_event(conn, "member_not_found", serde_json::json!({ "target": target }));
+            return;
+        };
+        let reported_user_id = reported.user_id.clone();
+        let reporter_ip = self.conn_ips.get(conn).cloned().unwrap_or_else(|| conn.clone());
+        let reason: String = reason.unwrap_or("").chars().take(MAX_REPORT_REASON_LEN).collect();
+        if !self.abuse_reports.contains_key(&reported_user_id) && self.abuse_reports.len() >= MAX_REPORTED_USERS {
+            if let Some(stalest) = self.abuse_reports.iter().min_by_key(|(_, tally)| tally.updated_at).map(|(id, _)| id.clone()) {
+                self.abuse_reports.remove(&stalest);
+            }
+        }
+        let tally = self.abuse_reports.entry(reported_user_id.clone()).or_insert_with(|| ReportTally {
+            reporter_ips: HashSet::new(),
+            updated_at: Instant::now(),
+        });
+        tally.reporter_ips.insert(reporter_ip);
+        tally.updated_at = Instant::now();
+        let report_count = tally.reporter_ips.len();
+        log::warn!("User {} reported by {} ({} reports): {:?}", reported_user_id, conn, report_count, reason);
+        if report_count >= self.abuse_report_threshold && self.blocked_user_ids.insert(reported_user_id.clone()) {
+            log::warn!("Blocking user {} after {} reports", reported_user_id, report_count);
+        }
+        self.send_event(conn, "report_received", serde_json::json!({}));
+    }
+
+    // Stop matching this user with `user_id` (the current 1:1 partner when omitted). Lists are bounded in
+    // id length, ids per user and users overall, and forgotten BLOCK_LIST_TTL after they last changed.
+    fn block_user(&mut self, conn: &ConnId, user_id: Option<String>) {
+        let Some(user) = self.users.get(conn) else {
+            return;
+        };
+        let blocked = user_id.or_else(|| {
+            user.partner_id.as_ref()
+                .and_then(|partner_id| self.users.get(partner_id))
+                .map(|partner| partner.user_id.clone())
+        });
+        let Some(blocked) = blocked.filter(|blocked| *blocked != user.user_id) else {
+            self.send_event(conn, "no_partner", serde_json::json!({}));
+            return;
+        };
+        if blocked.is_empty() || blocked.chars().count() > MAX_BLOCKED_ID_LEN {
+            self.send_event(conn, "invalid_user_id", serde_json::json!({ "max_length": MAX_BLOCKED_ID_LEN }));
+            return;
+        }
+        let owner = user.user_id.clone();
+        if !self.user_blocks.contains_key(&owner) && self.user_blocks.len() >= MAX_BLOCK_LISTS {
+            if let Some(stalest) = self.user_blocks.iter().min_by_key(|(_, list)| list.updated_at).map(|(id, _)| id.clone()) {
+                self.user_blocks.remove(&stalest);
+            }
+        }
+        log::info!("{} blocked a user", conn);
+        let list = self.user_blocks.entry(owner).or_insert_with(|| BlockList {
+            blocked: VecDeque::new(),
+            updated_at: Instant::now(),
+        });
+        list.updated_at = Instant::now();
+        if !list.blocked.contains(&blocked) {
+            if list.blocked.len() >= MAX_BLOCKS_PER_USER {
+                list.blocked.pop_front();
+            }
+            list.blocked.push_back(blocked);
+        }
+        self.send_event(conn, "user_blocked", serde_json::json!({}));
+    }
+
+    fn expire_block_lists(&mut self) {
+        self.user_blocks.retain(|_, list| list.updated_at.elapsed() < BLOCK_LIST_TTL);
+    }
+
+    // Tallies below the threshold fade out; a user already blocked stays blocked
+    fn expire_abuse_reports(&mut self) {
+        self.abuse_reports.retain(|_, tally| tally.updated_at.elapsed() < ABUSE_REPORT_TTL);
+    }
+
+    // Lobby listing of groups a newcomer could join right now; only counts, never usernames
+    fn send_groups_list(&self, conn: &ConnId) {
+        let groups: Vec<Value> = self.groups.values()
+            .filter(|g| !g.members.is_empty() && !g.locked && g.members.len() < g.max_members)
+            .map(|g| serde_json::json!({
+                "groupCode": g.code,
+                "memberCount": g.members.len(),
+                "maxMembers": g.max_members,
+                "name": g.name,
+            }))
+            .collect();
+        self.send_event(conn, "groups_list", serde_json::json!({ "groups": groups }));
+    }
+
+    async fn join_random_group(&mut self, conn: &ConnId, max_members: Option<usize>) {
+        let group_code_option = {
+            // Never hand the user back a group they already sit in; with nothing else open they get a new one
+            let available_groups: Vec<&Group> = self.groups.values()
+                .filter(|g| !g.members.is_empty() && !g.locked && g.members.len() < g.max_members)
+                .filter(|g| !g.has_member(conn))
+                .collect();
+            // Near-simultaneous random joiners should end up together rather than each sitting in
+            // a scattered group, so a group that was just created and is still alone wins the pick
+            let fresh_group = available_groups.iter()
+                .filter(|g| g.members.len() == 1 && g.created_at.elapsed() < FRESH_GROUP_WINDOW)
+                .max_by_key(|g| g.created_at);
+            if let Some(group) = fresh_group {
+                Some(group.code.clone())
+            } else if available_groups.is_empty() {
+                None
+            } else {
+                let random_index = rand::random::<usize>() % available_groups.len();
+                Some(available_groups[random_index].code.clone())
+            }
+        };
+        
+        match group_code_option {
+            Some(code) => self.join_group_by_code(conn, &code).await,
+            None => self.create_new_group(conn, max_members, None, false).await,
+        }
+    }
+
+    async fn run(mut self, cmd_rx: &mut mpsc::UnboundedReceiver<Command>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut housekeeping = interval(HOUSEKEEPING_INTERVAL);
+        let mut critical_retry = interval(CRITICAL_RETRY_INTERVAL);
+        critical_retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            let cmd = tokio::select! {
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                // Only armed while some frame is parked, so an idle server isn't woken 50 times a second
+                _ = critical_retry.tick(), if self.parked_frames.load(Ordering::Relaxed) > 0 => {
+                    self.retry_critical_sends();
+                    continue;
+                }
+                _ = housekeeping.tick() => {
+                    self.expire_away_sessions().await;
+                    self.warn_slow_consumers();
+                    self.disconnect_stalled_sessions().await;
+                    self.broadcast_group_presence();
+                    self.expire_pair_codes();
+                    self.expire_ringing_calls();
+                    self.expire_message_authors();
+                    self.expire_typing();
+                    self.flush_group_typing();
+                    self.expire_presence();
+                    self.expire_recent_partings();
+                    self.expire_block_lists();
+                    self.expire_abuse_reports();
+                    self.notify_match_timeouts();
+                    self.match_relaxed_waiters().await;
+                    continue;
+                }
+            };
+            match cmd {
+                Command::Connect { conn_tx, end_tx, resume_token, ip, res_tx } => {
+                    let conn_id = self.handle_connect(conn_tx, end_tx, resume_token, ip).await;
+                    let _ = res_tx.send(conn_id);
+                }
+                Command::Disconnect { conn, reason } => {
+                    if self.is_resumable(&conn) {
+                        self.hold_session(&conn);
+                    } else {
+                        self.end_session(&conn, reason).await;
+                    }
+                }
+                Command::Heartbeat { conn } => {
+                    if self.sessions.contains_key(&conn) {
+                        self.last_heartbeat.insert(conn, Instant::now());
+                    }
+                }
+                Command::JoinChat { conn, profile, res_tx } => {
+                    // Checked first so nobody is told they are matched or waiting while joins are paused
+                    if self.maintenance {
+                        self.send_event(&conn, "server_maintenance", serde_json::json!({}));
+                        let _ = res_tx.send(false);
+                        continue;
+                    }
+                    if self.users.get(&conn).is_some_and(|user| user.partner_id.is_some()) {
+                        log::warn!("Ignoring join_chat from {}: already matched", conn);
+                        let _ = res_tx.send(true);
+                        continue;
+                    }
+                    // Joining again would overwrite the user while the old group still lists them
+                    if let Some(group_id) = self.users.get(&conn).and_then(|user| user.group_id.clone()) {
+                        log::warn!("Ignoring join_chat from {}: already in group {}", conn, group_id);
+                        self.send_room_state(&conn, &group_id);
+                        let _ = res_tx.send(true);
+                        continue;
+                    }
+                    if let Some(position) = self.waiting_position(&conn) {
+                        log::warn!("Ignoring join_chat from {}: already waiting", conn);
+                        self.send_event(&conn, "waiting_for_match", self.waiting_for_match_data(position));
+                        let _ = res_tx.send(true);
+                        continue;
+                    }
+                    if self.blocked_user_ids.contains(&profile.user_id) {
+                        log::warn!("Refusing join_chat from blocked user {}", profile.user_id);
+                        self.send_event(&conn, "blocked", serde_json::json!({ "reason": "abuse_reports" }));
+                        let _ = res_tx.send(false);
+                        continue;
+                    }
+                    let username = match sanitize_username(&profile.username) {
+                        name if name.is_empty() => format!("User-{}", profile.user_id.chars().take(5).collect::<String>()),
+                        name => name,
+                    };
+                    // Tell the client which name everyone else will see, so its own display matches
+                    if username != profile.username {
+                        log::info!("Adjusted username for {}", conn);
+                        self.send_event(&conn, "username_adjusted", serde_json::json!({ "username": username }));
+                    }
+                    let user = User {
+                        id: conn.clone(),
+                        user_id: profile.user_id.clone(),
+                        username: username.clone(),
+                        chosen_username: username,
+                        gender: profile.gender.clone(),
+                        preference: normalize_preference(&profile.preference).to_string(),
+                        room_type: profile.room_type.clone(),
+                        partner_id: None,
+                        partner_left_reason: None,
+                        group_id: None,
+                        language: normalize_language(profile.language.as_deref()),
+                        presence: PresenceStatus::Active,
+                        auto_away: false,
+                        last_active: Instant::now(),
+                    };
+                    self.users.insert(conn.clone(), user);
+                    if profile.room_type == "group" {
+                        let join_method = profile.group_join_method.unwrap_or("random".to_string());
+                        if join_method == "create" {
+                            // A bad name doesn't block creation; the group is simply left unnamed
+                            let name = match profile.group_name.as_deref().filter(|name| !name.trim().is_empty()) {
+                                Some(raw) => validate_group_name(raw)
+                                    .map_err(|reason| {
+                                        self.send_event(&conn, "invalid_group_name", serde_json::json!({ "reason": reason }));
+                                    })
+                                    .ok(),
+                                None => None,
+                            };
+                            self.create_new_group(&conn, profile.max_members, name, profile.keep_history.unwrap_or(false)).await;
+                        } else if join_method == "join" && profile.group_code.is_some() {
+                            self.join_group_by_code(&conn, &profile.group_code.unwrap()).await;
+                        } else {
+                            self.join_random_group(&conn, profile.max_members).await;
+                        }
+                    } else {
+                        match (profile.pair_join_method.as_deref(), profile.pair_code) {
+                            (Some("create"), _) => self.create_private_pair(&conn),
+                            (Some("join"), Some(code)) => self.join_private_pair(&conn, &code).await,
+                            _ => self.find_match(&conn).await,
+                        }
+                    }
+                    let _ = res_tx.send(self.has_joined(&conn));
+                }
+                Command::SendMessage { conn, mut message, is_group_chat, group_code, message_id, request_id, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "send_message") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    // Checked before fan-out so one oversized payload can't be multiplied across a group
+                    if message.encrypted.len() > self.max_message_size {
+                        log::warn!("Dropping {} byte message from {}", message.encrypted.len(), conn);
+                        self.send_event(&conn, "message_too_large", serde_json::json!({
+                            "request_id": request_id,
+                            "message_id": message_id,
+                            "max_size": self.max_message_size,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if let Err(retry_after) = self.take_message_token(&conn) {
+                        if self.message_buckets.get(&conn).is_some_and(|bucket| bucket.strikes >= RATE_LIMIT_BAN_STRIKES) {
+                            log::warn!("Banning {}: {} messages in a row while rate limited", conn, RATE_LIMIT_BAN_STRIKES);
+                            self.close_session(&conn, SessionEnd::RateLimitBan).await;
+                            let _ = res_tx.send(());
+                            continue;
+                        }
+                        log::warn!("Rate limited message from {}", conn);
+                        self.send_event(&conn, "rate_limited", serde_json::json!({
+                            "request_id": request_id,
+                            "message_id": message_id,
+                            "retry_after_ms": retry_after.as_millis() as u64,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if !is_valid_nonce(&message.nonce) {
+                        log::warn!("Dropping message with malformed nonce from {}", conn);
+                        self.send_event(&conn, "invalid_nonce", serde_json::json!({
+                            "request_id": request_id,
+                            "message_id": message_id,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    // Only checked once the message is otherwise accepted, so a rate-limited send can be retried as-is
+                    if !self.seen_nonces.entry(conn.clone()).or_default().insert(&message.nonce) {
+                        log::warn!("Dropping replayed nonce from {}", conn);
+                        self.send_event(&conn, "replay_detected", serde_json::json!({
+                            "request_id": request_id,
+                            "message_id": message_id,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if let Some(reply_to) = message.reply_to {
+                        if !(0..=MAX_REPLY_TO_ID).contains(&reply_to) {
+                            log::warn!("Stripping out-of-range reply_to {} from {}", reply_to, conn);
+                            message.reply_to = None;
+                        }
+                    }
+                    if let Some(user) = self.users.get(&conn) {
+                        let mut recipients = 0;
+                        let mut held = Vec::new();
+                        let mut history_group = None;
+                        let sender = user.username.clone();
+                        let payload = receive_message_payload(&message, &sender, &message_id);
+                        if is_group_chat {
+                            let group_id = group_code.or(user.group_id.clone());
+                            if let Some(group_id) = group_id {
+                                history_group = Some(group_id.clone());
+                                if let Some(group) = self.groups.get(&group_id) {
+                                    for member_id in group.member_ids() {
+                                        if member_id != &conn {
+                                            if let Some(tx) = self.sessions.get(member_id) {
+                                                if tx.send_critical(payload.clone().into()).is_ok_and(|sent| sent == CriticalSend::Queued) {
+                                                    recipients += 1;
+                                                }
+                                            } else if self.away_users.contains_key(member_id) {
+                                                held.push((member_id.clone(), payload.clone().into()));
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        } else {
+                            if let Some(partner_id) = &user.partner_id {
+                                if let Some(tx) = self.sessions.get(partner_id) {
+                                    if tx.send_critical(payload.clone().into()).is_ok_and(|sent| sent == CriticalSend::Queued) {
+                                        recipients += 1;
+                                    }
+                                } else if self.away_users.contains_key(partner_id) {
+                                    held.push((partner_id.clone(), payload.clone().into()));
+                                }
+                            }
+                        }
+                        // `message_sent` is the one ack every accepted message gets (the single check); held
+                        // counts copies kept for away recipients. `message_delivered` (the double check) follows
+                        // only when a live recipient's queue actually took the frame: never for held copies, nor
+                        // for frames parked behind a full queue, which may yet be dropped.
+                        self.send_event(&conn, "message_sent", serde_json::json!({
+                            "request_id": request_id,
+                            "message_id": message_id,
+                            "held": held.len(),
+                        }));
+                        if recipients > 0 {
+                            self.send_event(&conn, "message_delivered", serde_json::json!({
+                                "message_id": message_id,
+                                "recipients": recipients,
+                            }));
+                        }
+                        for (member_id, msg) in held {
+                            self.hold_message(&member_id, msg);
+                        }
+                        if let Some(group_id) = history_group {
+                            self.record_history(&conn, &group_id, sender, message, message_id.clone());
+                        }
+                        // The message itself ends the typing state; clients clear the indicator on receive
+                        self.clear_typing(&conn);
+                        self.touch_presence(&conn);
+                        if let Some(message_id) = message_id {
+                            self.record_message_author(&conn, message_id);
+                        }
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::ActivityUpdate { conn, activity, active, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "activity_update") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.touch_presence(&conn);
+                    if activity == Activity::Typing {
+                        if active {
+                            let expires_at = Instant::now() + self.typing_timeout;
+                            self.typing_deadlines.insert(conn.clone(), (is_group_chat, group_code.clone(), expires_at));
+                        } else {
+                            self.typing_deadlines.remove(&conn);
+                        }
+                    }
+                    self.relay_activity(&conn, activity, active, is_group_chat, group_code);
+                    let _ = res_tx.send(());
+                }
+                Command::FileSendingStart { conn, file_id, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "file_sending_start") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    let in_flight = self.file_transfers.entry(conn.clone()).or_default();
+                    if !in_flight.contains(&file_id) && in_flight.len() >= self.max_file_transfers {
+                        self.send_event(&conn, "too_many_transfers", serde_json::json!({
+                            "fileId": file_id,
+                            "max": self.max_file_transfers,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    in_flight.insert(file_id.clone());
+                    if let Some(user) = self.users.get(&conn) {
+                        let event_name = "file_sending_started".to_string();
+                        let event_data = serde_json::json!({
+                            "fileId": file_id,
+                            "username": user.username.clone()
+                        });
+
+                        if is_group_chat {
+                            let group_id = group_code.or(user.group_id.clone());
+                            if let Some(group_id) = group_id {
+                                if let Some(group) = self.groups.get(&group_id) {
+                                    for member_id in group.member_ids() {
+                                        if member_id != &conn {
+                                            if let Some(tx) = self.sessions.get(member_id) {
+                                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
+                                                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        } else {
+                            if let Some(partner_id) = &user.partner_id {
+                                if let Some(tx) = self.sessions.get(partner_id) {
+                                    let event = ServerEvent { event: event_name, data: event_data };
+                                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                }
+                            }
+                        }
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::FileProgress { conn, file_id, bytes_sent, total_bytes, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "file_progress") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.relay_file_progress(&conn, file_id, bytes_sent, total_bytes, is_group_chat, group_code);
+                    let _ = res_tx.send(());
+                }
+                Command::RelayFileChunk { conn, chunk, res_tx } => {
+                    self.relay_file_chunk(&conn, chunk);
+                    let _ = res_tx.send(());
+                }
+                Command::FileSendingEnd { conn, file_id, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "file_sending_end") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if let Some(in_flight) = self.file_transfers.get_mut(&conn) {
+                        in_flight.remove(&file_id);
+                        if in_flight.is_empty() {
+                            self.file_transfers.remove(&conn);
+                        }
+                    }
+                    if let Some(user) = self.users.get(&conn) {
+                        let event_name = "file_sending_ended".to_string();
+                        let event_data = serde_json::json!({
+                            "fileId": file_id,
+                            "username": user.username.clone()
+                        });
+
+                        if is_group_chat {
+                            let group_id = group_code.or(user.group_id.clone());
+                            if let Some(group_id) = group_id {
+                                if let Some(group) = self.groups.get(&group_id) {
+                                    for member_id in group.member_ids() {
+                                        if member_id != &conn {
+                                            if let Some(tx) = self.sessions.get(member_id) {
+                                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
+                                                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        } else {
+                            if let Some(partner_id) = &user.partner_id {
+                                if let Some(tx) = self.sessions.get(partner_id) {
+                                    let event = ServerEvent { event: event_name, data: event_data };
+                                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                }
+                            }
+                        }
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::DeleteMessage { conn, message_id, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "delete_message") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if !self.is_message_author(&conn, &message_id) {
+                        log::warn!("Rejected delete of message {} by non-author {}", message_id, conn);
+                        self.send_event(&conn, "unauthorized", serde_json::json!({
+                            "action": "delete_message",
+                            "messageId": message_id,
+                        }));
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.message_authors.remove(&(conn.clone(), message_id.clone()));
+                    self.update_history(&conn, &message_id, None);
+                    if let Some(user) = self.users.get(&conn) {
+                        let event_name = "message_deleted".to_string();
+                        let event_data = serde_json::json!({ "messageId": message_id });
+
+                        if is_group_chat {
+                            let group_id = group_code.or(user.group_id.clone());
+                            if let Some(group_id) = group_id {
+                                if let Some(group) = self.groups.get(&group_id) {
+                                    for member_id in group.member_ids() {
+                                        if let Some(tx) = self.sessions.get(member_id) {
+                                            let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
+                                            let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                        }
+                                    }
+                                }
+                            }
+                        } else {
+                            if let Some(partner_id) = &user.partner_id {
+                                if let Some(tx) = self.sessions.get(partner_id) {
+                                    let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
+                                    let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                                }
+                            }
+                            if let Some(tx) = self.sessions.get(&conn) {
+                                let event = ServerEvent { event: event_name.clone(), data: event_data.clone() };
+                                let _ = tx.send(serde_json::to_string(&event).unwrap().into());
+                            }
+                        }
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::EditMessage { conn, message_id, message, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "edit_message") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    if !self.is_message_author(&conn, &message_id) {
+                        log::warn!("Rejected edit of message {} by non-author {}", message_id, conn);
+                        self.send_event(&conn, "unauthorized", serde_json::json!({
+                            "action": "edit_message",
+                            "messageId": message_id,
+                        }));
+                    } else if message.encrypted.len() > self.max_message_size {
+                        self.send_event(&conn, "message_too_large", serde_json::json!({
+                            "message_id": message_id,
+                            "max_size": self.max_message_size,
+                        }));
+                    } else {
+                        self.update_history(&conn, &message_id, Some(message.clone()));
+                        self.relay_message_edit(&conn, &message_id, message, is_group_chat, group_code);
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::DisconnectChat { conn, res_tx } => {
+                    self.handle_disconnect(&conn, LeaveReason::Left).await;
+                    let _ = res_tx.send(());
+                }
+                Command::SkipPartner { conn, res_tx } => {
+                    self.skip_partner(&conn).await;
+                    let _ = res_tx.send(());
+                }
+                Command::CancelSearch { conn, res_tx } => {
+                    self.cancel_search(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::UpdatePreference { conn, preference, language, res_tx } => {
+                    self.update_preference(&conn, &preference, language.as_deref()).await;
+                    let _ = res_tx.send(());
+                }
+                Command::LeaveGroup { conn, res_tx } => {
+                    self.leave_group(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::KickMember { conn, target, res_tx } => {
+                    self.kick_member(&conn, &target);
+                    let _ = res_tx.send(());
+                }
+                Command::ReportUser { conn, target, reason, res_tx } => {
+                    self.report_user(&conn, target.as_deref(), reason.as_deref());
+                    let _ = res_tx.send(());
+                }
+                Command::UpdatePresence { conn, status, res_tx } => {
+                    if let Some(user) = self.users.get_mut(&conn) {
+                        user.last_active = Instant::now();
+                    }
+                    self.set_presence(&conn, status, false);
+                    let _ = res_tx.send(());
+                }
+                Command::BlockUser { conn, user_id, res_tx } => {
+                    // Shares the message allowance so a client can't churn the block lists at full speed
+                    match self.take_message_token(&conn) {
+                        Ok(()) => self.block_user(&conn, user_id),
+                        Err(retry_after) => {
+                            self.send_event(&conn, "rate_limited", serde_json::json!({
+                                "retry_after_ms": retry_after.as_millis() as u64,
+                            }));
+                        }
+                    }
+                    let _ = res_tx.send(());
+                }
+                Command::ListGroups { conn, res_tx } => {
+                    self.send_groups_list(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::GetHistory { conn, group_code, before_seq, limit, res_tx } => {
+                    self.send_history(&conn, group_code, before_seq, limit);
+                    let _ = res_tx.send(());
+                }
+                Command::GetStats { res_tx } => {
+                    let _ = res_tx.send(ServerStats {
+                        online: self.sessions.len(),
+                        waiting: self.waiting_users.values().map(Vec::len).sum(),
+                        groups: self.groups.len(),
+                    });
+                }
+                Command::HasLiveSession { token, res_tx } => {
+                    let live = self.resume_tokens.get(&token).is_some_and(|conn| self.sessions.contains_key(conn));
+                    let _ = res_tx.send(live);
+                }
+                Command::QueueDepths { res_tx } => {
+                    let sessions: Vec<SessionQueue> = self.sessions.iter()
+                        .map(|(conn, tx)| SessionQueue {
+                            conn_id: conn.clone(),
+                            depth: tx.queue_depth(),
+                            capacity: tx.tx.max_capacity(),
+                            dropped: tx.dropped.get(),
+                        })
+                        .collect();
+                    let _ = res_tx.send(QueueReport {
+                        slow_consumers: self.sessions.values().filter(|tx| tx.is_slow_consumer()).count(),
+                        max_queue_depth: sessions.iter().map(|queue| queue.depth).max().unwrap_or(0),
+                        dropped_frames: DROPPED_FRAMES.load(Ordering::Relaxed),
+                        sessions,
+                    });
+                }
+                Command::Shutdown { res_tx } => {
+                    self.announce_shutdown().await;
+                    let _ = res_tx.send(());
+                    break;
+                }
+                Command::RenameGroup { conn, name, res_tx } => {
+                    self.rename_group(&conn, &name);
+                    let _ = res_tx.send(());
+                }
+                Command::MessageRead { conn, message_id, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "message_read") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.relay_read_receipt(&conn, &message_id, is_group_chat, group_code);
+                    let _ = res_tx.send(());
+                }
+                Command::ReactMessage { conn, message_id, reaction, action, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&conn, is_group_chat, &group_code, "react_message") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.relay_reaction(&conn, &message_id, &reaction, action, is_group_chat, group_code);
+                    let _ = res_tx.send(());
+                }
+                Command::PartnerStatus { conn, res_tx } => {
+                    self.send_partner_status(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::MyRooms { conn, res_tx } => {
+                    self.send_my_rooms(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::GetServerInfo { conn, res_tx } => {
+                    self.send_server_info(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::OnlineCount { conn, res_tx } => {
+                    self.send_online_count(&conn);
+                    let _ = res_tx.send(());
+                }
+                Command::SetMaintenance { enabled, res_tx } => {
+                    self.maintenance = enabled;
+                    let _ = res_tx.send(());
+                }
+                Command::LockGroup { conn, res_tx } => {
+                    self.set_group_locked(&conn, true);
+                    let _ = res_tx.send(());
+                }
+                Command::UnlockGroup { conn, res_tx } => {
+                    self.set_group_locked(&conn, false);
+                    let _ = res_tx.send(());
+                }
+                Command::PingPeer { conn, target_id, res_tx } => {
+                    let _ = res_tx.send(self.is_peer_online(&conn, target_id.as_deref()));
+                }
+                Command::RelayWebRTCEvent { sender_id, event_type, target_id, data, is_group_chat, group_code, res_tx } => {
+                    if !self.is_group_member_for(&sender_id, is_group_chat, &group_code, "webrtc_relay") {
+                        let _ = res_tx.send(());
+                        continue;
+                    }
+                    self.relay_webrtc_event(sender_id, event_type, target_id, data, is_group_chat, group_code).await;
+                    let _ = res_tx.send(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Track unanswered 1:1 offers so simultaneous offers (glare) resolve to whoever offered first.
+    // Returns true when the event must be dropped because the sender should back off and answer.
+    fn resolve_glare(&mut self, sender_id: &ConnId, event_type: &str, target_id: &ConnId) -> bool {
+        match event_type {
+            "webrtc_offer" => {
+                let competing = (target_id.clone(), sender_id.clone());
+                if self.pending_offers.get(&competing).is_some_and(|at| at.elapsed() < GLARE_WINDOW) {
+                    log::info!("WebRTC glare between {} and {}; {} offered first", sender_id, target_id, target_id);
+                    self.send_event(sender_id, "call_glare", serde_json::json!({ "target_id": target_id }));
+                    return true;
+                }
+                self.pending_offers.insert((sender_id.clone(), target_id.clone()), Instant::now());
+            }
+            "webrtc_answer" => {
+                self.pending_offers.remove(&(target_id.clone(), sender_id.clone()));
+            }
+            "webrtc_end_call" => {
+                self.pending_offers.remove(&(sender_id.clone(), target_id.clone()));
+                self.pending_offers.remove(&(target_id.clone(), sender_id.clone()));
+            }
+            _ => {}
+        }
+        false
+    }
+
+    // Relay WebRTC signaling events between clients
+    #[tracing::instrument(name = "relay", skip(self, target_id, data, is_group_chat, group_code), fields(room = group_code.as_deref().unwrap_or_default()))]
+    pub async fn relay_webrtc_event(
+        &mut self,
+        sender_id: String,
+        event_type: String,
+        target_id: String,
+        data: serde_json::Value,
+        is_group_chat: bool,
+        group_code: Option<String>,
+    ) {
+        // Log full details at the start
+        log::info!("relay_webrtc_event: from={}, event={}, to={}, is_group={}, group_code={:?}",
+            sender_id, event_type, target_id, is_group_chat, group_code);
+            
+        // Find the sender's user for validation
+        let Some(sender) = self.users.get(&sender_id) else {
+            log::error!("WebRTC relay failed: Sender not found {}", sender_id);
+            return;
+        };
+
+        // Signaling only goes to the sender's own peers: the 1:1 partner, or a member of the named group
+        let is_peer = if is_group_chat {
+            target_id.is_empty() || group_code.as_ref()
+                .and_then(|code| self.groups.get(code))
+                .is_some_and(|group| group.has_member(&target_id))
+        } else {
+            sender.partner_id.as_ref() == Some(&target_id)
+        };
+        if !is_peer {
+            log::warn!("WebRTC relay refused: {} is not a peer of {}", target_id, sender_id);
+            self.send_event(&sender_id, "webrtc_error", serde_json::json!({
+                "reason": "invalid_target",
+                "target_id": target_id,
+                "event": event_type,
+            }));
+            return;
+        }
+
+        // The callee's incoming-call prompt shows who is calling; take the name from the server, not the client.
+        // Rings are tracked by caller and call id (generated when the client sent none) until answered or
+        // timed out; only the two sides of a ring may answer or end it, and a ring is never re-armed.
+        let mut data = data;
+        if event_type == "call_ring" {
+            let caller = sender.username.clone();
+            if let Some(fields) = data.as_object_mut() {
+                fields.insert("caller".to_string(), serde_json::json!(caller));
+                let call_id = fields.get("call_id")
+                    .and_then(|v| v.as_str())
+                    .map(String::from)
+                    .unwrap_or_else(|| Uuid::new_v4().to_string());
+                fields.insert("call_id".to_string(), serde_json::json!(call_id));
+                let key = (sender_id.clone(), call_id.clone());
+                if self.ringing_calls.contains_key(&key) {
+                    log::warn!("Refused repeat ring {} from {}", call_id, sender_id);
+                    self.send_event(&sender_id, "webrtc_error", serde_json::json!({
+                        "reason": "already_ringing",
+                        "call_id": call_id,
+                        "event": event_type,
+                    }));
+                    return;
+                }
+                if self.sessions.contains_key(&target_id) {
+                    let expires_at = Instant::now() + self.call_ring_timeout;
+                    self.ringing_calls.insert(key, (target_id.clone(), expires_at));
+                }
+            }
+        } else if matches!(event_type.as_str(), "call_accept" | "call_reject" | "webrtc_end_call") {
+            if let Some(call_id) = data.get("call_id").and_then(|v| v.as_str()) {
+                let own = self.ringing_calls.iter()
+                    .find(|((caller, id), (callee, _))| id == call_id && (*caller == sender_id || *callee == sender_id))
+                    .map(|(key, _)| key.clone());
+                match own {
+                    Some(key) => {
+                        self.ringing_calls.remove(&key);
+                    }
+                    None if self.ringing_calls.keys().any(|(_, id)| id == call_id) => {
+                        log::warn!("Refused {} for call {} from {}, who is not on it", event_type, call_id, sender_id);
+                        self.send_event(&sender_id, "webrtc_error", serde_json::json!({
+                            "reason": "not_in_call",
+                            "call_id": call_id,
+                            "event": event_type,
+                        }));
+                        return;
+                    }
+                    None => {}
+                }
+            }
+        }
+        
+        // Debug the data structure
+        log::debug!("WebRTC event data: {}", 
+                   serde_json::to_string_pretty(&data).unwrap_or_else(|_| "Invalid JSON".to_string()));
+        
+        // Prepare the event to send
+        let event = ServerEvent {
+            event: event_type.clone(),
+            data: data.clone(),
+        };
+        
+        // Debug the final event structure
+        log::debug!("WebRTC formatted event: {}", 
+                   serde_json::to_string_pretty(&event).unwrap_or_else(|_| "Invalid JSON".to_string()));
+        
+        let event_json = match serde_json::to_string(&event) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("Failed to serialize WebRTC event: {}", e);
+                return;
+            }
+        };
+        
+        // For group chat, relay to the targeted member, or to every other member when no target is set
+        if is_group_chat {
+            if let Some(code) = group_code {
+                if let Some(group) = self.groups.get(&code) {
+                    log::info!("Relaying WebRTC {} to {} group members in group {}",
+                        event_type, group.members.len(), code);
+                    
+                    let mut relay_count = 0;
+                    for member_id in group.member_ids() {
+                        let is_recipient = if target_id.is_empty() {
+                            member_id != &sender_id
+                        } else {
+                            member_id == &target_id && member_id != &sender_id
+                        };
+                        if is_recipient {
+                            if let Some(tx) = self.sessions.get(member_id) {
+                                if let Err(e) = tx.send_critical(event_json.clone().into()) {
+                                    log::error!("Failed to relay WebRTC event to {}: {}", member_id, e);
+                                } else {
+                                    relay_count += 1;
+                                }
+                            }
+                        }
+                    }
+                    log::info!("Successfully relayed WebRTC {} to {} member(s) in group {}",
+                        event_type, relay_count, code);
+                } else {
+                    log::error!("WebRTC relay failed: Group {} not found", code);
+                }
+            } else {
+                log::error!("WebRTC relay failed: No group code provided for group chat");
+            }
+        } else {
+            // For private chat, relay directly to target
+            if self.resolve_glare(&sender_id, &event_type, &target_id) {
+                return;
+            }
+            if let Some(tx) = self.sessions.get(&target_id) {
+                match tx.send_critical(event_json.into()) {
+                    Ok(_) => {
+                        log::info!("Successfully relayed WebRTC {} from {} to {}", 
+                            event_type, sender_id, target_id);
+                    },
+                    Err(e) => {
+                        log::error!("Failed to relay WebRTC event to {}: {}", target_id, e);
+                    }
+                }
+            } else {
+                log::error!("Failed to relay WebRTC event: Target session not found {}", target_id);
+                // Let the caller know instead of leaving the call hanging
+                self.send_event(&sender_id, "peer_unavailable", serde_json::json!({
+                    "target_id": target_id,
+                    "event": event_type,
+                }));
+            }
+        }
+    }
+}
+
+// Handle and command sender for chat server
+#[derive(Debug, Clone)]
+pub struct ChatServerHandle {
+    cmd_tx: mpsc::UnboundedSender<Command>,
+}
+
+#[cfg(test)]
+impl ChatServerHandle {
+    // A handle whose command loop has already gone away, so every request fails
+    pub(crate) fn disconnected() -> Self {
+        let (cmd_tx, _) = mpsc::unbounded_channel();
+        ChatServerHandle { cmd_tx }
+    }
+}
+
+impl ChatServerHandle {
+    // Register client message sender and obtain connection ID, resuming a previous session if possible
+    pub async fn connect(&self, conn_tx: mpsc::Sender<Msg>, end_tx: oneshot::Sender<SessionEnd>, resume_token: Option<String>, ip: String) -> Result<Option<(ConnId, bool)>, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::Connect { conn_tx, end_tx, resume_token, ip, res_tx })?;
+        Ok(res_rx.await?)
+    }
+
+    // Unregister message sender and broadcast disconnection message to current room
+    pub fn disconnect(&self, conn: ConnId, reason: LeaveReason) {
+        // Connections still closing after a shutdown have nobody left to tell
+        let _ = self.cmd_tx.send(Command::Disconnect { conn, reason });
+    }
+
+    // Record that the client answered a heartbeat
+    pub fn heartbeat(&self, conn: ConnId) {
+        let _ = self.cmd_tx.send(Command::Heartbeat { conn });
+    }
+
+    // Join chat with a user profile; true once the connection is matched, waiting or in a group
+    pub async fn join_chat(&self, conn: ConnId, profile: UserProfile) -> Result<bool, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::JoinChat { conn, profile, res_tx })?;
+        Ok(res_rx.await?)
+    }
+
+    // Send a message
+    pub async fn send_message(&self, conn: ConnId, message: EncryptedMessage, is_group_chat: bool, group_code: Option<String>, message_id: Option<String>, request_id: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::SendMessage { conn, message, is_group_chat, group_code, message_id, request_id, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Start or stop an activity indicator (typing, recording, uploading)
+    pub async fn activity_update(&self, conn: ConnId, activity: Activity, active: bool, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::ActivityUpdate { conn, activity, active, is_group_chat, group_code, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // New method for file sending start
+    pub async fn file_sending_start(&self, conn: ConnId, file_id: String, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::FileSendingStart {
+            conn,
+            file_id,
+            is_group_chat,
+            group_code,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // New method for file sending end
+    pub async fn file_sending_end(&self, conn: ConnId, file_id: String, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::FileSendingEnd {
+            conn,
+            file_id,
+            is_group_chat,
+            group_code,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Relay a progress update for an in-flight file
+    pub async fn file_progress(&self, conn: ConnId, file_id: String, bytes_sent: u64, total_bytes: u64, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::FileProgress {
+            conn,
+            file_id,
+            bytes_sent,
+            total_bytes,
+            is_group_chat,
+            group_code,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Relay one binary file chunk from this connection
+    pub async fn relay_file_chunk(&self, conn: ConnId, chunk: FileChunk) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::RelayFileChunk {
+            conn,
+            chunk,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // New method for deleting a message
+    pub async fn delete_message(&self, conn: ConnId, message_id: String, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::DeleteMessage {
+            conn,
+            message_id,
+            is_group_chat,
+            group_code,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Replace the ciphertext of a message this connection sent earlier
+    pub async fn edit_message(&self, conn: ConnId, message_id: String, message: EncryptedMessage, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::EditMessage {
+            conn,
+            message_id,
+            message,
+            is_group_chat,
+            group_code,
+            res_tx,
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Disconnect from chat
+    pub async fn disconnect_chat(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::DisconnectChat { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Change who to look for and search again with the new criteria
+    pub async fn update_preference(&self, conn: ConnId, preference: String, language: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::UpdatePreference { conn, preference, language, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Stop looking for a partner but stay connected
+    pub async fn cancel_search(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::CancelSearch { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Leave the current partner and look for a new one
+    pub async fn skip_partner(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::SkipPartner { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Leave the current group without closing the connection
+    pub async fn leave_group(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::LeaveGroup { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Remove a member from the caller's group (admin only)
+    pub async fn kick_member(&self, conn: ConnId, target: String) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::KickMember { conn, target, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Report the partner, or a group member by username or conn id
+    pub async fn report_user(&self, conn: ConnId, target: Option<String>, reason: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::ReportUser { conn, target, reason, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Set this user's presence to active or away
+    pub async fn update_presence(&self, conn: ConnId, status: PresenceStatus) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::UpdatePresence { conn, status, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Stop ever being matched with a user again
+    pub async fn block_user(&self, conn: ConnId, user_id: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::BlockUser { conn, user_id, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Current online, waiting and group counts
+    pub async fn stats(&self) -> Result<ServerStats, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::GetStats { res_tx })?;
+        Ok(res_rx.await?)
+    }
+
+    // Outgoing queue depth of every session, for operators chasing clients that can't keep up
+    pub async fn queue_depths(&self) -> Result<QueueReport, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::QueueDepths { res_tx })?;
+        Ok(res_rx.await?)
+    }
+
+    // Whether a resume token belongs to a session with a live socket right now; an away session doesn't count
+    pub async fn has_live_session(&self, token: String) -> Result<bool, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::HasLiveSession { token, res_tx })?;
+        Ok(res_rx.await?)
+    }
+
+    // Tell every client the server is going away, then stop the command loop
+    pub async fn shutdown(&self) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::Shutdown { res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Fetch a page of a group's encrypted backlog
+    pub async fn get_history(&self, conn: ConnId, group_code: Option<String>, before_seq: Option<u64>, limit: Option<usize>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::GetHistory { conn, group_code, before_seq, limit, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Ask for the groups that are currently open to new members
+    pub async fn list_groups(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::ListGroups { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Change the display name of the caller's group (admin only)
+    pub async fn rename_group(&self, conn: ConnId, name: String) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::RenameGroup { conn, name, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Report that a received message has been displayed
+    pub async fn message_read(&self, conn: ConnId, message_id: String, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::MessageRead { conn, message_id, is_group_chat, group_code, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Add or remove an emoji reaction on a message
+    pub async fn react_message(&self, conn: ConnId, message_id: String, reaction: String, action: ReactionAction, is_group_chat: bool, group_code: Option<String>) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::ReactMessage { conn, message_id, reaction, action, is_group_chat, group_code, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Ask whether the 1:1 partner is still connected
+    pub async fn partner_status(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::PartnerStatus { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Ask which partner and groups the connection currently belongs to
+    pub async fn my_rooms(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::MyRooms { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Ask for the limits and features of this deployment
+    pub async fn server_info(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::GetServerInfo { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Ask for the number of connected sessions
+    pub async fn online_count(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::OnlineCount { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Enable or disable maintenance (read-only) mode
+    pub async fn set_maintenance(&self, enabled: bool) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::SetMaintenance { enabled, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Lock the caller's group against new joins (admin only)
+    pub async fn lock_group(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::LockGroup { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Reopen the caller's group to new joins (admin only)
+    pub async fn unlock_group(&self, conn: ConnId) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::UnlockGroup { conn, res_tx })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Update the relay_webrtc_event method
+    pub async fn relay_webrtc_event(
+        &self,
+        sender_id: ConnId, 
+        event_type: String, 
+        target_id: String, 
+        data: Value, 
+        is_group_chat: bool, 
+        group_code: Option<String>
+    ) -> Result<(), ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx.send(Command::RelayWebRTCEvent {
+            sender_id, event_type, target_id, data, is_group_chat, group_code, res_tx
+        })?;
+        res_rx.await?;
+        Ok(())
+    }
+
+    // Whether the partner (or the given group member) currently has a live session
+    pub async fn ping_peer(&self, conn: ConnId, target_id: Option<ConnId>) -> Result<bool, ServerUnavailable> {
+        let (res_tx, res_rx) = oneshot::channel();
+        self.cmd_tx
+            .send(Command::PingPeer { conn, target_id, res_tx })?;
+        Ok(res_rx.await?)
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A session whose queue holds `capacity` frames, plus the end the connection would read from
+    fn session_queue(capacity: usize) -> (SessionTx, mpsc::Receiver<Msg>) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let (end_tx, _) = oneshot::channel();
+        (SessionTx::new(tx, end_tx, Arc::default()), rx)
+    }
+
+    fn user(conn: &str, gender: &str, preference: &str) -> User {
+        User {
+            id: conn.to_string(),
+            user_id: format!("user-{}", conn),
+            username: conn.to_string(),
+            chosen_username: conn.to_string(),
+            gender: gender.to_string(),
+            preference: preference.to_string(),
+            room_type: "chat".to_string(),
+            partner_id: None,
+            partner_left_reason: None,
+            group_id: None,
+            language: None,
+            presence: PresenceStatus::Active,
+            auto_away: false,
+            last_active: Instant::now(),
+        }
+    }
+
+    // Put a user straight into the waiting pool, as find_match does when nobody suits them yet
+    fn enqueue(server: &mut ChatServer, user: User) {
+        let key = WaitKey { gender: user.gender.clone(), preference: user.preference.clone() };
+        server.waiting_users.entry(key).or_default().push(user.id.clone());
+        server.waiting_since.insert(user.id.clone(), Instant::now());
+        server.users.insert(user.id.clone(), user);
+    }
+
+    fn text(msg: Msg) -> String {
+        match msg {
+            Msg::Text(text) => text,
+            Msg::Binary(_) => panic!("expected a text frame"),
+        }
+    }
+
+    // Register a session through handle_connect, returning its id, whether it resumed and its frames
+    async fn connect_from(server: &mut ChatServer, ip: &str, resume_token: Option<String>) -> Option<(ConnId, bool, mpsc::Receiver<Msg>)> {
+        let (tx, rx) = mpsc::channel(64);
+        let (end_tx, _) = oneshot::channel();
+        let (conn, resumed) = server.handle_connect(tx, end_tx, resume_token, ip.to_string()).await?;
+        Some((conn, resumed, rx))
+    }
+
+    // A fresh session from an address of its own, so the per-IP cap never gets in the way
+    async fn connect(server: &mut ChatServer) -> (ConnId, mpsc::Receiver<Msg>) {
+        let (conn, _, rx) = connect_from(server, &Uuid::new_v4().to_string(), None).await.expect("under the per-IP cap");
+        (conn, rx)
+    }
+
+    // Two connected sessions chatting 1:1
+    async fn pair(server: &mut ChatServer, a: &ConnId, b: &ConnId) {
+        server.users.insert(a.clone(), user(a, "male", "female"));
+        server.users.insert(b.clone(), user(b, "female", "male"));
+        server.connect_users(a, b).await;
+    }
+
+    // Every event sent to a session since the last call, as (name, data)
+    fn events(rx: &mut mpsc::Receiver<Msg>) -> Vec<(String, Value)> {
+        let mut events = Vec::new();
+        while let Ok(msg) = rx.try_recv() {
+            let mut event: Value = serde_json::from_str(&text(msg)).unwrap();
+            let name = event["event"].as_str().unwrap().to_string();
+            events.push((name, event["data"].take()));
+        }
+        events
+    }
+
+    fn profile(user_id: &str, gender: &str, preference: &str) -> UserProfile {
+        serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "username": user_id,
+            "preference": preference,
+            "gender": gender,
+            "room_type": "chat",
+        }))
+        .unwrap()
+    }
+
+    // A session registered with a running server, kept open by holding on to its receiving ends
+    async fn connect_to(handle: &ChatServerHandle) -> (ConnId, mpsc::Receiver<Msg>, oneshot::Receiver<SessionEnd>) {
+        let (tx, rx) = mpsc::channel(64);
+        let (end_tx, end_rx) = oneshot::channel();
+        let (conn, _) = handle.connect(tx, end_tx, None, Uuid::new_v4().to_string()).await.unwrap().unwrap();
+        (conn, rx, end_rx)
+    }
+
+    // A well-formed message whose nonce is unique per `n`, so replay protection lets it through
+    fn encrypted_message(n: u8) -> EncryptedMessage {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[0] = n;
+        EncryptedMessage {
+            encrypted: "Y2lwaGVydGV4dA==".to_string(),
+            nonce: base64::engine::general_purpose::STANDARD.encode(nonce),
+            reply_to: None,
+        }
+    }
+
+    fn event_names(rx: &mut mpsc::Receiver<Msg>) -> Vec<String> {
+        events(rx).into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn critical_send_goes_out_once_the_queue_drains_within_the_retry_window() {
+        let (tx, mut rx) = session_queue(1);
+        tx.send("first".to_string().into()).unwrap();
+        assert_eq!(tx.send_critical("message".to_string().into()).unwrap(), CriticalSend::Parked);
+        assert!(tx.send("typing".to_string().into()).is_err(), "sheddable frames are still dropped");
+
+        tokio::time::advance(CRITICAL_RETRY_WINDOW / 2).await;
+        assert_eq!(text(rx.try_recv().unwrap()), "first");
+        tx.retry_critical();
+        assert_eq!(text(rx.try_recv().unwrap()), "message");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn critical_send_is_dropped_once_the_retry_window_passes() {
+        let (tx, mut rx) = session_queue(1);
+        tx.send("first".to_string().into()).unwrap();
+        tx.send_critical("message".to_string().into()).unwrap();
+
+        tokio::time::advance(CRITICAL_RETRY_WINDOW + Duration::from_millis(1)).await;
+        rx.try_recv().unwrap();
+        tx.retry_critical();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(tx.dropped.get(), 1);
+    }
+
+    #[test]
+    fn queue_depth_counts_frames_queued_but_not_yet_sent() {
+        let (tx, mut rx) = session_queue(4);
+        assert_eq!(tx.queue_depth(), 0);
+        for n in 0..3 {
+            tx.send(format!("frame {}", n).into()).unwrap();
+        }
+        assert_eq!(tx.queue_depth(), 3);
+        assert!(tx.is_slow_consumer(), "3 of 4 is at the 75% threshold");
+
+        rx.try_recv().unwrap();
+        assert_eq!(tx.queue_depth(), 2);
+        assert!(!tx.is_slow_consumer());
+
+        tx.send("frame 3".to_string().into()).unwrap();
+        tx.send("frame 4".to_string().into()).unwrap();
+        tx.send_critical("message".to_string().into()).unwrap();
+        assert_eq!(tx.queue_depth(), 5, "a critical frame waiting for room is still queued");
+    }
+
+    #[test]
+    fn waiting_partner_must_accept_the_seeker_too() {
+        let mut server = ChatServer::new();
+        let seeker = user("seeker", "male", "female");
+        enqueue(&mut server, user("wants-women", "female", "female"));
+        enqueue(&mut server, user("also-a-man", "male", "female"));
+        assert_eq!(server.find_waiting_partner(&seeker, true), None);
+
+        enqueue(&mut server, user("match", "female", "male"));
+        assert_eq!(server.find_waiting_partner(&seeker, true).as_deref(), Some("match"));
+        assert_eq!(server.find_waiting_partner(&seeker, false).as_deref(), Some("match"));
+    }
+
+    #[test]
+    fn random_partner_pick_is_uniform_over_eligible_waiters() {
+        let mut server = ChatServer::new();
+        let seeker = user("seeker", "male", "female");
+        // Waiters the seeker just skipped share the queue, ahead of the eligible ones. A scan from a
+        // random start would hand the first eligible waiter after them most of the picks.
+        for n in 0..6 {
+            let skipped = user(&format!("skipped-{}", n), "female", "male");
+            server.remember_parting(&seeker.user_id, &skipped.user_id);
+            enqueue(&mut server, skipped);
+        }
+        enqueue(&mut server, user("a", "female", "male"));
+        enqueue(&mut server, user("b", "female", "male"));
+        enqueue(&mut server, user("c", "female", "any"));
+
+        let mut picks: HashMap<ConnId, usize> = HashMap::new();
+        for _ in 0..3000 {
+            let partner = server.find_waiting_partner(&seeker, true).expect("three eligible waiters");
+            *picks.entry(partner).or_default() += 1;
+        }
+        assert_eq!(picks.len(), 3, "only eligible waiters are picked: {:?}", picks);
+        for (partner, count) in &picks {
+            assert!((800..=1200).contains(count), "{} picked {} of 3000 times", partner, count);
+        }
+    }
+
+    #[test]
+    fn random_partner_pick_never_returns_a_filtered_out_waiter() {
+        let mut server = ChatServer::new();
+        let mut seeker = user("seeker", "male", "female");
+        seeker.language = Some("en".to_string());
+        // The seeker's own stale entry, and someone of each kind the filters turn away, spread across
+        // both compatible queues
+        let mut stale = user("seeker", "male", "female");
+        stale.language = seeker.language.clone();
+        enqueue(&mut server, stale);
+        let skipped = user("skipped", "female", "male");
+        server.remember_parting(&seeker.user_id, &skipped.user_id);
+        enqueue(&mut server, skipped);
+        let blocked = user("blocked", "female", "any");
+        server.user_blocks.insert(seeker.user_id.clone(), BlockList {
+            blocked: VecDeque::from([blocked.user_id.clone()]),
+            updated_at: Instant::now(),
+        });
+        enqueue(&mut server, blocked);
+        let mut other_language = user("other-language", "female", "male");
+        other_language.language = Some("fr".to_string());
+        enqueue(&mut server, other_language);
+        enqueue(&mut server, user("wants-women", "female", "female"));
+        for _ in 0..200 {
+            assert_eq!(server.find_waiting_partner(&seeker, true), None);
+        }
+
+        enqueue(&mut server, user("a", "female", "male"));
+        enqueue(&mut server, user("b", "female", "any"));
+        let mut picks: HashMap<ConnId, usize> = HashMap::new();
+        for _ in 0..2000 {
+            let partner = server.find_waiting_partner(&seeker, true).expect("two eligible waiters");
+            *picks.entry(partner).or_default() += 1;
+        }
+        assert_eq!(picks.keys().map(String::as_str).collect::<HashSet<_>>(), HashSet::from(["a", "b"]));
+        for (partner, count) in &picks {
+            assert!((850..=1150).contains(count), "{} picked {} of 2000 times", partner, count);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_partner_resumes_the_pairing_within_the_grace_period() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        let token = server.session_tokens[&b].clone();
+
+        assert!(server.is_resumable(&b));
+        server.hold_session(&b);
+        assert!(event_names(&mut a_rx).contains(&"partner_away".to_string()));
+
+        tokio::time::advance(RESUME_GRACE_PERIOD / 2).await;
+        let (resumed_id, resumed, mut b_rx) = connect_from(&mut server, "10.0.0.1", Some(token)).await.unwrap();
+        assert_eq!((resumed_id.as_str(), resumed), (b.as_str(), true));
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
+        assert_eq!(event_names(&mut a_rx), ["partner_reconnected"]);
+        assert!(event_names(&mut b_rx).contains(&"partner_reconnected".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_grace_period_requeues_the_partner() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        let token = server.session_tokens[&b].clone();
+        server.hold_session(&b);
+        event_names(&mut a_rx);
+
+        tokio::time::advance(RESUME_GRACE_PERIOD + Duration::from_secs(1)).await;
+        server.expire_away_sessions().await;
+        assert!(!server.users.contains_key(&b));
+        assert_eq!(server.users[&a].partner_id, None);
+        assert_eq!(server.waiting_position(&a), Some(1));
+        assert_eq!(event_names(&mut a_rx), ["partner_disconnected", "waiting_for_match"]);
+
+        let (new_id, resumed, _) = connect_from(&mut server, "10.0.0.1", Some(token)).await.unwrap();
+        assert!(!resumed && new_id != b, "an expired token starts a fresh session");
+    }
+
+    #[tokio::test]
+    async fn relay_to_a_missing_partner_session_reports_peer_unavailable() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.hold_session(&b);
+        events(&mut a_rx);
+
+        server.relay_webrtc_event(a.clone(), "webrtc_offer".to_string(), b.clone(), serde_json::json!({}), false, None).await;
+        let sent = events(&mut a_rx);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "peer_unavailable");
+        assert_eq!(sent[0].1["target_id"], b.as_str());
+        assert_eq!(sent[0].1["event"], "webrtc_offer");
+    }
+
+    #[test]
+    fn any_preference_pairs_only_with_someone_who_accepts_the_seeker() {
+        let mut server = ChatServer::new();
+        assert_eq!(normalize_preference("both"), "any");
+
+        // Male wants any: fine with a woman looking for a man
+        enqueue(&mut server, user("woman-wants-man", "female", "male"));
+        assert_eq!(server.find_waiting_partner(&user("m", "male", "any"), true).as_deref(), Some("woman-wants-man"));
+
+        // Any wants female: a woman open to anyone still needs a waiter who wants a woman
+        let seeker = user("f", "female", "any");
+        assert_eq!(server.find_waiting_partner(&seeker, true), None);
+        enqueue(&mut server, user("man-wants-woman", "male", "female"));
+        assert_eq!(server.find_waiting_partner(&seeker, true).as_deref(), Some("man-wants-woman"));
+    }
+
+    #[test]
+    fn any_wants_any_pairs_with_any_gender() {
+        let mut server = ChatServer::new();
+        enqueue(&mut server, user("open", "male", "any"));
+        assert_eq!(server.find_waiting_partner(&user("f", "female", "any"), true).as_deref(), Some("open"));
+        assert_eq!(server.find_waiting_partner(&user("m", "male", "any"), true).as_deref(), Some("open"));
+    }
+
+    #[tokio::test]
+    async fn users_with_conflicting_preferences_are_never_connected() {
+        let mut server = ChatServer::new();
+        let (a, _) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        // He wants a woman, but she is waiting for another woman
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.users.insert(b.clone(), user(&b, "female", "female"));
+
+        server.find_match(&b).await;
+        server.find_match(&a).await;
+        assert_eq!(server.users[&a].partner_id, None);
+        assert_eq!(server.users[&b].partner_id, None);
+        assert!(server.waiting_position(&a).is_some() && server.waiting_position(&b).is_some());
+        assert!(!event_names(&mut b_rx).contains(&"chat_started".to_string()));
+    }
+
+    #[tokio::test]
+    async fn survivor_of_a_disconnect_is_matched_with_the_next_waiter() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _) = connect(&mut server).await;
+        let (c, mut c_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        enqueue(&mut server, user(&c, "female", "male"));
+        events(&mut a_rx);
+
+        server.end_session(&b, LeaveReason::Left).await;
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&c));
+        assert_eq!(server.users[&c].partner_id.as_ref(), Some(&a));
+        assert_eq!(event_names(&mut a_rx), ["partner_disconnected", "chat_started"]);
+        assert_eq!(event_names(&mut c_rx), ["welcome", "chat_started"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_away_survivor_is_only_matched_once_they_resume() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, _) = connect(&mut server).await;
+        let (c, mut c_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        enqueue(&mut server, user(&c, "female", "male"));
+        let token = server.session_tokens[&a].clone();
+        server.hold_session(&a);
+
+        server.end_session(&b, LeaveReason::Left).await;
+        assert_eq!(server.users[&a].partner_id, None, "nobody is paired with a socket that is gone");
+        assert_eq!(server.waiting_position(&a), None);
+        assert_eq!(server.users[&c].partner_id, None);
+        assert_eq!(event_names(&mut c_rx), ["welcome"]);
+
+        let (_, resumed, mut a_rx) = connect_from(&mut server, "10.0.0.1", Some(token)).await.unwrap();
+        assert!(resumed);
+        let names: Vec<String> = event_names(&mut a_rx).into_iter().filter(|name| name != "welcome").collect();
+        assert_eq!(names, ["partner_disconnected", "chat_started"]);
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&c));
+        assert_eq!(server.users[&a].partner_left_reason, None);
+        assert_eq!(event_names(&mut c_rx), ["chat_started"]);
+    }
+
+    #[tokio::test]
+    async fn joining_twice_queues_the_connection_once() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+
+        assert!(handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert!(handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert_eq!(handle.stats().await.unwrap().waiting, 1);
+        let waiting: Vec<_> = events(&mut rx).into_iter().filter(|(name, _)| name == "waiting_for_match").collect();
+        assert_eq!(waiting.len(), 2);
+        assert!(waiting.iter().all(|(_, data)| data["position"] == 1), "{:?}", waiting);
+    }
+
+    #[tokio::test]
+    async fn join_chat_from_a_group_member_leaves_them_in_the_group() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        let mut create = profile("u1", "male", "female");
+        create.room_type = "group".to_string();
+        create.group_join_method = Some("create".to_string());
+        assert!(handle.join_chat(conn.clone(), create).await.unwrap());
+        events(&mut rx);
+
+        assert!(handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap());
+        let stats = handle.stats().await.unwrap();
+        assert_eq!((stats.groups, stats.waiting), (1, 0));
+        assert_eq!(event_names(&mut rx), ["room_state"]);
+    }
+
+    #[tokio::test]
+    async fn rapid_messages_beyond_the_burst_are_rate_limited() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("ua", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("ub", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        for n in 0..20 {
+            handle.send_message(a.clone(), encrypted_message(n), false, None, Some(format!("m{}", n)), None).await.unwrap();
+        }
+        let limited = event_names(&mut a_rx).iter().filter(|name| *name == "rate_limited").count();
+        let received = event_names(&mut b_rx).iter().filter(|name| *name == "receive_message").count();
+        let (burst, _) = keys::get_message_rate();
+        // A slow runner may refill a token or two mid-burst, but never ten
+        assert!(received >= burst as usize && limited > 0, "{} received, {} limited", received, limited);
+        assert_eq!(received + limited, 20);
+    }
+
+    #[tokio::test]
+    async fn connections_beyond_the_per_ip_cap_are_refused() {
+        let mut server = ChatServer::new();
+        let mut open = Vec::new();
+        for _ in 0..server.max_connections_per_ip {
+            open.push(connect_from(&mut server, "203.0.113.7", None).await.expect("under the cap"));
+        }
+        assert!(connect_from(&mut server, "203.0.113.7", None).await.is_none());
+        assert!(connect_from(&mut server, "203.0.113.8", None).await.is_some(), "other addresses are unaffected");
+
+        let (conn, _, _) = open.pop().unwrap();
+        server.end_session(&conn, LeaveReason::Left).await;
+        assert!(connect_from(&mut server, "203.0.113.7", None).await.is_some(), "a closed session frees its slot");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unanswered_call_times_out_on_both_sides() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.relay_webrtc_event(a.clone(), "call_ring".to_string(), b.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        events(&mut a_rx);
+        assert_eq!(events(&mut b_rx).last().unwrap().0, "call_ring");
+
+        tokio::time::advance(server.call_ring_timeout - Duration::from_secs(1)).await;
+        server.expire_ringing_calls();
+        assert!(events(&mut a_rx).is_empty());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        server.expire_ringing_calls();
+        let (name, data) = events(&mut a_rx).pop().unwrap();
+        assert_eq!((name.as_str(), &data["call_id"], &data["target_id"]), ("call_timeout", &serde_json::json!("call-1"), &serde_json::json!(b)));
+        let (name, data) = events(&mut b_rx).pop().unwrap();
+        assert_eq!((name.as_str(), &data["target_id"]), ("call_timeout", &serde_json::json!(a)));
+        assert!(server.ringing_calls.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn typing_stops_on_its_own_when_no_update_follows() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("ua", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("ub", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.activity_update(a.clone(), Activity::Typing, true, false, None).await.unwrap();
+        assert!(event_names(&mut b_rx).contains(&"typing_started".to_string()));
+
+        let timeout = keys::get_typing_timeout();
+        tokio::time::sleep(timeout - Duration::from_secs(1)).await;
+        assert!(!event_names(&mut b_rx).contains(&"typing_stopped".to_string()));
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let stopped: Vec<_> = events(&mut b_rx).into_iter()
+            .filter(|(name, data)| name == "typing_stopped" || (name == "activity_update" && data["active"] == false))
+            .collect();
+        assert_eq!(stopped.len(), 2, "{:?}", stopped);
+        assert!(events(&mut a_rx).iter().all(|(name, _)| name != "typing_stopped"), "the typist isn't told about themselves");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_waiter_is_told_once_about_the_match_timeout_and_stays_queued() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap();
+        events(&mut rx);
+
+        tokio::time::sleep(keys::get_match_timeout() + Duration::from_secs(5)).await;
+        let timeouts: Vec<_> = events(&mut rx).into_iter().filter(|(name, _)| name == "match_timeout").collect();
+        assert_eq!(timeouts.len(), 1, "{:?}", timeouts);
+        assert_eq!(timeouts[0].1["waited_secs"], keys::get_match_timeout().as_secs());
+        assert_eq!(handle.stats().await.unwrap().waiting, 1);
+    }
+
+    #[tokio::test]
+    async fn join_chat_reports_whether_the_join_took() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        events(&mut rx);
+
+        handle.set_maintenance(true).await.unwrap();
+        assert!(!handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert_eq!(event_names(&mut rx), ["server_maintenance"]);
+
+        handle.set_maintenance(false).await.unwrap();
+        let mut missing_group = profile("u1", "male", "female");
+        missing_group.room_type = "group".to_string();
+        missing_group.group_join_method = Some("join".to_string());
+        missing_group.group_code = Some("NOSUCH".to_string());
+        assert!(!handle.join_chat(conn.clone(), missing_group).await.unwrap());
+        assert!(event_names(&mut rx).contains(&"group_not_found".to_string()));
+
+        assert!(handle.join_chat(conn.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert_eq!(handle.stats().await.unwrap().waiting, 1);
+    }
+
+    #[tokio::test]
+    async fn requests_fail_with_server_unavailable_once_the_actor_is_gone() {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        drop(cmd_rx);
+        let handle = ChatServerHandle { cmd_tx };
+        assert!(matches!(handle.stats().await, Err(ServerUnavailable)));
+        assert!(matches!(handle.join_chat("conn".to_string(), profile("u1", "male", "female")).await, Err(ServerUnavailable)));
+    }
+
+    #[tokio::test]
+    async fn requests_fail_with_server_unavailable_when_the_actor_drops_the_reply() {
+        let (cmd_tx, mut cmd_rx) = mpsc::unbounded_channel::<Command>();
+        tokio::spawn(async move {
+            while let Some(cmd) = cmd_rx.recv().await {
+                drop(cmd);
+            }
+        });
+        let handle = ChatServerHandle { cmd_tx };
+        assert!(matches!(handle.stats().await, Err(ServerUnavailable)));
+    }
+
+    // A connected session that has picked `name`, ready to create or join a group
+    async fn named_member(server: &mut ChatServer, name: &str) -> (ConnId, mpsc::Receiver<Msg>) {
+        let (conn, rx) = connect(server).await;
+        let mut member = user(&conn, "male", "any");
+        member.username = name.to_string();
+        member.chosen_username = name.to_string();
+        member.room_type = "group".to_string();
+        server.users.insert(conn.clone(), member);
+        (conn, rx)
+    }
+
+    // Every roster entry names the connection it belongs to by that user's current display name
+    fn assert_roster_aligned(server: &ChatServer, group_id: &str) {
+        let group = &server.groups[group_id];
+        for (conn, username) in &group.members {
+            assert_eq!(&server.users[conn].username, username);
+            assert_eq!(server.users[conn].group_id.as_deref(), Some(group_id));
+        }
+        assert_eq!(group.usernames().iter().collect::<HashSet<_>>().len(), group.members.len(), "{:?}", group.members);
+    }
+
+    #[tokio::test]
+    async fn group_roster_stays_aligned_through_joins_and_leaves() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "sam").await;
+        let (b, _b_rx) = named_member(&mut server, "sam").await;
+        let (c, _c_rx) = named_member(&mut server, "sam").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        assert_roster_aligned(&server, &code);
+
+        server.leave_group(&b);
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&a, &c]);
+        assert_eq!(server.users[&b].username, "sam");
+        assert_roster_aligned(&server, &code);
+
+        server.join_group_by_code(&b, &code).await;
+        let (d, _d_rx) = named_member(&mut server, "sam").await;
+        server.join_group_by_code(&d, &code).await;
+        server.end_session(&a, LeaveReason::Left).await;
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&c, &b, &d]);
+        assert_roster_aligned(&server, &code);
+    }
+
+    #[tokio::test]
+    async fn admin_passes_to_the_longest_standing_member_when_they_leave() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        let (c, _c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        events(&mut b_rx);
+
+        server.end_session(&a, LeaveReason::Left).await;
+        assert_eq!(server.groups[&code].admin, b);
+        let left: Vec<_> = events(&mut b_rx).into_iter().filter(|(name, _)| name == "member_left").collect();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].1["username"], "ann");
+    }
+
+    #[tokio::test]
+    async fn the_last_member_leaving_deletes_the_group_and_a_second_removal_is_a_no_op() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+
+        assert_eq!(server.remove_user_from_group(&a, LeaveReason::Left), Some(code.clone()));
+        assert_eq!(server.remove_user_from_group(&a, LeaveReason::Left), None);
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&b]);
+
+        server.end_session(&b, LeaveReason::Left).await;
+        assert!(server.groups.is_empty());
+        assert_eq!(server.remove_user_from_group(&b, LeaveReason::Left), None);
+    }
+
+    #[tokio::test]
+    async fn a_stranded_roster_entry_is_removed_even_without_a_recorded_group() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.users.get_mut(&b).unwrap().group_id = None;
+
+        assert_eq!(server.remove_user_from_group(&b, LeaveReason::Left), Some(code.clone()));
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&a]);
+    }
+
+    #[tokio::test]
+    async fn overlong_and_control_character_usernames_are_cleaned_at_join() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        events(&mut rx);
+        let mut overlong = profile("u1", "male", "female");
+        overlong.username = "x".repeat(MAX_USERNAME_LEN + 10);
+        handle.join_chat(conn.clone(), overlong).await.unwrap();
+        let adjusted: Vec<_> = events(&mut rx).into_iter().filter(|(name, _)| name == "username_adjusted").collect();
+        assert_eq!(adjusted.len(), 1);
+        assert_eq!(adjusted[0].1["username"], "x".repeat(MAX_USERNAME_LEN));
+
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        events(&mut rx);
+        let mut control = profile("u2", "male", "female");
+        control.username = "e\u{7}v\u{1b}[31mil\n".to_string();
+        handle.join_chat(conn, control).await.unwrap();
+        let adjusted: Vec<_> = events(&mut rx).into_iter().filter(|(name, _)| name == "username_adjusted").collect();
+        assert_eq!(adjusted[0].1["username"], "ev[31mil");
+
+        assert_eq!(sanitize_username("ann"), "ann");
+        assert_eq!(sanitize_username("\u{0}\u{7f}"), "", "a name of only control characters falls back to the default");
+    }
+
+    #[tokio::test]
+    async fn a_locked_group_turns_joiners_away_until_it_is_unlocked() {
+        let mut server = ChatServer::new();
+        let (admin, mut admin_rx) = named_member(&mut server, "ann").await;
+        let (member, mut member_rx) = named_member(&mut server, "ben").await;
+        let (joiner, mut joiner_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&admin, None, None, false).await;
+        let code = server.users[&admin].group_id.clone().unwrap();
+        server.join_group_by_code(&member, &code).await;
+        events(&mut admin_rx);
+        events(&mut member_rx);
+        events(&mut joiner_rx);
+
+        server.set_group_locked(&member, true);
+        assert_eq!(event_names(&mut member_rx), ["unauthorized"]);
+        assert!(!server.groups[&code].locked);
+
+        server.set_group_locked(&admin, true);
+        assert_eq!(event_names(&mut admin_rx), ["group_locked"]);
+        assert_eq!(event_names(&mut member_rx), ["group_locked"]);
+        server.join_group_by_code(&joiner, &code).await;
+        assert_eq!(event_names(&mut joiner_rx), ["group_locked"]);
+        server.join_random_group(&joiner, None).await;
+        assert_ne!(server.users[&joiner].group_id.as_ref(), Some(&code), "random joins skip a locked group");
+        server.leave_group(&joiner);
+
+        server.set_group_locked(&admin, false);
+        assert_eq!(event_names(&mut member_rx), ["group_unlocked"]);
+        events(&mut joiner_rx);
+        server.join_group_by_code(&joiner, &code).await;
+        assert_eq!(server.users[&joiner].group_id.as_ref(), Some(&code));
+        assert!(server.groups[&code].has_member(&joiner));
+    }
+
+    #[tokio::test]
+    async fn partner_status_follows_the_partner_from_connected_to_away_to_gone() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        let (loner, mut loner_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut a_rx);
+        events(&mut loner_rx);
+
+        let status = |rx: &mut mpsc::Receiver<Msg>| {
+            let sent = events(rx);
+            assert_eq!(sent.len(), 1, "{:?}", sent);
+            assert_eq!(sent[0].0, "partner_status");
+            sent[0].1["status"].as_str().unwrap().to_string()
+        };
+        server.send_partner_status(&a);
+        assert_eq!(status(&mut a_rx), "connected");
+
+        server.hold_session(&b);
+        events(&mut a_rx);
+        server.send_partner_status(&a);
+        assert_eq!(status(&mut a_rx), "away");
+
+        // The partner's socket is gone without the pairing having been torn down yet
+        server.away_users.remove(&b);
+        server.send_partner_status(&a);
+        assert_eq!(status(&mut a_rx), "disconnected");
+
+        server.send_partner_status(&loner);
+        assert_eq!(event_names(&mut loner_rx), ["no_partner"]);
+    }
+
+    #[tokio::test]
+    async fn batched_ice_candidates_reach_the_peer_as_one_intact_event() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut b_rx);
+
+        let candidates = serde_json::json!([
+            { "candidate": "candidate:1 1 udp 2122260223 10.0.0.2 54400 typ host", "sdpMid": "0", "sdpMLineIndex": 0 },
+            { "candidate": "candidate:2 1 udp 1686052607 203.0.113.7 54400 typ srflx", "sdpMid": "0", "sdpMLineIndex": 0 },
+            { "candidate": "candidate:3 1 tcp 1518280447 10.0.0.2 9 typ host tcptype active", "sdpMid": "1", "sdpMLineIndex": 1 },
+        ]);
+        let data = serde_json::json!({ "target_id": b, "candidates": candidates });
+        server.relay_webrtc_event(a.clone(), "webrtc_ice_candidates".to_string(), b.clone(), data, false, None).await;
+
+        let sent = events(&mut b_rx);
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        assert_eq!(sent[0].0, "webrtc_ice_candidates");
+        assert_eq!(sent[0].1["candidates"], candidates);
+    }
+
+    #[tokio::test]
+    async fn near_simultaneous_random_joiners_land_in_the_same_group() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        server.join_random_group(&a, None).await;
+        server.join_random_group(&b, None).await;
+        assert_eq!(server.groups.len(), 1, "the second joiner takes the fresh group instead of opening another");
+        let code = server.users[&a].group_id.clone().unwrap();
+        assert_eq!(server.users[&b].group_id.as_ref(), Some(&code));
+
+        // Once a fresh group has company, the next joiner is placed anywhere open
+        let (c, _c_rx) = named_member(&mut server, "cat").await;
+        server.join_random_group(&c, None).await;
+        assert_eq!(server.groups.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn message_delivered_follows_message_sent_only_when_the_partner_took_it() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        assert!(handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert!(handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap());
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.send_message(a.clone(), encrypted_message(1), false, None, Some("m1".to_string()), Some("r1".to_string())).await.unwrap();
+        let acks = events(&mut a_rx);
+        assert_eq!(acks.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["message_sent", "message_delivered"]);
+        assert_eq!(acks[0].1, serde_json::json!({ "request_id": "r1", "message_id": "m1", "held": 0 }));
+        assert_eq!(acks[1].1, serde_json::json!({ "message_id": "m1", "recipients": 1 }));
+
+        // A partner inside their resume window gets the message later; the sender only hears it was accepted
+        handle.disconnect(b, LeaveReason::Error);
+        handle.send_message(a, encrypted_message(2), false, None, Some("m2".to_string()), None).await.unwrap();
+        let acks: Vec<_> = events(&mut a_rx).into_iter().filter(|(name, _)| name != "partner_away").collect();
+        assert_eq!(acks, [("message_sent".to_string(), serde_json::json!({ "request_id": null, "message_id": "m2", "held": 1 }))]);
+    }
+
+    #[tokio::test]
+    async fn a_message_parked_behind_a_full_queue_is_not_reported_delivered() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        // Room for the welcome and nothing else, and nobody reading it
+        let (tx, _b_rx) = mpsc::channel(1);
+        let (end_tx, _b_end) = oneshot::channel();
+        let (b, _) = handle.connect(tx, end_tx, None, Uuid::new_v4().to_string()).await.unwrap().unwrap();
+        assert!(handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert!(handle.join_chat(b, profile("u2", "female", "male")).await.unwrap());
+        events(&mut a_rx);
+
+        handle.send_message(a, encrypted_message(1), false, None, Some("m1".to_string()), None).await.unwrap();
+        assert_eq!(event_names(&mut a_rx), ["message_sent"]);
+    }
+
+    #[tokio::test]
+    async fn maintenance_refuses_new_joins_but_keeps_existing_chats_relaying() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        let (late, mut late_rx, _late_end) = connect_to(&handle).await;
+        assert!(handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap());
+        assert!(handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap());
+        events(&mut a_rx);
+        events(&mut b_rx);
+        events(&mut late_rx);
+
+        handle.set_maintenance(true).await.unwrap();
+        assert!(!handle.join_chat(late, profile("u3", "female", "male")).await.unwrap());
+        assert_eq!(event_names(&mut late_rx), ["server_maintenance"]);
+
+        handle.send_message(a, encrypted_message(1), false, None, Some("m1".to_string()), None).await.unwrap();
+        assert_eq!(event_names(&mut b_rx), ["receive_message"]);
+        assert!(event_names(&mut a_rx).contains(&"message_sent".to_string()));
+    }
+
+    #[tokio::test]
+    async fn waiters_behind_a_matched_user_move_up_the_queue() {
+        let mut server = ChatServer::new();
+        let mut waiters = Vec::new();
+        for n in 0..3 {
+            let (conn, mut rx) = connect(&mut server).await;
+            server.users.insert(conn.clone(), user(&conn, "male", "female"));
+            server.find_match(&conn).await;
+            let queued = events(&mut rx);
+            assert_eq!(queued[queued.len() - 1].0, "waiting_for_match");
+            assert_eq!(queued[queued.len() - 1].1["position"], n + 1);
+            waiters.push((conn, rx));
+        }
+
+        let (seeker, _seeker_rx) = connect(&mut server).await;
+        server.users.insert(seeker.clone(), user(&seeker, "female", "male"));
+        server.find_match(&seeker).await;
+        let matched = server.users[&seeker].partner_id.clone().expect("a waiter suits the seeker");
+        let matched_index = waiters.iter().position(|(conn, _)| conn == &matched).unwrap();
+
+        let mut position = 0;
+        for (index, (conn, rx)) in waiters.iter_mut().enumerate() {
+            if index == matched_index {
+                continue;
+            }
+            position += 1;
+            assert_eq!(server.waiting_position(conn), Some(position));
+            let updates: Vec<_> = events(rx).into_iter().filter(|(name, _)| name == "waiting_count").collect();
+            if index < matched_index {
+                assert!(updates.is_empty(), "a waiter ahead of the match keeps their place: {:?}", updates);
+            } else {
+                assert_eq!(updates.len(), 1);
+                assert_eq!(updates[0].1["position"], position);
+                assert_eq!(updates[0].1["count"], 2);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn in_range_reply_ids_pass_through_and_out_of_range_ones_are_stripped() {
+        let handle = ChatServer::start();
+        let (a, _a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+        events(&mut b_rx);
+
+        for (n, reply_to, expected) in [(1, 42, Some(42)), (2, -1, None), (3, MAX_REPLY_TO_ID + 1, None), (4, 0, Some(0))] {
+            let mut message = encrypted_message(n);
+            message.reply_to = Some(reply_to);
+            handle.send_message(a.clone(), message, false, None, None, None).await.unwrap();
+            let received = events(&mut b_rx);
+            assert_eq!(received.len(), 1, "{:?}", received);
+            assert_eq!(received[0].1["reply_to"], serde_json::json!(expected), "reply_to {}", reply_to);
+            assert_eq!(received[0].1["message"]["reply_to"], serde_json::json!(expected), "reply_to {}", reply_to);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_creator_chosen_member_cap_is_enforced_and_clamped_to_the_global_max() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, Some(2), None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        assert_eq!(server.groups[&code].max_members, 2);
+        server.join_group_by_code(&b, &code).await;
+        events(&mut c_rx);
+        server.join_group_by_code(&c, &code).await;
+        assert_eq!(event_names(&mut c_rx), ["group_full"]);
+        assert!(!server.groups[&code].has_member(&c));
+
+        server.create_new_group(&c, Some(server.max_group_size + 100), None, false).await;
+        let big = server.users[&c].group_id.clone().unwrap();
+        assert_eq!(server.groups[&big].max_members, server.max_group_size);
+        let (d, _d_rx) = named_member(&mut server, "dan").await;
+        server.create_new_group(&d, Some(0), None, false).await;
+        let tiny = server.users[&d].group_id.clone().unwrap();
+        assert_eq!(server.groups[&tiny].max_members, 2, "a group always has room for a second member");
+    }
+
+    #[tokio::test]
+    async fn a_joiner_gets_one_room_state_snapshot_of_the_configured_group() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, Some(5), Some("Book club".to_string()), false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        events(&mut c_rx);
+
+        server.join_group_by_code(&c, &code).await;
+        let snapshots: Vec<_> = events(&mut c_rx).into_iter().filter(|(name, _)| name == "room_state").collect();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].1, serde_json::json!({
+            "groupCode": code,
+            "members": ["ann", "ben", "cat"],
+            "admin": "ann",
+            "locked": false,
+            "maxMembers": 5,
+            "name": "Book club",
+        }));
+    }
+
+    #[tokio::test]
+    async fn a_waiter_who_disconnects_leaves_no_trace_in_the_pool() {
+        let handle = ChatServer::start();
+        let (gone, _gone_rx, _gone_end) = connect_to(&handle).await;
+        let (other, _other_rx, _other_end) = connect_to(&handle).await;
+        handle.join_chat(gone.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(other.clone(), profile("u2", "female", "female")).await.unwrap();
+        assert_eq!(handle.stats().await.unwrap().waiting, 2);
+
+        handle.disconnect(gone.clone(), LeaveReason::Left);
+        let stats = handle.stats().await.unwrap();
+        assert_eq!((stats.online, stats.waiting), (1, 1));
+
+        // A newcomer who would have suited the departed waiter is queued rather than matched with a ghost
+        let (late, mut late_rx, _late_end) = connect_to(&handle).await;
+        events(&mut late_rx);
+        handle.join_chat(late, profile("u3", "female", "male")).await.unwrap();
+        assert_eq!(event_names(&mut late_rx), ["waiting_for_match"]);
+        assert_eq!(handle.stats().await.unwrap().waiting, 2);
+    }
+
+    #[tokio::test]
+    async fn the_first_event_on_a_connection_is_a_complete_welcome() {
+        let mut server = ChatServer::new();
+        let (conn, mut rx) = connect(&mut server).await;
+        let sent = events(&mut rx);
+        assert_eq!(sent.len(), 1);
+        let (name, welcome) = &sent[0];
+        assert_eq!(name, "welcome");
+        assert_eq!(welcome["conn_id"], conn.as_str());
+        assert_eq!(welcome["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(welcome["capabilities"], serde_json::json!(SERVER_CAPABILITIES));
+        assert_eq!(welcome["resume_token"], server.session_tokens[&conn].as_str());
+        assert_eq!(welcome["resumed"], false);
+        let ice_servers = welcome["ice_servers"].as_array().unwrap();
+        assert_eq!(ice_servers.len(), keys::get_ice_servers().len());
+        assert!(ice_servers.iter().all(|server| server["urls"].is_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn online_count_reflects_connected_sessions_and_is_rate_limited() {
+        let mut server = ChatServer::new();
+        let (asker, mut rx) = connect(&mut server).await;
+        let _others = [connect(&mut server).await, connect(&mut server).await];
+        events(&mut rx);
+        let counts = |rx: &mut mpsc::Receiver<Msg>| -> Vec<Value> {
+            events(rx).into_iter().filter(|(name, _)| name == "online_count").map(|(_, data)| data["count"].clone()).collect()
+        };
+
+        server.send_online_count(&asker);
+        assert_eq!(counts(&mut rx), [3]);
+        let (_late, _late_rx) = connect(&mut server).await;
+        server.send_online_count(&asker);
+        assert!(counts(&mut rx).is_empty(), "a repeat inside the cache window goes unanswered");
+
+        tokio::time::advance(ONLINE_COUNT_CACHE_TTL).await;
+        server.send_online_count(&asker);
+        assert_eq!(counts(&mut rx), [4]);
+    }
+
+    #[tokio::test]
+    async fn my_rooms_reports_the_current_partner_or_group() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        let (loner, mut loner_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut a_rx);
+        events(&mut loner_rx);
+
+        let rooms = |rx: &mut mpsc::Receiver<Msg>| {
+            let sent = events(rx);
+            assert_eq!(sent.len(), 1, "{:?}", sent);
+            assert_eq!(sent[0].0, "my_rooms");
+            sent[0].1.clone()
+        };
+        server.send_my_rooms(&a);
+        assert_eq!(rooms(&mut a_rx), serde_json::json!({
+            "partner": { "username": b, "target_id": b },
+            "groups": [],
+        }));
+        server.send_my_rooms(&loner);
+        assert_eq!(rooms(&mut loner_rx), serde_json::json!({ "partner": null, "groups": [] }));
+
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        let (d, _d_rx) = named_member(&mut server, "dan").await;
+        server.create_new_group(&c, None, None, false).await;
+        let code = server.users[&c].group_id.clone().unwrap();
+        server.join_group_by_code(&d, &code).await;
+        events(&mut c_rx);
+        server.send_my_rooms(&c);
+        assert_eq!(rooms(&mut c_rx), serde_json::json!({
+            "partner": null,
+            "groups": [{ "groupCode": code, "members": ["cat", "dan"] }],
+        }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_member_silent_past_the_soft_threshold_is_reported_stale() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        events(&mut a_rx);
+
+        tokio::time::advance(SOFT_LIVENESS_THRESHOLD + Duration::from_secs(1)).await;
+        server.last_heartbeat.insert(a.clone(), Instant::now());
+        server.broadcast_group_presence();
+        let presence: Vec<_> = events(&mut a_rx).into_iter().filter(|(name, _)| name == "group_presence").collect();
+        assert_eq!(presence.len(), 1);
+        assert_eq!(presence[0].1["groupCode"], code.as_str());
+        assert_eq!(presence[0].1["members"], serde_json::json!([
+            { "username": "ann", "stale": false, "status": "active" },
+            { "username": "ben", "stale": true, "status": "active" },
+        ]));
+
+        server.broadcast_group_presence();
+        assert!(events(&mut a_rx).is_empty(), "broadcasts are spaced by the presence interval");
+    }
+
+    // The pairing code handed to a creator in private_pair_created
+    fn pair_code(rx: &mut mpsc::Receiver<Msg>) -> String {
+        let created: Vec<_> = events(rx).into_iter().filter(|(name, _)| name == "private_pair_created").collect();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].1["expiresIn"], PAIR_CODE_TTL.as_secs());
+        created[0].1["pairCode"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn a_private_pair_code_connects_its_creator_with_one_joiner() {
+        let mut server = ChatServer::new();
+        let (creator, mut creator_rx) = connect(&mut server).await;
+        let (joiner, mut joiner_rx) = connect(&mut server).await;
+        let (late, mut late_rx) = connect(&mut server).await;
+        for conn in [&creator, &joiner, &late] {
+            server.users.insert(conn.clone(), user(conn, "male", "male"));
+        }
+        server.create_private_pair(&creator);
+        let code = pair_code(&mut creator_rx);
+        assert!(!code.is_empty() && code.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        server.join_private_pair(&creator, &code).await;
+        assert!(event_names(&mut creator_rx).contains(&"pair_code_invalid".to_string()), "a creator can't redeem their own code");
+
+        events(&mut joiner_rx);
+        server.join_private_pair(&joiner, &code).await;
+        assert_eq!(server.users[&joiner].partner_id.as_ref(), Some(&creator));
+        assert_eq!(server.users[&creator].partner_id.as_ref(), Some(&joiner));
+        assert!(event_names(&mut joiner_rx).contains(&"chat_started".to_string()));
+
+        events(&mut late_rx);
+        server.join_private_pair(&late, &code).await;
+        assert_eq!(event_names(&mut late_rx), ["pair_code_invalid"], "a code works once");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_unused_private_pair_code_expires() {
+        let mut server = ChatServer::new();
+        let (creator, mut creator_rx) = connect(&mut server).await;
+        let (joiner, mut joiner_rx) = connect(&mut server).await;
+        server.users.insert(joiner.clone(), user(&joiner, "male", "male"));
+        server.create_private_pair(&creator);
+        let code = pair_code(&mut creator_rx);
+
+        tokio::time::advance(PAIR_CODE_TTL).await;
+        server.expire_pair_codes();
+        let expired = events(&mut creator_rx);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "pair_code_expired");
+        assert_eq!(expired[0].1["pairCode"], code.as_str());
+
+        events(&mut joiner_rx);
+        server.join_private_pair(&joiner, &code).await;
+        assert_eq!(event_names(&mut joiner_rx), ["pair_code_invalid"]);
+        assert_eq!(server.users[&joiner].partner_id, None);
+    }
+
+    #[tokio::test]
+    async fn no_waiting_for_match_follows_chat_started() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.users.insert(b.clone(), user(&b, "female", "male"));
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        server.find_match(&a).await;
+        server.find_match(&b).await;
+        // A second search landing just after the match, e.g. a retried join from either side
+        server.find_match(&a).await;
+        server.find_match(&b).await;
+
+        assert_eq!(event_names(&mut a_rx), ["waiting_for_match", "chat_started"]);
+        assert_eq!(event_names(&mut b_rx), ["chat_started"]);
+        assert_eq!(server.waiting_position(&a), None);
+    }
+
+    #[tokio::test]
+    async fn simultaneous_offers_resolve_to_whoever_offered_first() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+        let offer = |target: &ConnId| serde_json::json!({ "target_id": target, "offer": { "type": "offer", "sdp": "v=0" } });
+
+        server.relay_webrtc_event(a.clone(), "webrtc_offer".to_string(), b.clone(), offer(&b), false, None).await;
+        server.relay_webrtc_event(b.clone(), "webrtc_offer".to_string(), a.clone(), offer(&a), false, None).await;
+        assert_eq!(event_names(&mut b_rx), ["webrtc_offer", "call_glare"]);
+        assert!(events(&mut a_rx).is_empty(), "the later offer is dropped, leaving one initiator");
+
+        // Once answered, the offer is settled and either side may renegotiate
+        server.relay_webrtc_event(b.clone(), "webrtc_answer".to_string(), a.clone(), serde_json::json!({ "target_id": a }), false, None).await;
+        assert_eq!(event_names(&mut a_rx), ["webrtc_answer"]);
+        server.relay_webrtc_event(b.clone(), "webrtc_offer".to_string(), a.clone(), offer(&a), false, None).await;
+        assert_eq!(event_names(&mut a_rx), ["webrtc_offer"]);
+        assert!(events(&mut b_rx).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_quick_drop_and_resume_keeps_the_seat_without_join_leave_churn() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        let (c, _c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        let token = server.session_tokens[&b].clone();
+        events(&mut a_rx);
+
+        assert!(server.is_resumable(&b));
+        server.hold_session(&b);
+        tokio::time::advance(RESUME_GRACE_PERIOD / 3).await;
+        let (resumed_id, resumed, mut b_rx) = connect_from(&mut server, "10.0.0.2", Some(token)).await.unwrap();
+        assert_eq!((resumed_id.as_str(), resumed), (b.as_str(), true));
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&a, &b, &c], "same seat in the roster");
+
+        let seen = event_names(&mut a_rx);
+        assert!(seen.contains(&"member_reconnected".to_string()), "{:?}", seen);
+        for churn in ["user_joined_group", "member_left", "group_members_update"] {
+            assert!(!seen.contains(&churn.to_string()), "{} in {:?}", churn, seen);
+        }
+        let snapshots: Vec<_> = events(&mut b_rx).into_iter().filter(|(name, _)| name == "room_state").collect();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].1["members"], serde_json::json!(["ann", "ben", "cat"]));
+    }
+
+    #[tokio::test]
+    async fn the_queue_report_counts_frames_a_slow_session_has_not_read() {
+        let handle = ChatServer::start();
+        let (slow_tx, _slow_rx) = mpsc::channel(4);
+        let (end_tx, _end_rx) = oneshot::channel();
+        let (slow, _) = handle.connect(slow_tx, end_tx, None, "10.0.0.3".to_string()).await.unwrap().unwrap();
+        let (_fast, mut fast_rx, _fast_end) = connect_to(&handle).await;
+        events(&mut fast_rx);
+        // The welcome plus two server_info replies, none of them read
+        handle.server_info(slow.clone()).await.unwrap();
+        handle.server_info(slow.clone()).await.unwrap();
+
+        let report = handle.queue_depths().await.unwrap();
+        assert_eq!(report.sessions.len(), 2);
+        let queue = report.sessions.iter().find(|queue| queue.conn_id == slow).unwrap();
+        assert_eq!((queue.depth, queue.capacity), (3, 4));
+        assert_eq!(report.max_queue_depth, 3);
+        assert_eq!(report.slow_consumers, 1, "3 of 4 is at the slow-consumer threshold");
+    }
+
+    #[tokio::test]
+    async fn every_activity_reaches_the_partner_and_the_group() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut b_rx);
+        for activity in [Activity::Typing, Activity::RecordingAudio, Activity::RecordingVideo, Activity::Uploading] {
+            let name = serde_json::to_value(activity).unwrap();
+            server.relay_activity(&a, activity, true, false, None);
+            let sent = events(&mut b_rx);
+            assert_eq!(sent[0], ("activity_update".to_string(), serde_json::json!({ "activity": name, "active": true })));
+            let legacy: Vec<_> = sent[1..].iter().map(|(event, _)| event.as_str()).collect();
+            assert_eq!(legacy, if activity == Activity::Typing { vec!["typing_started"] } else { vec![] });
+        }
+        server.relay_activity(&a, Activity::Typing, false, false, None);
+        assert_eq!(event_names(&mut b_rx), ["activity_update", "typing_stopped"]);
+
+        let (c, _c_rx) = named_member(&mut server, "cat").await;
+        let (d, mut d_rx) = named_member(&mut server, "dan").await;
+        server.create_new_group(&c, None, None, false).await;
+        let code = server.users[&c].group_id.clone().unwrap();
+        server.join_group_by_code(&d, &code).await;
+        events(&mut d_rx);
+        for activity in [Activity::RecordingAudio, Activity::RecordingVideo, Activity::Uploading] {
+            server.relay_activity(&c, activity, true, true, Some(code.clone()));
+            assert_eq!(events(&mut d_rx), [("activity_update".to_string(), serde_json::json!({
+                "activity": serde_json::to_value(activity).unwrap(),
+                "active": true,
+                "username": "cat",
+            }))]);
+        }
+        // Group typing is folded into the consolidated update on the next housekeeping flush
+        server.typing_deadlines.insert(c.clone(), (true, Some(code.clone()), Instant::now() + server.typing_timeout));
+        server.relay_activity(&c, Activity::Typing, true, true, Some(code.clone()));
+        assert!(events(&mut d_rx).is_empty());
+        server.flush_group_typing();
+        let update = events(&mut d_rx);
+        assert_eq!(update[0].0, "group_typing_update");
+        assert_eq!(update[0].1["usernames"], serde_json::json!(["cat"]));
+    }
+
+    #[tokio::test]
+    async fn group_signaling_goes_to_the_target_when_set_and_to_everyone_else_when_not() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        for rx in [&mut a_rx, &mut b_rx, &mut c_rx] {
+            events(rx);
+        }
+
+        let offer = serde_json::json!({ "target_id": b, "offer": { "type": "offer", "sdp": "v=0" } });
+        server.relay_webrtc_event(a.clone(), "webrtc_offer".to_string(), b.clone(), offer, true, Some(code.clone())).await;
+        assert_eq!(event_names(&mut b_rx), ["webrtc_offer"]);
+        assert!(events(&mut c_rx).is_empty(), "a targeted offer reaches only its target");
+        assert!(events(&mut a_rx).is_empty());
+
+        server.relay_webrtc_event(a.clone(), "webrtc_end_call".to_string(), String::new(), serde_json::json!({}), true, Some(code.clone())).await;
+        assert_eq!(event_names(&mut b_rx), ["webrtc_end_call"]);
+        assert_eq!(event_names(&mut c_rx), ["webrtc_end_call"]);
+        assert!(events(&mut a_rx).is_empty(), "the sender never gets its own broadcast");
+
+        server.relay_webrtc_event(a.clone(), "webrtc_offer".to_string(), a.clone(), serde_json::json!({}), true, Some(code)).await;
+        assert!(events(&mut a_rx).is_empty(), "targeting oneself delivers nothing");
+    }
+
+    // The reason carried by the single partner_disconnected a session was sent
+    fn partner_disconnected_reason(rx: &mut mpsc::Receiver<Msg>) -> Value {
+        let sent: Vec<_> = events(rx).into_iter().filter(|(name, _)| name == "partner_disconnected").collect();
+        assert_eq!(sent.len(), 1, "{:?}", sent);
+        sent[0].1["reason"].clone()
+    }
+
+    #[tokio::test]
+    async fn partner_disconnected_says_why_the_partner_went() {
+        let mut server = ChatServer::new();
+        for (reason, expected) in [
+            (LeaveReason::Left, "left"),
+            (LeaveReason::Timeout, "timeout"),
+            (LeaveReason::Banned, "banned"),
+            (LeaveReason::Error, "error"),
+        ] {
+            let (a, _a_rx) = connect(&mut server).await;
+            let (b, mut b_rx) = connect(&mut server).await;
+            pair(&mut server, &a, &b).await;
+            server.end_session(&a, reason).await;
+            assert_eq!(partner_disconnected_reason(&mut b_rx), expected);
+        }
+
+        // Server-side ends hand their own reason on
+        for (end, expected) in [(SessionEnd::RateLimitBan, "banned"), (SessionEnd::SlowConsumer, "error")] {
+            let (a, _a_rx) = connect(&mut server).await;
+            let (b, mut b_rx) = connect(&mut server).await;
+            pair(&mut server, &a, &b).await;
+            server.close_session(&a, end).await;
+            assert_eq!(partner_disconnected_reason(&mut b_rx), expected);
+        }
+
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.handle_disconnect(&a, LeaveReason::Left).await;
+        assert_eq!(partner_disconnected_reason(&mut b_rx), "left", "disconnect_chat keeps the socket but ends the chat");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_partner_lost_while_away_is_explained_on_resume() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        let token = server.session_tokens[&b].clone();
+        server.hold_session(&b);
+        server.end_session(&a, LeaveReason::Timeout).await;
+
+        let (_, resumed, mut b_rx) = connect_from(&mut server, "10.0.0.1", Some(token)).await.unwrap();
+        assert!(resumed);
+        assert_eq!(partner_disconnected_reason(&mut b_rx), "timeout");
+        assert_eq!(server.users[&b].partner_left_reason, None);
+
+        // The away window itself running out reads as a timeout to the partner left behind
+        let (c, mut c_rx) = connect(&mut server).await;
+        pair(&mut server, &b, &c).await;
+        events(&mut c_rx);
+        server.hold_session(&b);
+        tokio::time::advance(RESUME_GRACE_PERIOD + Duration::from_secs(1)).await;
+        server.expire_away_sessions().await;
+        assert_eq!(partner_disconnected_reason(&mut c_rx), "timeout");
+    }
+
+    #[tokio::test]
+    async fn file_transfers_beyond_the_cap_are_refused_until_one_ends() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+        let max = keys::get_max_file_transfers();
+
+        for n in 0..max {
+            handle.file_sending_start(a.clone(), format!("file-{}", n), false, None).await.unwrap();
+        }
+        assert_eq!(event_names(&mut b_rx).len(), max);
+        handle.file_sending_start(a.clone(), "one-too-many".to_string(), false, None).await.unwrap();
+        let refused = events(&mut a_rx);
+        assert_eq!(refused, [("too_many_transfers".to_string(), serde_json::json!({ "fileId": "one-too-many", "max": max }))]);
+        assert!(events(&mut b_rx).is_empty());
+
+        // Restarting a transfer already in flight doesn't take a second slot
+        handle.file_sending_start(a.clone(), "file-0".to_string(), false, None).await.unwrap();
+        assert!(events(&mut a_rx).is_empty());
+
+        handle.file_sending_end(a.clone(), "file-0".to_string(), false, None).await.unwrap();
+        handle.file_sending_start(a.clone(), "one-too-many".to_string(), false, None).await.unwrap();
+        assert!(events(&mut a_rx).is_empty());
+        assert_eq!(event_names(&mut b_rx), ["file_sending_started", "file_sending_ended", "file_sending_started"]);
+    }
+
+    #[tokio::test]
+    async fn server_info_reflects_the_active_configuration() {
+        let mut server = ChatServer::new();
+        server.max_group_size = 4;
+        server.max_message_size = 1024;
+        server.max_file_transfers = 2;
+        server.match_timeout = Duration::from_secs(45);
+        server.maintenance = true;
+        let (conn, mut rx) = connect(&mut server).await;
+        events(&mut rx);
+
+        server.send_server_info(&conn);
+        assert_eq!(events(&mut rx), [("server_info".to_string(), serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol_versions": PROTOCOL_VERSIONS,
+            "max_payload_size": MAX_PAYLOAD_SIZE,
+            "max_message_size": 1024,
+            "max_group_size": 4,
+            "max_username_length": MAX_USERNAME_LEN,
+            "max_file_transfers": 2,
+            "match_timeout_secs": 45,
+            "resume_grace_secs": RESUME_GRACE_PERIOD.as_secs(),
+            "group_chat_enabled": true,
+            "maintenance": true,
+        }))]);
+    }
+
+    #[tokio::test]
+    async fn skipping_tells_the_partner_and_requeues_both_without_rematching_them() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        server.skip_partner(&a).await;
+        assert_eq!(event_names(&mut b_rx), ["partner_skipped", "waiting_for_match"]);
+        assert_eq!(event_names(&mut a_rx), ["waiting_for_match"]);
+        for conn in [&a, &b] {
+            assert_eq!(server.users[conn].partner_id, None);
+            assert!(server.waiting_position(conn).is_some(), "{} is queued again", conn);
+        }
+
+        // Each of them is free to meet someone new
+        let (c, _c_rx) = connect(&mut server).await;
+        server.users.insert(c.clone(), user(&c, "female", "male"));
+        server.find_match(&c).await;
+        assert_eq!(server.users[&c].partner_id.as_ref(), Some(&a));
+        let (d, _d_rx) = connect(&mut server).await;
+        server.users.insert(d.clone(), user(&d, "male", "female"));
+        server.find_match(&d).await;
+        assert_eq!(server.users[&d].partner_id.as_ref(), Some(&b));
+
+        server.skip_partner(&b).await;
+        server.skip_partner(&b).await;
+        assert_eq!(event_names(&mut b_rx).last().map(String::as_str), Some("no_partner"));
+    }
+
+    #[tokio::test]
+    async fn a_random_join_never_picks_a_group_the_user_already_sits_in() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        server.create_new_group(&a, None, None, false).await;
+        let own = server.users[&a].group_id.clone().unwrap();
+
+        server.join_random_group(&a, None).await;
+        assert_eq!(server.groups[&own].members.len(), 1, "not seated twice in the same group");
+        let fresh = server.users[&a].group_id.clone().unwrap();
+        assert_ne!(fresh, own, "with no other group open, a new one is created");
+        assert_eq!(server.groups.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn leaving_a_group_keeps_the_session_ready_for_another_room() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        server.leave_group(&b);
+        assert!(server.sessions.contains_key(&b), "the socket stays registered");
+        assert_eq!(server.users[&b].group_id, None);
+        assert_eq!(events(&mut b_rx), [("left_group".to_string(), serde_json::json!({ "groupCode": code }))]);
+        let told: Vec<_> = events(&mut a_rx).into_iter().filter(|(name, _)| name != "group_peers_update").collect();
+        assert_eq!(told, [
+            ("user_left_group".to_string(), serde_json::json!("ben")),
+            ("member_left".to_string(), serde_json::json!({ "username": "ben", "reason": "left" })),
+            ("group_members_update".to_string(), serde_json::json!(["ann"])),
+        ]);
+
+        server.leave_group(&b);
+        assert_eq!(event_names(&mut b_rx), ["not_in_group"]);
+        server.join_random_group(&b, None).await;
+        assert_eq!(server.users[&b].group_id.as_ref(), Some(&code), "free to join straight away");
+
+        server.leave_group(&a);
+        server.leave_group(&b);
+        assert!(server.groups.is_empty(), "the last one out removes the group");
+    }
+
+    #[tokio::test]
+    async fn only_the_group_admin_can_kick_a_member() {
+        let mut server = ChatServer::new();
+        let (admin, mut admin_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&admin, None, None, false).await;
+        let code = server.users[&admin].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        events(&mut admin_rx);
+        events(&mut b_rx);
+        events(&mut c_rx);
+
+        server.kick_member(&b, "cat");
+        assert_eq!(events(&mut b_rx), [("unauthorized".to_string(), serde_json::json!({ "action": "kick_member" }))]);
+        assert!(server.groups[&code].has_member(&c), "a non-admin kick changes nothing");
+        assert!(events(&mut c_rx).is_empty());
+
+        server.kick_member(&admin, "cat");
+        assert_eq!(events(&mut c_rx).first(), Some(&("kicked_from_group".to_string(), serde_json::json!({ "groupCode": code }))));
+        assert!(!server.groups[&code].has_member(&c));
+        assert_eq!(server.users[&c].group_id, None);
+        for rx in [&mut admin_rx, &mut b_rx] {
+            let told: Vec<_> = events(rx).into_iter().filter(|(name, _)| name != "group_peers_update").collect();
+            assert!(told.contains(&("member_left".to_string(), serde_json::json!({ "username": "cat", "reason": "kicked" }))));
+            assert!(told.contains(&("group_members_update".to_string(), serde_json::json!(["ann", "ben"]))));
+        }
+    }
+
+    #[tokio::test]
+    async fn groups_list_describes_each_joinable_group() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "ben").await;
+        let (c, mut c_rx) = named_member(&mut server, "cat").await;
+        server.create_new_group(&a, Some(4), Some("rustaceans".to_string()), false).await;
+        let open = server.users[&a].group_id.clone().unwrap();
+        server.create_new_group(&b, None, None, false).await;
+        let locked = server.users[&b].group_id.clone().unwrap();
+        server.groups.get_mut(&locked).unwrap().locked = true;
+        events(&mut c_rx);
+
+        server.send_groups_list(&c);
+        assert_eq!(events(&mut c_rx), [("groups_list".to_string(), serde_json::json!({
+            "groups": [{ "groupCode": open, "memberCount": 1, "maxMembers": 4, "name": "rustaceans" }],
+        }))]);
+    }
+
+    #[tokio::test]
+    async fn a_group_keeps_the_name_it_was_created_with_until_the_admin_renames_it() {
+        let handle = ChatServer::start();
+        let (admin, mut admin_rx, _admin_end) = connect_to(&handle).await;
+        let (member, mut member_rx, _member_end) = connect_to(&handle).await;
+        let mut create = profile("ua", "male", "any");
+        create.room_type = "group".to_string();
+        create.group_join_method = Some("create".to_string());
+        create.group_name = Some("  book club ".to_string());
+        handle.join_chat(admin.clone(), create).await.unwrap();
+        let info = events(&mut admin_rx).into_iter().find(|(name, _)| name == "group_info").expect("group_info on creation").1;
+        assert_eq!(info["name"], "book club");
+        let code = info["groupCode"].as_str().unwrap().to_string();
+
+        let mut join = profile("ub", "female", "any");
+        join.room_type = "group".to_string();
+        join.group_join_method = Some("join".to_string());
+        join.group_code = Some(code.clone());
+        handle.join_chat(member.clone(), join).await.unwrap();
+        events(&mut admin_rx);
+        events(&mut member_rx);
+
+        handle.rename_group(member.clone(), "hijacked".to_string()).await.unwrap();
+        assert_eq!(events(&mut member_rx), [("unauthorized".to_string(), serde_json::json!({ "action": "rename_group" }))]);
+        handle.rename_group(admin.clone(), " ".to_string()).await.unwrap();
+        assert_eq!(events(&mut admin_rx), [("invalid_group_name".to_string(), serde_json::json!({ "reason": "empty" }))]);
+        handle.rename_group(admin.clone(), "x".repeat(MAX_GROUP_NAME_LEN + 1)).await.unwrap();
+        assert_eq!(events(&mut admin_rx), [("invalid_group_name".to_string(), serde_json::json!({ "reason": "too_long" }))]);
+
+        handle.rename_group(admin.clone(), "film club".to_string()).await.unwrap();
+        let renamed = ("group_info".to_string(), serde_json::json!({ "groupCode": code, "name": "film club", "history": false }));
+        for rx in [&mut admin_rx, &mut member_rx] {
+            assert_eq!(events(rx), std::slice::from_ref(&renamed));
+        }
     }
 
-    // Joins a user to a group chat
-    pub async fn join_group_chat(&self, _conn_id: String, _group_code: String, _username: String) -> bool {
-        // ... existing code ...
-        true
+    #[tokio::test]
+    async fn a_read_receipt_from_the_partner_reaches_the_sender() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("ua", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("ub", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.message_read(b.clone(), "m1".to_string(), false, None).await.unwrap();
+        assert_eq!(events(&mut a_rx), [("message_read".to_string(), serde_json::json!({ "message_id": "m1" }))]);
+        assert!(events(&mut b_rx).is_empty(), "the reader isn't echoed their own receipt");
     }
 
-    // Relay WebRTC signaling events between clients
-    pub async fn relay_webrtc_event(
-        &self,
-        sender_id: String,
-        event_type: String,
-        target_id: String,
-        data: serde_json::Value,
-        is_group_chat: bool,
-        group_code: Option<String>,
-    ) {
-        // Log full details at the start
-        log::info!("relay_webrtc_event: from={}, event={}, to={}, is_group={}, group_code={:?}",
-            sender_id, event_type, target_id, is_group_chat, group_code);
-            
-        // Find the sender's user for validation
-        if !self.users.contains_key(&sender_id) {
-            log::error!("WebRTC relay failed: Sender not found {}", sender_id);
-            return;
+    #[tokio::test]
+    async fn a_read_receipt_in_a_group_names_the_reader() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        server.relay_read_receipt(&b, "m1", true, Some(code));
+        assert_eq!(events(&mut a_rx), [("message_read".to_string(), serde_json::json!({ "message_id": "m1", "username": "ben" }))]);
+        assert!(events(&mut b_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn an_over_limit_payload_is_refused_instead_of_relayed() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("ua", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("ub", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        let mut message = encrypted_message(1);
+        message.encrypted = "A".repeat(keys::get_max_message_size() + 1);
+        handle.send_message(a.clone(), message, false, None, Some("m1".to_string()), Some("r1".to_string())).await.unwrap();
+        assert_eq!(events(&mut a_rx), [("message_too_large".to_string(), serde_json::json!({
+            "request_id": "r1",
+            "message_id": "m1",
+            "max_size": keys::get_max_message_size(),
+        }))]);
+        assert!(events(&mut b_rx).is_empty(), "nothing reaches the partner");
+    }
+
+    #[tokio::test]
+    async fn only_the_two_sides_of_a_ring_can_answer_it() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        let (c, mut c_rx) = connect(&mut server).await;
+        let (d, mut d_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        pair(&mut server, &c, &d).await;
+        server.relay_webrtc_event(a.clone(), "call_ring".to_string(), b.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+        events(&mut c_rx);
+        events(&mut d_rx);
+
+        server.relay_webrtc_event(c.clone(), "call_accept".to_string(), d.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        assert_eq!(events(&mut c_rx), [("webrtc_error".to_string(), serde_json::json!({
+            "reason": "not_in_call",
+            "call_id": "call-1",
+            "event": "call_accept",
+        }))]);
+        assert!(events(&mut d_rx).is_empty());
+        assert!(server.ringing_calls.contains_key(&(a.clone(), "call-1".to_string())), "the ring is still live");
+
+        server.relay_webrtc_event(b.clone(), "call_accept".to_string(), a.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        assert_eq!(event_names(&mut a_rx), ["call_accept"]);
+        assert!(server.ringing_calls.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_second_ring_does_not_replace_the_first() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.relay_webrtc_event(a.clone(), "call_ring".to_string(), b.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        let first = (a.clone(), "call-1".to_string());
+        let armed = server.ringing_calls[&first].clone();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        server.relay_webrtc_event(a.clone(), "call_ring".to_string(), b.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        assert_eq!(event_names(&mut a_rx), ["webrtc_error"]);
+        assert!(events(&mut b_rx).is_empty(), "the callee isn't rung twice");
+        assert_eq!(server.ringing_calls[&first], armed, "the original ring keeps its deadline");
+
+        // The callee reusing the id rings separately rather than taking over the caller's entry
+        server.relay_webrtc_event(b.clone(), "call_ring".to_string(), a.clone(), serde_json::json!({ "call_id": "call-1" }), false, None).await;
+        assert_eq!(server.ringing_calls[&first], armed);
+        assert_eq!(server.ringing_calls.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn a_blocked_user_is_never_matched_even_when_otherwise_compatible() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.block_user(&a, Some(format!("user-{}", b)));
+        assert_eq!(event_names(&mut a_rx), ["welcome", "user_blocked"]);
+
+        enqueue(&mut server, user(&b, "female", "male"));
+        server.find_match(&a).await;
+        assert_eq!(server.users[&a].partner_id, None);
+        assert_eq!(event_names(&mut a_rx), ["waiting_for_match"]);
+
+        // Nor the other way round, when the blocked user is the one looking
+        server.remove_from_waiting(&[&b]);
+        server.find_match(&b).await;
+        assert_eq!(server.users[&b].partner_id, None);
+        assert!(!event_names(&mut b_rx).contains(&"chat_started".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn block_lists_are_bounded_and_forgotten_once_idle() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        events(&mut a_rx);
+
+        server.block_user(&a, Some("x".repeat(MAX_BLOCKED_ID_LEN + 1)));
+        assert_eq!(event_names(&mut a_rx), ["invalid_user_id"]);
+        assert!(server.user_blocks.is_empty());
+
+        for n in 0..=MAX_BLOCKS_PER_USER {
+            server.block_user(&a, Some(format!("blocked-{}", n)));
         }
-        
-        // Debug the data structure
-        log::debug!("WebRTC event data: {}", 
-                   serde_json::to_string_pretty(&data).unwrap_or_else(|_| "Invalid JSON".to_string()));
-        
-        // Prepare the event to send
-        let event = ServerEvent {
-            event: event_type.clone(),
-            data: data.clone(),
-        };
-        
-        // Debug the final event structure
-        log::debug!("WebRTC formatted event: {}", 
-                   serde_json::to_string_pretty(&event).unwrap_or_else(|_| "Invalid JSON".to_string()));
-        
-        let event_json = match serde_json::to_string(&event) {
-            Ok(json) => json,
-            Err(e) => {
-                log::error!("Failed to serialize WebRTC event: {}", e);
-                return;
-            }
-        };
-        
-        // For group chat, relay to all members of the group
-        if is_group_chat {
-            if let Some(code) = group_code {
-                if let Some(group) = self.groups.get(&code) {
-                    log::info!("Relaying WebRTC {} to {} group members in group {}",
-                        event_type, group.members.len(), code);
-                    
-                    let mut relay_count = 0;
-                    for member_id in &group.members {
-                        if member_id != &sender_id {
-                            if let Some(tx) = self.sessions.get(member_id) {
-                                if let Err(e) = tx.send(event_json.clone()) {
-                                    log::error!("Failed to relay WebRTC event to {}: {}", member_id, e);
-                                } else {
-                                    relay_count += 1;
-                                }
-                            }
-                        }
-                    }
-                    log::info!("Successfully relayed WebRTC {} to {}/{} members in group {}",
-                        event_type, relay_count, group.members.len() - 1, code);
-                } else {
-                    log::error!("WebRTC relay failed: Group {} not found", code);
-                }
-            } else {
-                log::error!("WebRTC relay failed: No group code provided for group chat");
-            }
-        } else {
-            // For private chat, relay directly to target
-            if let Some(tx) = self.sessions.get(&target_id) {
-                match tx.send(event_json) {
-                    Ok(_) => {
-                        log::info!("Successfully relayed WebRTC {} from {} to {}", 
-                            event_type, sender_id, target_id);
-                    },
-                    Err(e) => {
-                        log::error!("Failed to relay WebRTC event to {}: {}", target_id, e);
-                    }
-                }
-            } else {
-                log::error!("Failed to relay WebRTC event: Target session not found {}", target_id);
-            }
+        let list = &server.user_blocks[&format!("user-{}", a)];
+        assert_eq!(list.blocked.len(), MAX_BLOCKS_PER_USER);
+        assert_eq!(list.blocked.front().map(String::as_str), Some("blocked-1"), "the oldest block made room");
+
+        tokio::time::advance(BLOCK_LIST_TTL + Duration::from_secs(1)).await;
+        server.expire_block_lists();
+        assert!(server.user_blocks.is_empty());
+    }
+
+    // A fresh 1:1 user from `ip` who reports `target` as their partner
+    async fn report_from(server: &mut ChatServer, target: &ConnId, ip: &str) {
+        let (reporter, _, mut rx) = connect_from(server, ip, None).await.unwrap();
+        let mut reporter_user = user(&reporter, "male", "female");
+        reporter_user.partner_id = Some(target.clone());
+        server.users.insert(reporter.clone(), reporter_user);
+        server.report_user(&reporter, None, Some("spam"));
+        assert!(event_names(&mut rx).contains(&"report_received".to_string()));
+    }
+
+    #[tokio::test]
+    async fn reports_count_once_per_reporter_ip_and_block_past_the_threshold() {
+        let mut server = ChatServer::new();
+        server.abuse_report_threshold = 2;
+        let (target, _) = connect(&mut server).await;
+        server.users.insert(target.clone(), user(&target, "female", "male"));
+        let target_user_id = format!("user-{}", target);
+
+        report_from(&mut server, &target, "198.51.100.1").await;
+        assert_eq!(server.abuse_reports[&target_user_id].reporter_ips.len(), 1);
+        // The same client back under a fresh user id
+        report_from(&mut server, &target, "198.51.100.1").await;
+        assert_eq!(server.abuse_reports[&target_user_id].reporter_ips.len(), 1);
+        assert!(!server.blocked_user_ids.contains(&target_user_id));
+
+        report_from(&mut server, &target, "198.51.100.2").await;
+        assert_eq!(server.abuse_reports[&target_user_id].reporter_ips.len(), 2);
+        assert!(server.blocked_user_ids.contains(&target_user_id));
+        let seeker = user("seeker", "male", "female");
+        assert!(!server.can_pair(&seeker, &server.users[&target]), "a blocked user is out of the pool");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn report_tallies_below_the_threshold_fade_out() {
+        let mut server = ChatServer::new();
+        let (target, _) = connect(&mut server).await;
+        let (reporter, _) = connect(&mut server).await;
+        server.users.insert(target.clone(), user(&target, "female", "male"));
+        let mut reporter_user = user(&reporter, "male", "female");
+        reporter_user.partner_id = Some(target.clone());
+        server.users.insert(reporter.clone(), reporter_user);
+        server.report_user(&reporter, None, None);
+        assert_eq!(server.abuse_reports.len(), 1);
+
+        tokio::time::advance(ABUSE_REPORT_TTL + Duration::from_secs(1)).await;
+        server.expire_abuse_reports();
+        assert!(server.abuse_reports.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_ordered_pick_takes_the_longest_waiter_across_every_queue() {
+        let mut server = ChatServer::new();
+        let seeker = user("seeker", "male", "any");
+        // One waiter in each compatible queue, the oldest somewhere in the middle
+        for (conn, gender, preference) in [("f-male", "female", "male"), ("m-any", "male", "any"), ("oldest", "female", "any"), ("m-male", "male", "male")] {
+            enqueue(&mut server, user(conn, gender, preference));
+            tokio::time::advance(Duration::from_secs(1)).await;
         }
+        server.waiting_since.insert("oldest".to_string(), Instant::now() - Duration::from_secs(60));
+        assert_eq!(server.find_waiting_partner(&seeker, false).as_deref(), Some("oldest"));
+
+        server.remove_from_waiting(&[&"oldest".to_string()]);
+        assert_eq!(server.find_waiting_partner(&seeker, false).as_deref(), Some("f-male"));
     }
 
-    // Disconnect a user from the chat server
-    pub async fn disconnect(&self, _conn_id: &str) {
-        // ... existing code ...
+    #[tokio::test]
+    async fn the_retry_timer_is_armed_only_while_a_frame_is_parked() {
+        let mut server = ChatServer::new();
+        let (tx, mut rx) = mpsc::channel(1);
+        let (end_tx, _) = oneshot::channel();
+        let conn = "slow".to_string();
+        server.sessions.insert(conn.clone(), SessionTx::new(tx, end_tx, server.parked_frames.clone()));
+        assert_eq!(server.parked_frames.load(Ordering::Relaxed), 0);
+
+        let session = &server.sessions[&conn];
+        session.send("first".to_string().into()).unwrap();
+        session.send_critical("message".to_string().into()).unwrap();
+        assert_eq!(server.parked_frames.load(Ordering::Relaxed), 1);
+
+        rx.try_recv().unwrap();
+        server.retry_critical_sends();
+        assert_eq!(text(rx.try_recv().unwrap()), "message");
+        assert_eq!(server.parked_frames.load(Ordering::Relaxed), 0);
+
+        // A session that leaves with frames still parked doesn't keep the timer running
+        let session = &server.sessions[&conn];
+        session.send("second".to_string().into()).unwrap();
+        session.send_critical("lost".to_string().into()).unwrap();
+        server.sessions.remove(&conn);
+        server.retry_critical_sends();
+        assert_eq!(server.parked_frames.load(Ordering::Relaxed), 0);
     }
-}
 
-// Handle and command sender for chat server
-#[derive(Debug, Clone)]
-pub struct ChatServerHandle {
-    cmd_tx: mpsc::UnboundedSender<Command>,
-}
+    fn group_profile(user_id: &str, join_method: &str, group_code: Option<&str>) -> UserProfile {
+        serde_json::from_value(serde_json::json!({
+            "user_id": user_id,
+            "username": user_id,
+            "preference": "any",
+            "gender": "male",
+            "room_type": "group",
+            "group_join_method": join_method,
+            "group_code": group_code,
+        }))
+        .unwrap()
+    }
 
-impl ChatServerHandle {
-    // Register client message sender and obtain connection ID
-    pub async fn connect(&self, conn_tx: mpsc::UnboundedSender<Msg>) -> ConnId {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::Connect { conn_tx, res_tx })
-            .unwrap();
-        res_rx.await.unwrap()
+    // Create a group through a running server, returning the code its creator was handed
+    async fn create_group(handle: &ChatServerHandle, conn: &ConnId, rx: &mut mpsc::Receiver<Msg>, user_id: &str) -> String {
+        handle.join_chat(conn.clone(), group_profile(user_id, "create", None)).await.unwrap();
+        let joined = events(rx).into_iter().find(|(name, _)| name == "group_joined").expect("the creator is told the code");
+        joined.1["groupCode"].as_str().unwrap().to_string()
     }
 
-    // Unregister message sender and broadcast disconnection message to current room
-    pub fn disconnect(&self, conn: ConnId) {
-        self.cmd_tx.send(Command::Disconnect { conn }).unwrap();
+    #[tokio::test]
+    async fn an_explicit_presence_change_reaches_the_partner_and_the_rest_of_the_group() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.update_presence(a.clone(), PresenceStatus::Away).await.unwrap();
+        assert_eq!(events(&mut b_rx), [("presence_update".to_string(), serde_json::json!({ "username": "u1", "status": "away" }))]);
+        assert!(events(&mut a_rx).is_empty(), "the sender isn't told about its own change");
+
+        let (c, mut c_rx, _c_end) = connect_to(&handle).await;
+        let (d, mut d_rx, _d_end) = connect_to(&handle).await;
+        let code = create_group(&handle, &c, &mut c_rx, "cat").await;
+        handle.join_chat(d.clone(), group_profile("dan", "join", Some(&code))).await.unwrap();
+        events(&mut c_rx);
+        events(&mut d_rx);
+
+        handle.update_presence(d, PresenceStatus::Away).await.unwrap();
+        assert_eq!(events(&mut c_rx), [("presence_update".to_string(), serde_json::json!({ "username": "dan", "status": "away" }))]);
+        assert!(events(&mut d_rx).is_empty());
+        assert!(events(&mut a_rx).is_empty() && events(&mut b_rx).is_empty(), "nothing leaks outside the group");
     }
 
-    // Join chat with a user profile
-    pub async fn join_chat(&self, conn: ConnId, profile: UserProfile) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::JoinChat { conn, profile, res_tx })
-            .unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn two_members_typing_together_produce_one_update_naming_both() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        let (c, mut c_rx, _c_end) = connect_to(&handle).await;
+        let code = create_group(&handle, &a, &mut a_rx, "ann").await;
+        handle.join_chat(b.clone(), group_profile("ben", "join", Some(&code))).await.unwrap();
+        handle.join_chat(c, group_profile("cat", "join", Some(&code))).await.unwrap();
+        for rx in [&mut a_rx, &mut b_rx, &mut c_rx] {
+            events(rx);
+        }
+
+        handle.activity_update(b, Activity::Typing, true, true, Some(code.clone())).await.unwrap();
+        handle.activity_update(a, Activity::Typing, true, true, Some(code.clone())).await.unwrap();
+        tokio::time::sleep(HOUSEKEEPING_INTERVAL).await;
+        handle.stats().await.unwrap();
+
+        let updates: Vec<_> = events(&mut c_rx).into_iter().filter(|(name, _)| name == "group_typing_update").collect();
+        assert_eq!(updates, [("group_typing_update".to_string(), serde_json::json!({
+            "groupCode": code,
+            "usernames": ["ann", "ben"],
+        }))]);
     }
 
-    // Send a message
-    pub async fn send_message(&self, conn: ConnId, message: EncryptedMessage, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::SendMessage { conn, message, is_group_chat, group_code, res_tx })
-            .unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test(start_paused = true)]
+    async fn a_searcher_who_changes_preference_is_requeued_without_losing_their_place() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.find_match(&a).await;
+        let since = server.waiting_since[&a];
+        tokio::time::advance(Duration::from_secs(5)).await;
+
+        server.update_preference(&a, "any", None).await;
+        assert_eq!(server.users[&a].preference, "any");
+        let old = WaitKey { gender: "male".to_string(), preference: "female".to_string() };
+        let new = WaitKey { gender: "male".to_string(), preference: "any".to_string() };
+        assert!(server.waiting_users.get(&old).is_none_or(Vec::is_empty));
+        assert_eq!(server.waiting_users[&new], std::slice::from_ref(&a));
+        assert_eq!(server.waiting_since[&a], since, "the wait keeps counting from the first join");
+
+        // Someone only the new preference accepts is now a match
+        let (b, _b_rx) = connect(&mut server).await;
+        server.users.insert(b.clone(), user(&b, "male", "male"));
+        server.find_match(&b).await;
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
+
+        events(&mut a_rx);
+        server.update_preference(&a, "female", None).await;
+        assert_eq!(event_names(&mut a_rx), ["preference_locked"]);
+        assert_eq!(server.users[&a].preference, "any", "a matched user's criteria stay as they were");
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
     }
 
-    // Start typing
-    pub async fn typing_start(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::TypingStart { conn, is_group_chat, group_code, res_tx })
-            .unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn changing_preference_drops_a_pair_code_held_open() {
+        let mut server = ChatServer::new();
+        let (creator, mut creator_rx) = connect(&mut server).await;
+        server.users.insert(creator.clone(), user(&creator, "male", "female"));
+        server.create_private_pair(&creator);
+        let code = pair_code(&mut creator_rx);
+
+        server.update_preference(&creator, "any", None).await;
+        assert!(!server.pair_codes.contains_key(&code));
+        assert_eq!(server.waiting_position(&creator), Some(1));
     }
 
-    // Stop typing
-    pub async fn typing_stop(&self, conn: ConnId, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::TypingStop { conn, is_group_chat, group_code, res_tx })
-            .unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn a_cancelled_search_leaves_the_user_in_no_waiting_bucket() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.find_match(&a).await;
+        // A stale entry under another bucket must go as well
+        let stale = WaitKey { gender: "male".to_string(), preference: "any".to_string() };
+        server.waiting_users.entry(stale).or_default().push(a.clone());
+        events(&mut a_rx);
+
+        server.cancel_search(&a);
+        assert_eq!(event_names(&mut a_rx), ["search_cancelled"]);
+        assert!(server.waiting_users.values().all(|list| !list.contains(&a)));
+        assert!(!server.waiting_since.contains_key(&a));
+        assert_eq!(server.waiting_position(&a), None);
+
+        server.cancel_search(&a);
+        assert_eq!(event_names(&mut a_rx), ["not_waiting"]);
     }
 
-    // New method for file sending start
-    pub async fn file_sending_start(&self, conn: ConnId, file_id: String, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx.send(Command::FileSendingStart {
-            conn,
-            file_id,
-            is_group_chat,
-            group_code,
-            res_tx,
-        }).unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn group_joined_carries_the_joiner_s_own_id_and_goes_only_to_them() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, mut b_rx) = named_member(&mut server, "ben").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        events(&mut a_rx);
+
+        server.join_group_by_code(&b, &code).await;
+        let joined: Vec<_> = events(&mut b_rx).into_iter().filter(|(name, _)| name == "group_joined").collect();
+        assert_eq!(joined, [("group_joined".to_string(), serde_json::json!({
+            "groupCode": code,
+            "self_id": b,
+            "username": "ben",
+            "peers": [
+                { "username": "ann", "peer_id": a },
+                { "username": "ben", "peer_id": b },
+            ],
+        }))]);
+        assert!(!event_names(&mut a_rx).contains(&"group_joined".to_string()), "existing members hear user_joined_group instead");
     }
 
-    // New method for file sending end
-    pub async fn file_sending_end(&self, conn: ConnId, file_id: String, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx.send(Command::FileSendingEnd {
-            conn,
-            file_id,
-            is_group_chat,
-            group_code,
-            res_tx,
-        }).unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn of_two_members_sharing_a_name_the_one_who_leaves_is_the_one_removed() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "sam").await;
+        let (c, _c_rx) = named_member(&mut server, "sam").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        server.join_group_by_code(&c, &code).await;
+        assert_eq!(server.groups[&code].usernames(), ["ann", "sam", "sam (2)"]);
+        events(&mut a_rx);
+
+        server.leave_group(&c);
+        assert_eq!(server.groups[&code].member_ids().collect::<Vec<_>>(), [&a, &b]);
+        assert_eq!(server.groups[&code].usernames(), ["ann", "sam"]);
+        let left: Vec<_> = events(&mut a_rx).into_iter().filter(|(name, _)| name == "member_left").collect();
+        assert_eq!(left[0].1["username"], "sam (2)");
+        assert_roster_aligned(&server, &code);
     }
 
-    // New method for deleting a message
-    pub async fn delete_message(&self, conn: ConnId, message_id: String, is_group_chat: bool, group_code: Option<String>) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx.send(Command::DeleteMessage {
-            conn,
-            message_id,
-            is_group_chat,
-            group_code,
-            res_tx,
-        }).unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn an_empty_username_joins_under_a_default_name() {
+        let handle = ChatServer::start();
+        let (conn, mut rx, _end) = connect_to(&handle).await;
+        events(&mut rx);
+        let mut blank = profile("abcdefgh", "male", "female");
+        blank.username = "   ".to_string();
+        assert!(handle.join_chat(conn, blank).await.unwrap(), "a blank name is fixed up, not refused");
+        let sent = events(&mut rx);
+        assert_eq!(sent[0], ("username_adjusted".to_string(), serde_json::json!({ "username": "User-abcde" })));
+        assert!(sent.iter().any(|(name, _)| name == "waiting_for_match"));
     }
 
-    // Disconnect from chat
-    pub async fn disconnect_chat(&self, conn: ConnId) {
-        let (res_tx, res_rx) = oneshot::channel();
-        self.cmd_tx
-            .send(Command::DisconnectChat { conn, res_tx })
-            .unwrap();
-        res_rx.await.unwrap();
+    #[tokio::test]
+    async fn a_replayed_or_malformed_nonce_is_refused_and_not_relayed() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b, profile("u2", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.send_message(a.clone(), encrypted_message(1), false, None, Some("m1".to_string()), Some("r1".to_string())).await.unwrap();
+        assert_eq!(event_names(&mut b_rx), ["receive_message"]);
+        events(&mut a_rx);
+        handle.send_message(a.clone(), encrypted_message(1), false, None, Some("m2".to_string()), Some("r2".to_string())).await.unwrap();
+        assert_eq!(events(&mut a_rx), [("replay_detected".to_string(), serde_json::json!({ "request_id": "r2", "message_id": "m2" }))]);
+        assert!(events(&mut b_rx).is_empty(), "a replay never reaches the partner");
+
+        let mut malformed = encrypted_message(2);
+        malformed.nonce = "not a nonce".to_string();
+        handle.send_message(a.clone(), malformed, false, None, Some("m3".to_string()), Some("r3".to_string())).await.unwrap();
+        assert_eq!(events(&mut a_rx), [("invalid_nonce".to_string(), serde_json::json!({ "request_id": "r3", "message_id": "m3" }))]);
+        assert!(events(&mut b_rx).is_empty());
     }
 
-    // Update the relay_webrtc_event method
-    pub async fn relay_webrtc_event(
-        &self,
-        sender_id: ConnId, 
-        event_type: String, 
-        target_id: String, 
-        data: Value, 
-        is_group_chat: bool, 
-        group_code: Option<String>
-    ) {
-        let (res_tx, res_rx) = oneshot::channel();
-        if let Err(e) = self.cmd_tx.send(Command::RelayWebRTCEvent { 
-            sender_id, event_type, target_id, data, is_group_chat, group_code, res_tx 
-        }) {
-            log::error!("Failed to send RelayWebRTCEvent command: {}", e);
-            return;
+    #[tokio::test]
+    async fn signaling_reaches_a_real_peer_and_a_spoofed_target_is_refused() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = connect(&mut server).await;
+        let (b, mut b_rx) = connect(&mut server).await;
+        let (c, mut c_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.users.insert(c.clone(), user(&c, "male", "female"));
+        for rx in [&mut a_rx, &mut b_rx, &mut c_rx] {
+            events(rx);
         }
-        
-        if let Err(e) = res_rx.await {
-            log::error!("Failed to receive RelayWebRTCEvent response: {}", e);
+
+        let candidate = serde_json::json!({ "candidate": "candidate:1 1 udp 1 10.0.0.1 9 typ host" });
+        server.relay_webrtc_event(a.clone(), "webrtc_ice_candidate".to_string(), b.clone(), candidate.clone(), false, None).await;
+        assert_eq!(event_names(&mut b_rx), ["webrtc_ice_candidate"]);
+        assert!(events(&mut a_rx).is_empty());
+
+        server.relay_webrtc_event(a.clone(), "webrtc_ice_candidate".to_string(), c.clone(), candidate.clone(), false, None).await;
+        assert_eq!(events(&mut a_rx), [("webrtc_error".to_string(), serde_json::json!({
+            "reason": "invalid_target",
+            "target_id": c,
+            "event": "webrtc_ice_candidate",
+        }))]);
+        assert!(events(&mut c_rx).is_empty(), "nothing reaches a session the sender isn't talking to");
+
+        // In a group, a target has to be a member of the group named
+        let (d, mut d_rx) = named_member(&mut server, "dan").await;
+        let (e, _e_rx) = named_member(&mut server, "eve").await;
+        server.create_new_group(&d, None, None, false).await;
+        let code = server.users[&d].group_id.clone().unwrap();
+        server.join_group_by_code(&e, &code).await;
+        events(&mut d_rx);
+        server.relay_webrtc_event(d, "webrtc_offer".to_string(), c.clone(), serde_json::json!({}), true, Some(code)).await;
+        assert_eq!(events(&mut d_rx)[0].1["reason"], "invalid_target");
+        assert!(events(&mut c_rx).is_empty());
+    }
+
+    #[tokio::test]
+    async fn injecting_into_a_group_the_sender_is_not_in_is_unauthorized() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        let (outsider, mut outsider_rx, _outsider_end) = connect_to(&handle).await;
+        let code = create_group(&handle, &a, &mut a_rx, "ann").await;
+        handle.join_chat(b, group_profile("ben", "join", Some(&code))).await.unwrap();
+        // The outsider sits in a group of their own, so they are a group member, just not of this one
+        create_group(&handle, &outsider, &mut outsider_rx, "eve").await;
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.send_message(outsider.clone(), encrypted_message(1), true, Some(code.clone()), None, None).await.unwrap();
+        handle.activity_update(outsider.clone(), Activity::Uploading, true, true, Some(code.clone())).await.unwrap();
+        handle.file_sending_start(outsider, "f1".to_string(), true, Some(code.clone())).await.unwrap();
+        assert_eq!(events(&mut outsider_rx), ["send_message", "activity_update", "file_sending_start"].map(|action| {
+            ("unauthorized".to_string(), serde_json::json!({ "action": action, "groupCode": code }))
+        }));
+        assert!(events(&mut a_rx).is_empty() && events(&mut b_rx).is_empty(), "nothing reaches the group");
+    }
+
+    #[tokio::test]
+    async fn file_progress_reaches_the_other_side_and_is_not_echoed_back() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b, profile("u2", "female", "male")).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.file_progress(a.clone(), "f1".to_string(), 512, 2048, false, None).await.unwrap();
+        assert_eq!(events(&mut b_rx), [("file_progress".to_string(), serde_json::json!({
+            "fileId": "f1",
+            "bytes_sent": 512,
+            "total_bytes": 2048,
+            "username": "u1",
+        }))]);
+        assert!(events(&mut a_rx).is_empty(), "the sender isn't sent its own progress");
+
+        let (c, mut c_rx, _c_end) = connect_to(&handle).await;
+        let (d, mut d_rx, _d_end) = connect_to(&handle).await;
+        let code = create_group(&handle, &c, &mut c_rx, "cat").await;
+        handle.join_chat(d, group_profile("dan", "join", Some(&code))).await.unwrap();
+        events(&mut c_rx);
+        events(&mut d_rx);
+        handle.file_progress(c, "f2".to_string(), 1, 2, true, Some(code)).await.unwrap();
+        assert_eq!(event_names(&mut d_rx), ["file_progress"]);
+        assert!(events(&mut c_rx).is_empty());
+    }
+
+    // The seq of each message in the single chat_history a session was sent, plus its has_more flag
+    fn history_page(rx: &mut mpsc::Receiver<Msg>) -> (Vec<u64>, bool) {
+        let sent = events(rx);
+        assert_eq!(sent.len(), 1);
+        let (name, page) = &sent[0];
+        assert_eq!(name, "chat_history");
+        let seqs = page["messages"].as_array().unwrap().iter().map(|entry| entry["seq"].as_u64().unwrap()).collect();
+        (seqs, page["has_more"].as_bool().unwrap())
+    }
+
+    #[tokio::test]
+    async fn a_late_joiner_pages_back_through_the_group_history() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let mut create = group_profile("ann", "create", None);
+        create.keep_history = Some(true);
+        handle.join_chat(a.clone(), create).await.unwrap();
+        let code = events(&mut a_rx).into_iter().find(|(name, _)| name == "group_joined").unwrap().1["groupCode"].as_str().unwrap().to_string();
+        for n in 1..=3 {
+            handle.send_message(a.clone(), encrypted_message(n), true, Some(code.clone()), Some(format!("m{}", n)), None).await.unwrap();
         }
+
+        let (late, mut late_rx, _late_end) = connect_to(&handle).await;
+        handle.join_chat(late.clone(), group_profile("ben", "join", Some(&code))).await.unwrap();
+        events(&mut late_rx);
+        handle.get_history(late.clone(), Some(code.clone()), None, Some(2)).await.unwrap();
+        let (seqs, has_more) = history_page(&mut late_rx);
+        assert!(seqs.len() == 2 && seqs[0] < seqs[1], "the newest page, oldest first: {:?}", seqs);
+        assert!(has_more);
+
+        handle.get_history(late, Some(code), Some(seqs[0]), Some(2)).await.unwrap();
+        let (older, has_more) = history_page(&mut late_rx);
+        assert_eq!(older.len(), 1);
+        assert!(older[0] < seqs[0]);
+        assert!(!has_more, "the first message ends the paging");
     }
 
-    // Helper method to get a session's transmitter
-    async fn get_session_tx(&self, conn_id: &str) -> Option<mpsc::UnboundedSender<Msg>> {
-        // Create a channel to get the response
-        let (res_tx, res_rx) = oneshot::channel();
-        
-        // Send a command to get the session
-        let _ = self.cmd_tx.send(Command::GetSessionTx { 
-            conn_id: conn_id.to_string(), 
-            res_tx 
-        });
-        
-        // Await the response
-        match res_rx.await {
-            Ok(opt_tx) => opt_tx,
-            Err(_) => None,
+    #[tokio::test(start_paused = true)]
+    async fn every_session_is_told_of_the_shutdown_even_behind_a_full_queue() {
+        let mut server = ChatServer::new();
+        let (_a, mut a_rx) = connect(&mut server).await;
+        // The welcome fills this session's one-frame queue
+        let (tx, mut slow_rx) = mpsc::channel(1);
+        let (end_tx, _) = oneshot::channel();
+        server.handle_connect(tx, end_tx, None, "10.0.0.9".to_string()).await.unwrap();
+        events(&mut a_rx);
+
+        let (_, welcome) = tokio::join!(server.announce_shutdown(), slow_rx.recv());
+        assert!(text(welcome.unwrap()).contains("welcome"));
+        assert_eq!(event_names(&mut a_rx), ["server_shutting_down"]);
+        assert_eq!(event_names(&mut slow_rx), ["server_shutting_down"]);
+        assert!(server.sessions.is_empty());
+        assert!(slow_rx.recv().await.is_none(), "the connection is closed after the notice");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_peer_reads_online_only_while_its_socket_is_up() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        let (stranger, _stranger_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.users.insert(stranger.clone(), user(&stranger, "male", "female"));
+
+        assert!(server.is_peer_online(&a, None), "the partner is the default target");
+        assert!(server.is_peer_online(&a, Some(&b)));
+        assert!(!server.is_peer_online(&a, Some(&stranger)), "someone who isn't a peer reads as offline");
+        assert!(!server.is_peer_online(&a, Some(&a)));
+
+        // Still the partner while away, but with no socket to reach
+        server.hold_session(&b);
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
+        assert!(!server.is_peer_online(&a, None));
+        assert!(!server.is_peer_online(&stranger, None), "no partner, nobody to ping");
+    }
+
+    #[tokio::test]
+    async fn ping_peer_answers_through_the_handle() {
+        let handle = ChatServer::start();
+        let (a, _a_rx, _a_end) = connect_to(&handle).await;
+        let (b, _b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+        assert!(handle.ping_peer(a.clone(), Some(b.clone())).await.unwrap());
+
+        handle.disconnect(b.clone(), LeaveReason::Left);
+        assert!(!handle.ping_peer(a, Some(b)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn each_side_of_a_match_learns_the_other_s_id_and_name() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("ann", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("bea", "female", "male")).await.unwrap();
+
+        let chat_started = |rx: &mut mpsc::Receiver<Msg>| {
+            events(rx).into_iter().find(|(name, _)| name == "chat_started").expect("told of the match").1
+        };
+        assert_eq!(chat_started(&mut a_rx), serde_json::json!({ "partner_username": "bea", "target_id": b }));
+        assert_eq!(chat_started(&mut b_rx), serde_json::json!({ "partner_username": "ann", "target_id": a }));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_parted_pair_is_only_rematched_once_the_cooldown_elapses() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        pair(&mut server, &a, &b).await;
+        server.skip_partner(&a).await;
+
+        tokio::time::advance(server.rematch_cooldown / 2).await;
+        server.find_match(&a).await;
+        assert_eq!(server.users[&a].partner_id, None, "still cooling down");
+        assert!(server.waiting_position(&a).is_some() && server.waiting_position(&b).is_some());
+
+        tokio::time::advance(server.rematch_cooldown).await;
+        server.find_match(&a).await;
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
+        assert_eq!(server.users[&b].partner_id.as_ref(), Some(&a));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_for_match_carries_an_estimated_wait() {
+        let mut server = ChatServer::new();
+        let estimate = |rx: &mut mpsc::Receiver<Msg>| {
+            let (_, data) = events(rx).into_iter().find(|(name, _)| name == "waiting_for_match").expect("queued");
+            data["estimated_wait_seconds"].as_u64().expect("a whole, non-negative number of seconds")
+        };
+        let (a, mut a_rx) = connect(&mut server).await;
+        server.users.insert(a.clone(), user(&a, "male", "female"));
+        server.find_match(&a).await;
+        assert_eq!(estimate(&mut a_rx), (server.match_timeout / 2).as_secs(), "a guess until a match has been timed");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        server.users.insert(b.clone(), user(&b, "female", "male"));
+        server.find_match(&b).await;
+        assert_eq!(server.users[&a].partner_id.as_ref(), Some(&b));
+
+        let (c, mut c_rx) = connect(&mut server).await;
+        server.users.insert(c.clone(), user(&c, "male", "female"));
+        server.find_match(&c).await;
+        assert_eq!(estimate(&mut c_rx), 10, "then the average of recent waits");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn languages_must_match_until_the_match_timeout_passes() {
+        let mut server = ChatServer::new();
+        let (a, _a_rx) = connect(&mut server).await;
+        let (b, _b_rx) = connect(&mut server).await;
+        let (c, _c_rx) = connect(&mut server).await;
+        let (d, _d_rx) = connect(&mut server).await;
+        let mut speaker = |conn: &ConnId, gender: &str, preference: &str, language: &str| {
+            let mut speaker = user(conn, gender, preference);
+            speaker.language = Some(language.to_string());
+            server.users.insert(conn.clone(), speaker);
+        };
+        speaker(&a, "male", "female", "uz");
+        speaker(&b, "female", "male", "en");
+        speaker(&c, "female", "male", "uz");
+        speaker(&d, "male", "female", "ru");
+
+        server.find_match(&a).await;
+        server.find_match(&b).await;
+        assert_eq!(server.users[&b].partner_id, None, "no match across languages");
+        server.find_match(&c).await;
+        assert_eq!(server.users[&c].partner_id.as_ref(), Some(&a), "a shared language matches at once");
+
+        server.find_match(&d).await;
+        tokio::time::advance(server.match_timeout / 2).await;
+        server.match_relaxed_waiters().await;
+        assert_eq!(server.users[&d].partner_id, None, "still holding out for their own language");
+
+        tokio::time::advance(server.match_timeout).await;
+        server.match_relaxed_waiters().await;
+        assert_eq!(server.users[&d].partner_id.as_ref(), Some(&b), "any language will do after the timeout");
+    }
+
+    #[tokio::test]
+    async fn a_reaction_added_and_removed_in_a_group_reaches_every_member() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        let code = create_group(&handle, &a, &mut a_rx, "ann").await;
+        handle.join_chat(b.clone(), group_profile("ben", "join", Some(&code))).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        for action in [ReactionAction::Add, ReactionAction::Remove] {
+            handle.react_message(b.clone(), "m1".to_string(), "👍".to_string(), action, true, Some(code.clone())).await.unwrap();
+            let expected = [("message_reaction".to_string(), serde_json::json!({
+                "message_id": "m1",
+                "reaction": "👍",
+                "action": action,
+                "username": "ben",
+            }))];
+            assert_eq!(events(&mut a_rx), expected);
+            assert_eq!(events(&mut b_rx), expected, "the reactor sees it confirmed too");
         }
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn only_the_author_can_delete_a_message() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+        handle.send_message(a.clone(), encrypted_message(1), false, None, Some("m1".to_string()), None).await.unwrap();
+        events(&mut a_rx);
+        events(&mut b_rx);
+
+        handle.delete_message(b.clone(), "m1".to_string(), false, None).await.unwrap();
+        assert_eq!(events(&mut b_rx), [("unauthorized".to_string(), serde_json::json!({
+            "action": "delete_message",
+            "messageId": "m1",
+        }))]);
+        assert!(events(&mut a_rx).is_empty(), "the author's message stays put");
+
+        handle.delete_message(a, "m1".to_string(), false, None).await.unwrap();
+        let deleted = [("message_deleted".to_string(), serde_json::json!({ "messageId": "m1" }))];
+        assert_eq!(events(&mut b_rx), deleted);
+        assert_eq!(events(&mut a_rx), deleted);
+    }
+
+    #[tokio::test]
+    async fn a_message_sent_during_the_grace_window_is_delivered_on_resume() {
+        let handle = ChatServer::start();
+        let (a, mut a_rx, _a_end) = connect_to(&handle).await;
+        let (b, mut b_rx, _b_end) = connect_to(&handle).await;
+        let token = events(&mut b_rx)[0].1["resume_token"].as_str().unwrap().to_string();
+        handle.join_chat(a.clone(), profile("u1", "male", "female")).await.unwrap();
+        handle.join_chat(b.clone(), profile("u2", "female", "male")).await.unwrap();
+
+        handle.disconnect(b.clone(), LeaveReason::Timeout);
+        handle.send_message(a.clone(), encrypted_message(1), false, None, Some("m1".to_string()), None).await.unwrap();
+        assert!(!event_names(&mut a_rx).contains(&"message_delivered".to_string()), "nobody has taken it yet");
+
+        let (tx, mut resumed_rx) = mpsc::channel(64);
+        let (end_tx, _resumed_end) = oneshot::channel();
+        let (resumed, was_resumed) = handle.connect(tx, end_tx, Some(token), Uuid::new_v4().to_string()).await.unwrap().unwrap();
+        assert_eq!((resumed, was_resumed), (b, true));
+        let received: Vec<_> = events(&mut resumed_rx).into_iter().filter(|(name, _)| name == "receive_message").collect();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].1["message_id"], "m1");
+        assert_eq!(received[0].1["message"]["encrypted"], encrypted_message(1).encrypted);
+    }
+
+    #[tokio::test]
+    async fn group_peers_update_lists_each_member_once() {
+        let mut server = ChatServer::new();
+        let (a, mut a_rx) = named_member(&mut server, "ann").await;
+        let (b, _b_rx) = named_member(&mut server, "sam").await;
+        let (c, _c_rx) = named_member(&mut server, "sam").await;
+        server.create_new_group(&a, None, None, false).await;
+        let code = server.users[&a].group_id.clone().unwrap();
+        server.join_group_by_code(&b, &code).await;
+        events(&mut a_rx);
+        server.join_group_by_code(&c, &code).await;
+
+        let updates: Vec<_> = events(&mut a_rx).into_iter().filter(|(name, _)| name == "group_peers_update").collect();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].1, serde_json::json!({
+            "groupCode": code,
+            "peers": [
+                { "username": "ann", "peer_id": a },
+                { "username": "sam", "peer_id": b },
+                { "username": "sam (2)", "peer_id": c },
+            ],
+        }));
+    }
+}
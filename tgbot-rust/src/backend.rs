@@ -0,0 +1,97 @@
+use serde_json::Value;
+use std::future::Future;
+use std::time::Duration;
+
+/// How long /link and /loom wait for their backend before falling back to the web app
+pub const BACKEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a backend call gave no usable answer
+#[derive(Debug)]
+pub enum BackendError {
+    /// No URL is configured for this backend
+    NotConfigured,
+    /// The backend did not answer within BACKEND_TIMEOUT
+    Timeout,
+    /// Network, status or body problem talking to the backend
+    Request(reqwest::Error),
+    /// The backend answered without the field the command needs
+    BadResponse,
+}
+
+impl std::fmt::Display for BackendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackendError::NotConfigured => write!(f, "backend URL not configured"),
+            BackendError::Timeout => write!(f, "no answer within {:?}", BACKEND_TIMEOUT),
+            BackendError::Request(e) => write!(f, "{}", e),
+            BackendError::BadResponse => write!(f, "unexpected response body"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(e: reqwest::Error) -> Self {
+        BackendError::Request(e)
+    }
+}
+
+// The shortener and Loom downloader calls behind /link and /loom, so the commands can run without the network
+pub trait Backend: Send + Sync {
+    // A short link for `url`
+    fn shorten(&self, url: &str) -> impl Future<Output = Result<String, BackendError>> + Send;
+
+    // A direct download link for the Loom video shared at `url`
+    fn loom_download(&self, url: &str) -> impl Future<Output = Result<String, BackendError>> + Send;
+}
+
+// Talks to the notl.ink and yaps.lol APIs; a backend without a URL fails with NotConfigured
+pub struct HttpBackend {
+    client: reqwest::Client,
+    link_api_url: Option<&'static str>,
+    loom_api_url: Option<&'static str>,
+}
+
+impl HttpBackend {
+    pub fn new(link_api_url: Option<&'static str>, loom_api_url: Option<&'static str>) -> Self {
+        HttpBackend {
+            client: reqwest::Client::new(),
+            link_api_url,
+            loom_api_url,
+        }
+    }
+
+    // POST `{ "url": url }` to `base_url/path` and read one string field from the JSON answer
+    async fn post_url(&self, base_url: Option<&str>, path: &str, url: &str, field: &str) -> Result<String, BackendError> {
+        let base_url = base_url.ok_or(BackendError::NotConfigured)?;
+        let body: Value = self.client
+            .post(format!("{}/{}", base_url.trim_end_matches('/'), path))
+            .timeout(BACKEND_TIMEOUT)
+            .json(&serde_json::json!({ "url": url }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        body.get(field)
+            .and_then(Value::as_str)
+            .map(String::from)
+            .ok_or(BackendError::BadResponse)
+    }
+}
+
+impl Backend for HttpBackend {
+    async fn shorten(&self, url: &str) -> Result<String, BackendError> {
+        self.post_url(self.link_api_url, "shorten", url, "short_url").await
+    }
+
+    async fn loom_download(&self, url: &str) -> Result<String, BackendError> {
+        self.post_url(self.loom_api_url, "download", url, "download_url").await
+    }
+}
+
+// Run a backend call, giving up after BACKEND_TIMEOUT whatever the client itself does
+pub async fn with_timeout<T>(call: impl Future<Output = Result<T, BackendError>>) -> Result<T, BackendError> {
+    tokio::time::timeout(BACKEND_TIMEOUT, call)
+        .await
+        .unwrap_or(Err(BackendError::Timeout))
+}
@@ -0,0 +1,45 @@
+use std::future::Future;
+use teloxide::prelude::*;
+use teloxide::types::{BotCommand, InlineKeyboardMarkup};
+use teloxide::RequestError;
+use url::Url;
+
+// The slice of the Telegram Bot API the handlers use, so command routing can run without the network
+pub trait BotApi: Send + Sync {
+    fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> impl Future<Output = Result<(), RequestError>> + Send;
+
+    fn set_webhook(&self, url: Url) -> impl Future<Output = Result<(), RequestError>> + Send;
+
+    fn set_my_commands(&self, commands: Vec<BotCommand>) -> impl Future<Output = Result<(), RequestError>> + Send;
+}
+
+impl BotApi for Bot {
+    async fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        keyboard: Option<InlineKeyboardMarkup>,
+    ) -> Result<(), RequestError> {
+        let request = Requester::send_message(self, chat_id, text);
+        match keyboard {
+            Some(keyboard) => request.reply_markup(keyboard).await?,
+            None => request.await?,
+        };
+        Ok(())
+    }
+
+    async fn set_webhook(&self, url: Url) -> Result<(), RequestError> {
+        Requester::set_webhook(self, url).await?;
+        Ok(())
+    }
+
+    async fn set_my_commands(&self, commands: Vec<BotCommand>) -> Result<(), RequestError> {
+        Requester::set_my_commands(self, commands).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use serde_json::Value;
+use std::time::Duration;
+
+/// How long /status waits for the chat server before reporting it unreachable
+const STATUS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Counts reported by the chat server's public `/stats` endpoint
+pub struct ServerStats {
+    pub online: u64,
+    pub waiting: u64,
+    pub groups: u64,
+}
+
+// Fetch live counts from the chat server; any network, status or body problem is an error
+pub async fn fetch_stats(base_url: &str) -> Result<ServerStats, reqwest::Error> {
+    let url = format!("{}/stats", base_url.trim_end_matches('/'));
+    let body: Value = reqwest::Client::new()
+        .get(url)
+        .timeout(STATUS_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let count = |field: &str| body.get(field).and_then(Value::as_u64).unwrap_or(0);
+    Ok(ServerStats {
+        online: count("online"),
+        waiting: count("waiting"),
+        groups: count("groups"),
+    })
+}
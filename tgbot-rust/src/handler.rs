@@ -1,57 +1,112 @@
-use teloxide::prelude::*;
-use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+use teloxide::types::{BotCommand, InlineKeyboardButton, InlineKeyboardMarkup, Message};
 use url::Url;
-use log;
+use crate::backend::{self, Backend};
+use crate::bot_api::BotApi;
+use crate::{chat_server, keys};
+use crate::throttle::CommandThrottle;
+use std::sync::Arc;
+
+// Commands shown in Telegram's command menu; keep in sync with the /help text below
+pub fn command_menu() -> Vec<BotCommand> {
+    vec![
+        BotCommand::new("start", "yoo, wassap!"),
+        BotCommand::new("chat", "one-time end-to-end encrypted anonymous chats"),
+        BotCommand::new("link", "free open source blazingly fast url shortener"),
+        BotCommand::new("loom", "free open source loom video downloader"),
+        BotCommand::new("help", "try me if you're lost;)"),
+        BotCommand::new("status", "is yaps.chat up right now?"),
+        BotCommand::new("enterprise", "let's yapp on business"),
+    ]
+}
+
+// Launch button and blurb for each mini app, shared by its command and its /start deep-link payload
+fn launch_reply(app: &str) -> Option<(String, InlineKeyboardMarkup)> {
+    let (path, label, text) = match app {
+        "chat" => ("chat", "👀 Start Yapping!", "Click to start one-time end-to-end encrypted anonymous chats."),
+        "group" => ("chat?startapp=group", "👀 Start Group Yapping!", "Click to jump straight into an end-to-end encrypted anonymous group chat."),
+        "link" => ("link", "👀 Start Shortening!", "Click to shorten your long links blazingly fast."),
+        "loom" => ("loom", "👀 Start Downloading!", "Not a Loom Pro subscriber? Click to download videos from Loom for free."),
+        _ => return None,
+    };
+    // Parse the URL properly for the InlineKeyboardButton
+    let url = Url::parse(&format!("https://t.me/yapsworld_bot/{}", path))
+        .expect("Failed to parse launch URL");
+    let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::url(label, url)]]);
+    Some((text.to_string(), keyboard))
+}
+
+// The /status text for the chat server at `base_url`, if one is configured
+async fn status_reply(base_url: Option<&str>) -> String {
+    let Some(base_url) = base_url else {
+        return "Status checks are not configured.".to_string();
+    };
+    match chat_server::fetch_stats(base_url).await {
+        Ok(stats) => format!(
+            "🟢 yaps.chat is up\n\nOnline: {}\nWaiting for a match: {}\nGroups: {}",
+            stats.online, stats.waiting, stats.groups,
+        ),
+        Err(e) => {
+            log::warn!("Chat server status check failed: {}", e);
+            "🔴 yaps.chat is unreachable right now.".to_string()
+        }
+    }
+}
+
+/// Sent in place of a /link or /loom result when the backend is down, slow or not configured
+const BACKEND_BUSY_REPLY: &str = "Service is busy, try the web app.";
+
+// The reply to "/link <url>" or "/loom <url>": the backend's answer, or a pointer to the web app
+// when the call fails or times out
+async fn backend_reply<K: Backend>(backend: &K, app: &str, url: &str) -> (String, Option<InlineKeyboardMarkup>) {
+    let result = match app {
+        "link" => backend::with_timeout(backend.shorten(url)).await.map(|short_url| format!("🔗 {}", short_url)),
+        _ => backend::with_timeout(backend.loom_download(url)).await.map(|download_url| format!("⬇️ {}", download_url)),
+    };
+    match result {
+        Ok(reply) => (reply, None),
+        Err(e) => {
+            log::warn!("/{} backend call failed: {}", app, e);
+            (BACKEND_BUSY_REPLY.to_string(), launch_reply(app).map(|(_, keyboard)| keyboard))
+        }
+    }
+}
 
 // Handle incoming messages (e.g., /chat command)
-pub async fn message_handler(bot: Bot, msg: Message) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+pub async fn message_handler<B: BotApi, K: Backend>(
+    bot: B,
+    msg: Message,
+    throttle: Arc<CommandThrottle>,
+    backend: Arc<K>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     if let Some(text) = msg.text() {
+        // Silently drop command bursts so one chat can't push the bot into Telegram's rate limits
+        if text.starts_with('/') && !throttle.allow(msg.chat.id) {
+            log::debug!("Throttled command from chat {}", msg.chat.id);
+            return Ok(());
+        }
         match text {
-            "/start" => {
-                bot.send_message(msg.chat.id, "Yoo, wassap! It's Yaps World on yaps.gg. We build things. Try /help.")
-                    .await?;
-            }
-            text if text.starts_with("/chat") => {
-                // Parse the URL properly for the InlineKeyboardButton
-                let chat_url = Url::parse("https://t.me/yapsworld_bot/chat")
-                    .expect("Failed to parse chat URL");
-                
-                let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::url(
-                    "👀 Start Yapping!",
-                    chat_url,
-                )]]);
-                
-                bot.send_message(msg.chat.id, "Click to start one-time end-to-end encrypted anonymous chats.")
-                    .reply_markup(keyboard)
-                    .await?;
+            text if text == "/start" || text.starts_with("/start ") => {
+                // Deep links (t.me/yapsworld_bot?start=group) arrive as "/start group"
+                let payload = text["/start".len()..].trim();
+                match launch_reply(payload) {
+                    Some((reply, keyboard)) => {
+                        bot.send_message(msg.chat.id, reply, Some(keyboard)).await?;
+                    }
+                    None => {
+                        bot.send_message(msg.chat.id, "Yoo, wassap! It's Yaps World on yaps.gg. We build things. Try /help.".to_string(), None)
+                            .await?;
+                    }
+                }
             }
-            text if text.starts_with("/link") => {
-                // Parse the URL properly for the InlineKeyboardButton
-                let chat_url = Url::parse("https://t.me/yapsworld_bot/link")
-                    .expect("Failed to parse chat URL");
-                
-                let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::url(
-                    "👀 Start Shortening!",
-                    chat_url,
-                )]]);
-                
-                bot.send_message(msg.chat.id, "Click to shorten your long links blazingly fast.")
-                    .reply_markup(keyboard)
-                    .await?;
+            // "/link <url>" shortens and "/loom <url>" fetches a download link; bare, they open the mini app
+            text if (text.starts_with("/link ") || text.starts_with("/loom ")) && !text[5..].trim().is_empty() => {
+                let (reply, keyboard) = backend_reply(backend.as_ref(), &text[1..5], text[5..].trim()).await;
+                bot.send_message(msg.chat.id, reply, keyboard).await?;
             }
-            text if text.starts_with("/loom") => {
-                // Parse the URL properly for the InlineKeyboardButton
-                let chat_url = Url::parse("https://t.me/yapsworld_bot/loom")
-                    .expect("Failed to parse chat URL");
-                
-                let keyboard = InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::url(
-                    "👀 Start Downloading!",
-                    chat_url,
-                )]]);
-                
-                bot.send_message(msg.chat.id, "Not a Loom Pro subscriber? Click to download videos from Loom for free.")
-                    .reply_markup(keyboard)
-                    .await?;
+            text if text.starts_with("/chat") || text.starts_with("/link") || text.starts_with("/loom") => {
+                if let Some((reply, keyboard)) = launch_reply(&text[1..5]) {
+                    bot.send_message(msg.chat.id, reply, Some(keyboard)).await?;
+                }
             }
             "/help" => {
                 bot.send_message(
@@ -60,14 +115,19 @@ pub async fn message_handler(bot: Bot, msg: Message) -> Result<(), Box<dyn std::
                     Available commands:\n\
                     /start - yoo, wassap!\n\
                     /chat - Launch \"yaps.chat - one-time end-to-end encrypted anonymous chats\" on Telegram\n\
-                    /link - Launch \"notl.ink - free open source blazingly fast url shortener ever\" on Telegram\n\
-                    /loom - Launch \"yaps.lol - free open source loom video downloader\" on Telegram\n\
+                    /link - Launch \"notl.ink - free open source blazingly fast url shortener ever\" on Telegram, or /link <url> to shorten it here\n\
+                    /loom - Launch \"yaps.lol - free open source loom video downloader\" on Telegram, or /loom <url> to get the video here\n\
                     /help - try me if you're lost;)\n\
-                    /enterprise - let's yapp on business"
+                    /status - is yaps.chat up right now?\n\
+                    /enterprise - let's yapp on business".to_string(),
+                    None,
                 ).await?;
             }
+            "/status" => {
+                bot.send_message(msg.chat.id, status_reply(keys::get_chat_server_url()).await, None).await?;
+            }
             "/enterprise" => {
-                bot.send_message(msg.chat.id, "Kindly contact me via abdibrokhim@gmail.com.").await?;
+                bot.send_message(msg.chat.id, "Kindly contact me via abdibrokhim@gmail.com.".to_string(), None).await?;
             }
             _ => {
                 // Handle other messages or commands
@@ -76,4 +136,233 @@ pub async fn message_handler(bot: Bot, msg: Message) -> Result<(), Box<dyn std::
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use teloxide::types::ChatId;
+    use teloxide::RequestError;
+    use crate::backend::{BackendError, BACKEND_TIMEOUT};
+
+    type Reply = (ChatId, String, Option<InlineKeyboardMarkup>);
+
+    // Records every reply instead of calling Telegram
+    #[derive(Clone, Default)]
+    struct RecordingBot {
+        sent: Arc<Mutex<Vec<Reply>>>,
+    }
+
+    impl BotApi for RecordingBot {
+        async fn send_message(&self, chat_id: ChatId, text: String, keyboard: Option<InlineKeyboardMarkup>) -> Result<(), RequestError> {
+            self.sent.lock().unwrap().push((chat_id, text, keyboard));
+            Ok(())
+        }
+
+        async fn set_webhook(&self, _url: Url) -> Result<(), RequestError> {
+            Ok(())
+        }
+
+        async fn set_my_commands(&self, _commands: Vec<BotCommand>) -> Result<(), RequestError> {
+            Ok(())
+        }
+    }
+
+    fn text_message(chat_id: i64, text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "date": 0,
+            "chat": { "id": chat_id, "type": "private", "first_name": "yapper" },
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    // Answers the way working shortener and downloader backends would
+    struct WorkingBackend;
+
+    impl Backend for WorkingBackend {
+        async fn shorten(&self, _url: &str) -> Result<String, BackendError> {
+            Ok("https://notl.ink/abc".to_string())
+        }
+
+        async fn loom_download(&self, _url: &str) -> Result<String, BackendError> {
+            Ok("https://yaps.lol/v/abc.mp4".to_string())
+        }
+    }
+
+    // Fails every call straight away, like a backend that is down
+    struct FailingBackend;
+
+    impl Backend for FailingBackend {
+        async fn shorten(&self, _url: &str) -> Result<String, BackendError> {
+            Err(BackendError::BadResponse)
+        }
+
+        async fn loom_download(&self, _url: &str) -> Result<String, BackendError> {
+            Err(BackendError::NotConfigured)
+        }
+    }
+
+    // Never answers, like an overloaded backend
+    struct HangingBackend;
+
+    impl Backend for HangingBackend {
+        async fn shorten(&self, _url: &str) -> Result<String, BackendError> {
+            std::future::pending().await
+        }
+
+        async fn loom_download(&self, _url: &str) -> Result<String, BackendError> {
+            std::future::pending().await
+        }
+    }
+
+    // The replies one command produces from a fresh chat, with /link and /loom going to `backend`
+    async fn replies_via<K: Backend>(text: &str, backend: K) -> Vec<Reply> {
+        let bot = RecordingBot::default();
+        message_handler(bot.clone(), text_message(42, text), Arc::new(CommandThrottle::default()), Arc::new(backend)).await.unwrap();
+        let sent = bot.sent.lock().unwrap().clone();
+        sent
+    }
+
+    async fn replies(text: &str) -> Vec<Reply> {
+        replies_via(text, WorkingBackend).await
+    }
+
+    fn button_url(keyboard: &InlineKeyboardMarkup) -> String {
+        match &keyboard.inline_keyboard[0][0].kind {
+            teloxide::types::InlineKeyboardButtonKind::Url(url) => url.to_string(),
+            other => panic!("expected a url button, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn start_greets_without_a_keyboard() {
+        let sent = replies("/start").await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(42));
+        assert!(sent[0].1.starts_with("Yoo, wassap!"));
+        assert!(sent[0].2.is_none());
+    }
+
+    #[tokio::test]
+    async fn chat_replies_with_the_mini_app_button() {
+        let sent = replies("/chat").await;
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("anonymous chats"));
+        assert_eq!(button_url(sent[0].2.as_ref().unwrap()), "https://t.me/yapsworld_bot/chat");
+    }
+
+    #[tokio::test]
+    async fn help_lists_every_menu_command() {
+        let sent = replies("/help").await;
+        assert_eq!(sent.len(), 1);
+        for command in command_menu() {
+            assert!(sent[0].1.contains(&format!("/{} - ", command.command)), "/help is missing /{}", command.command);
+        }
+    }
+
+    // A one-shot stand-in for the chat server that answers the next request with `response`
+    fn fake_chat_server(response: String) -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
+            let _ = stream.write_all(response.as_bytes());
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn status_reports_the_live_counts() {
+        let body = r#"{"online":12,"waiting":3,"groups":2}"#;
+        let response = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            body.len(), body,
+        );
+        let reply = status_reply(Some(&fake_chat_server(response))).await;
+        assert_eq!(reply, "🟢 yaps.chat is up\n\nOnline: 12\nWaiting for a match: 3\nGroups: 2");
+    }
+
+    #[tokio::test]
+    async fn status_reports_an_unreachable_server() {
+        // Nothing listens on a port that was bound and released
+        let url = format!("http://{}", std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+        assert_eq!(status_reply(Some(&url)).await, "🔴 yaps.chat is unreachable right now.");
+
+        let error = fake_chat_server("HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\nconnection: close\r\n\r\n".to_string());
+        assert_eq!(status_reply(Some(&error)).await, "🔴 yaps.chat is unreachable right now.");
+    }
+
+    #[tokio::test]
+    async fn status_without_a_configured_server_says_so() {
+        assert_eq!(status_reply(None).await, "Status checks are not configured.");
+    }
+
+    #[tokio::test]
+    async fn a_command_burst_gets_a_single_reply() {
+        let bot = RecordingBot::default();
+        let throttle = Arc::new(CommandThrottle::default());
+        let backend = Arc::new(WorkingBackend);
+        for _ in 0..5 {
+            message_handler(bot.clone(), text_message(42, "/chat"), throttle.clone(), backend.clone()).await.unwrap();
+        }
+        message_handler(bot.clone(), text_message(7, "/chat"), throttle.clone(), backend.clone()).await.unwrap();
+        let chats: Vec<ChatId> = bot.sent.lock().unwrap().iter().map(|(chat_id, _, _)| *chat_id).collect();
+        assert_eq!(chats, [ChatId(42), ChatId(7)]);
+    }
+
+    #[tokio::test]
+    async fn start_group_deep_link_opens_group_chat() {
+        let sent = replies("/start group").await;
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("group chat"));
+        assert_eq!(button_url(sent[0].2.as_ref().unwrap()), "https://t.me/yapsworld_bot/chat?startapp=group");
+    }
+
+    #[tokio::test]
+    async fn start_with_an_unknown_payload_falls_back_to_the_greeting() {
+        assert_eq!(replies("/start nonsense").await, replies("/start").await);
+    }
+
+    #[tokio::test]
+    async fn plain_text_gets_no_reply() {
+        assert!(replies("hello there").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn link_and_loom_with_a_url_reply_with_the_backend_result() {
+        let sent = replies("/link https://example.com/a/very/long/path").await;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].1, "🔗 https://notl.ink/abc");
+        assert!(sent[0].2.is_none());
+        assert_eq!(replies("/loom https://www.loom.com/share/abc").await[0].1, "⬇️ https://yaps.lol/v/abc.mp4");
+
+        // Without a URL they still just open the mini app
+        let sent = replies("/link").await;
+        assert_eq!(button_url(sent[0].2.as_ref().unwrap()), "https://t.me/yapsworld_bot/link");
+    }
+
+    #[tokio::test]
+    async fn a_failing_backend_gets_the_busy_reply_with_the_web_app_button() {
+        for (text, app_url) in [
+            ("/link https://example.com", "https://t.me/yapsworld_bot/link"),
+            ("/loom https://www.loom.com/share/abc", "https://t.me/yapsworld_bot/loom"),
+        ] {
+            let sent = replies_via(text, FailingBackend).await;
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].1, BACKEND_BUSY_REPLY);
+            assert_eq!(button_url(sent[0].2.as_ref().unwrap()), app_url);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_backend_that_never_answers_times_out_into_the_busy_reply() {
+        let started = tokio::time::Instant::now();
+        let sent = replies_via("/link https://example.com", HangingBackend).await;
+        assert_eq!(sent[0].1, BACKEND_BUSY_REPLY);
+        assert_eq!(started.elapsed(), BACKEND_TIMEOUT);
+    }
+}
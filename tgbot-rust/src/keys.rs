@@ -4,6 +4,9 @@ use std::sync::OnceLock;
 
 static TELEGRAM_BOT_TOKEN: OnceLock<String> = OnceLock::new();
 static APP_HOST: OnceLock<String> = OnceLock::new();
+static CHAT_SERVER_URL: OnceLock<String> = OnceLock::new();
+static LINK_API_URL: OnceLock<String> = OnceLock::new();
+static LOOM_API_URL: OnceLock<String> = OnceLock::new();
 
 pub fn init_secrets(secrets: &SecretStore) {
     // Initialize TELEGRAM_BOT_TOKEN
@@ -17,6 +20,23 @@ pub fn init_secrets(secrets: &SecretStore) {
         .expect("APP_HOST not found in secrets");
     APP_HOST.set(app_host.clone())
         .expect("APP_HOST already initialized");
+
+    // Initialize CHAT_SERVER_URL (optional; /status is disabled without it)
+    if let Some(chat_server_url) = secrets.get("CHAT_SERVER_URL") {
+        CHAT_SERVER_URL.set(chat_server_url)
+            .expect("CHAT_SERVER_URL already initialized");
+    }
+
+    // Initialize LINK_API_URL and LOOM_API_URL (optional; without them /link and /loom with a URL
+    // fall back to the web app)
+    if let Some(link_api_url) = secrets.get("LINK_API_URL") {
+        LINK_API_URL.set(link_api_url)
+            .expect("LINK_API_URL already initialized");
+    }
+    if let Some(loom_api_url) = secrets.get("LOOM_API_URL") {
+        LOOM_API_URL.set(loom_api_url)
+            .expect("LOOM_API_URL already initialized");
+    }
 }
 
 pub fn get_telegram_bot_token() -> &'static str {
@@ -26,3 +46,15 @@ pub fn get_telegram_bot_token() -> &'static str {
 pub fn get_app_host() -> &'static str {
     APP_HOST.get().expect("APP_HOST not initialized")
 }
+
+pub fn get_chat_server_url() -> Option<&'static str> {
+    CHAT_SERVER_URL.get().map(String::as_str)
+}
+
+pub fn get_link_api_url() -> Option<&'static str> {
+    LINK_API_URL.get().map(String::as_str)
+}
+
+pub fn get_loom_api_url() -> Option<&'static str> {
+    LOOM_API_URL.get().map(String::as_str)
+}
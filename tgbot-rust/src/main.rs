@@ -4,10 +4,17 @@ use teloxide::{prelude::*, types::UpdateKind};
 use shuttle_runtime::SecretStore;
 // use std::net::SocketAddr;
 use url::Url;
-use log;
+use backend::HttpBackend;
+use bot_api::BotApi;
+use std::sync::Arc;
+use throttle::CommandThrottle;
 
 mod keys;
 mod handler;
+mod backend;
+mod bot_api;
+mod chat_server;
+mod throttle;
 
 // Simple index route
 async fn index() -> impl Responder {
@@ -37,16 +44,26 @@ async fn main(
     let url = Url::parse(&webhook_url).expect("Failed to parse webhook URL");
     
     // Set up webhook in Telegram servers
-    bot.set_webhook(url)
+    BotApi::set_webhook(&bot, url)
         .await
         .expect("Failed to set webhook");
 
+    // The command menu is a nicety; the bot works without it
+    if let Err(e) = BotApi::set_my_commands(&bot, handler::command_menu()).await {
+        log::warn!("Failed to set the command menu: {:?}", e);
+    }
+
+    // One throttle and one backend client for both the dispatcher and the webhook route
+    let throttle = Arc::new(CommandThrottle::default());
+    let backend = Arc::new(HttpBackend::new(keys::get_link_api_url(), keys::get_loom_api_url()));
+
     // Define the dispatcher to handle updates
     let handler = dptree::entry()
-        .branch(Update::filter_message().endpoint(handler::message_handler));
+        .branch(Update::filter_message().endpoint(handler::message_handler::<Bot, HttpBackend>));
 
     // Start the dispatcher
     let mut dispatcher = Dispatcher::builder(bot.clone(), handler.clone())
+        .dependencies(dptree::deps![throttle.clone(), backend.clone()])
         .enable_ctrlc_handler()
         .build();
         
@@ -59,6 +76,8 @@ async fn main(
     let config = move |cfg: &mut web::ServiceConfig| {
         cfg.app_data(web::Data::new(bot.clone()))
             .app_data(web::Data::new(handler.clone()))
+            .app_data(web::Data::from(throttle.clone()))
+            .app_data(web::Data::from(backend.clone()))
             .route("/", web::get().to(index))
             .route("/webhook", web::post().to(webhook_handler));
     };
@@ -70,6 +89,8 @@ async fn main(
 async fn webhook_handler(
     body: web::Json<Update>,  // Parse the update directly from JSON
     bot: web::Data<Bot>,
+    throttle: web::Data<CommandThrottle>,
+    backend: web::Data<HttpBackend>,
 ) -> actix_web::Result<HttpResponse> {
     // Get the update from the request body
     let update = body.into_inner();
@@ -83,7 +104,7 @@ async fn webhook_handler(
     match update.kind {
         UpdateKind::Message(message) => {
             // Process message directly
-            if let Err(e) = handler::message_handler(bot_instance, message).await {
+            if let Err(e) = handler::message_handler(bot_instance, message, throttle.into_inner(), backend.into_inner()).await {
                 log::error!("Error handling message: {:?}", e);
             }
         },
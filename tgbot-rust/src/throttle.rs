@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use teloxide::types::ChatId;
+
+/// Minimum gap between two commands from the same chat; anything faster is dropped
+const COMMAND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Past this many tracked chats, idle entries are pruned before inserting
+const MAX_TRACKED_CHATS: usize = 10_000;
+
+// Per-chat command rate limit shared by the dispatcher and the webhook route
+#[derive(Default)]
+pub struct CommandThrottle {
+    last_command: Mutex<HashMap<ChatId, Instant>>,
+}
+
+impl CommandThrottle {
+    // Record a command from `chat_id`, returning false if it came too soon after the previous one
+    pub fn allow(&self, chat_id: ChatId) -> bool {
+        let now = Instant::now();
+        let mut last_command = self.last_command.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if last_command.get(&chat_id).is_some_and(|at| now.duration_since(*at) < COMMAND_INTERVAL) {
+            return false;
+        }
+        if last_command.len() >= MAX_TRACKED_CHATS {
+            last_command.retain(|_, at| now.duration_since(*at) < COMMAND_INTERVAL);
+        }
+        last_command.insert(chat_id, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_burst_from_one_chat_is_throttled_after_the_first_command() {
+        let throttle = CommandThrottle::default();
+        assert!(throttle.allow(ChatId(1)));
+        assert!((0..5).all(|_| !throttle.allow(ChatId(1))));
+        assert!(throttle.allow(ChatId(2)), "other chats have their own budget");
+    }
+
+    #[test]
+    fn a_chat_is_allowed_again_once_the_interval_passes() {
+        let throttle = CommandThrottle::default();
+        throttle.last_command.lock().unwrap().insert(ChatId(1), Instant::now() - COMMAND_INTERVAL);
+        assert!(throttle.allow(ChatId(1)));
+        assert!(!throttle.allow(ChatId(1)));
+    }
+}